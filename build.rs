@@ -0,0 +1,13 @@
+use std::env;
+
+// Passes what the library is built for on to `build_info`, which only build scripts are told.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| Some(name.strip_prefix("CARGO_FEATURE_")?.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=BUILD_TARGET={}", env::var("TARGET").unwrap_or_default());
+    println!("cargo:rustc-env=BUILD_PROFILE={}", env::var("PROFILE").unwrap_or_default());
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+}
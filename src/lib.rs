@@ -1,301 +1,7930 @@
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 use serde::{Serialize, Deserialize};
-use std::{path::{PathBuf, Path}, ffi::{CString, CStr}, os::raw::c_char, sync::{Arc, Mutex}};
-use tokio::{fs, runtime::Runtime, io};
-use async_recursion::async_recursion;
+use std::{cmp::Reverse, collections::{BTreeMap, BinaryHeap, HashSet, VecDeque}, time::{Duration, Instant, SystemTime}, path::{PathBuf, Path}, ffi::{CString, CStr}, os::raw::c_char, sync::{Arc, Condvar, Mutex, RwLock, mpsc::{self, Receiver, Sender, SyncSender}, atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering}}};
+use tokio::{fs, runtime::{Builder, Handle, Runtime, RuntimeFlavor}, io, sync::Semaphore};
+use tokio_util::sync::CancellationToken;
+use futures::{future::BoxFuture, stream::{self, BoxStream, FuturesUnordered, StreamExt}};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, error, warn};
+#[cfg(any(windows, target_os = "macos"))]
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Serialize, Deserialize, Clone, Debug, Default)]
-struct FolderHierarchy {
-    value: u64,
-    name: String,
-    path: String,
-    children: Vec<FolderHierarchy>,
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    #[default]
+    Directory,
+    Symlink,
 }
 
-pub struct DirectoryScanner {
-    directory_map: Arc<Mutex<FolderHierarchy>>,
-    stop_requested: Arc<Mutex<bool>>,
+impl From<std::fs::FileType> for EntryKind {
+    fn from(file_type: std::fs::FileType) -> Self {
+        if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_dir() {
+            EntryKind::Directory
+        } else {
+            EntryKind::File
+        }
+    }
 }
 
-impl DirectoryScanner {
-    fn new() -> Self {
-        Self {
-            directory_map: Arc::new(Mutex::new(FolderHierarchy::default())),
-            stop_requested: Arc::new(Mutex::new(false)),
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FolderHierarchy {
+    pub value: u64,
+    pub name: String,
+    pub path: String,
+    pub entry_type: EntryKind,
+    /// Last modification time, serialized as milliseconds since the Unix epoch.
+    #[serde(default, with = "epoch_millis")]
+    pub modified: Option<SystemTime>,
+    /// Creation time, serialized the same way. `None` where the platform or file system does not record it.
+    #[serde(default, with = "epoch_millis")]
+    pub created: Option<SystemTime>,
+    /// Unix mode bits, such as `0o100644` for a regular file readable by everyone. `None` on other platforms.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Where a symlink points, as stored in the link. `None` for other entries.
+    #[serde(default)]
+    pub symlink_target: Option<String>,
+    /// Kind of content recognised from the first bytes of a file, such as `image`, `video`,
+    /// `audio`, `document`, `archive`, `font`, `text` or `application`. Only looked for with
+    /// `ScanOptions::detect_content_type`; `None` for other entries and unrecognised files.
+    #[serde(default)]
+    pub category: Option<String>,
+    /// xxHash3 of a file's contents, as 16 hexadecimal digits. Only computed with
+    /// `ScanOptions::hash_files`; `None` for other entries and files that could not be read.
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// User and group owning the entry on Unix. Only read with `ScanOptions::read_owners`;
+    /// `None` otherwise and on other platforms.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Number of files in this subtree, 1 for a file.
+    #[serde(default)]
+    pub file_count: u64,
+    /// Number of directories in this subtree, not counting this one.
+    #[serde(default)]
+    pub dir_count: u64,
+    /// Whether anything was counted below this node, even if `children` was left out, such as
+    /// below the depth limit, so that a tree view can tell which directories can be expanded.
+    #[serde(default)]
+    pub has_children: bool,
+    /// Share of the parent's `value` this node makes up, from 0 to 100. 100 for the scanned
+    /// root, and 0 for every child of a parent of size 0. Only set once the parent has been
+    /// fully scanned, so directories still being scanned or just streamed report 100.
+    #[serde(default)]
+    pub percent_of_parent: f32,
+    /// When the scan of this root started, in milliseconds since the Unix epoch. Only set on
+    /// the scanned root, once its scan has finished, and 0 otherwise.
+    #[serde(default)]
+    pub scanned_at: u64,
+    /// How long the scan of this root took, in milliseconds rounded up, so that only nodes it
+    /// is not set on report 0. Set along with `scanned_at`.
+    #[serde(default)]
+    pub scan_duration_ms: u64,
+    /// Whether the tree of this root holds everything below it or was cut short, and why.
+    /// Set along with `scanned_at`, and `None` on every other node.
+    #[serde(default)]
+    pub completeness: Option<Completeness>,
+    pub children: Vec<FolderHierarchy>,
+}
+
+impl FolderHierarchy {
+    fn directory(path: &Path) -> Self {
+        FolderHierarchy {
+            name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            path: display_path(path),
+            entry_type: EntryKind::Directory,
+            percent_of_parent: 100.0,
+            ..Default::default()
+        }
+    }
+
+    fn file(path: &Path, entry_type: EntryKind, metadata: &EntryMetadata) -> Self {
+        FolderHierarchy {
+            value: metadata.len,
+            name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            path: display_path(path),
+            entry_type,
+            modified: metadata.modified,
+            created: metadata.created,
+            mode: metadata.mode,
+            symlink_target: None,
+            category: None,
+            hash: None,
+            uid: None,
+            gid: None,
+            file_count: 1,
+            dir_count: 0,
+            has_children: false,
+            percent_of_parent: 100.0,
+            scanned_at: 0,
+            scan_duration_ms: 0,
+            completeness: None,
+            children: vec![],
+        }
+    }
+
+    /// Copy of this node without its children.
+    fn without_children(&self) -> Self {
+        FolderHierarchy {
+            value: self.value,
+            name: self.name.clone(),
+            path: self.path.clone(),
+            entry_type: self.entry_type,
+            modified: self.modified,
+            created: self.created,
+            mode: self.mode,
+            symlink_target: self.symlink_target.clone(),
+            category: self.category.clone(),
+            hash: self.hash.clone(),
+            uid: self.uid,
+            gid: self.gid,
+            file_count: self.file_count,
+            dir_count: self.dir_count,
+            has_children: self.has_children,
+            percent_of_parent: self.percent_of_parent,
+            scanned_at: self.scanned_at,
+            scan_duration_ms: self.scan_duration_ms,
+            completeness: self.completeness,
+            children: vec![],
+        }
+    }
+
+    /// Copy of this node keeping `levels` levels of descendants. Truncated nodes keep their totals.
+    fn truncated(&self, levels: usize) -> Self {
+        match levels {
+            0 => self.without_children(),
+            _ => FolderHierarchy {
+                children: self.children.iter().map(|child| child.truncated(levels - 1)).collect(),
+                ..self.without_children()
+            },
+        }
+    }
+
+    /// Copy of this node where the children of each directory totalling less than `min_bytes`
+    /// are folded into a single `(other)` entry carrying their combined size and counts.
+    pub fn pruned_below(&self, min_bytes: u64) -> Self {
+        let mut pruned = self.without_children();
+        let mut other: Option<FolderHierarchy> = None;
+        for child in &self.children {
+            if child.value >= min_bytes {
+                pruned.children.push(child.pruned_below(min_bytes));
+                continue;
+            }
+            let other = other.get_or_insert_with(|| FolderHierarchy {
+                name: "(other)".to_owned(),
+                path: display_path(&Path::new(&self.path).join("(other)")),
+                entry_type: EntryKind::File,
+                ..Default::default()
+            });
+            other.value += child.value;
+            other.file_count += child.file_count;
+            other.dir_count += child.dir_count + u64::from(child.entry_type == EntryKind::Directory);
+        }
+        pruned.children.extend(other);
+        pruned.update_percentages();
+        pruned
+    }
+
+    /// Copy of this node where every chain of directories below it holding nothing but the next
+    /// one, as in Java package trees, is merged into a single node named like `a/b/c/d`. The
+    /// merged node has the path and children of the deepest directory, and the totals of the
+    /// first, which are the same unless entries were left out of the hierarchy.
+    pub fn collapsed_chains(&self) -> Self {
+        let mut collapsed = self.without_children();
+        for child in &self.children {
+            let mut name = child.name.clone();
+            let mut deepest = child;
+            while let Some(only) = deepest.only_subdirectory() {
+                name = format!("{name}/{}", only.name);
+                deepest = only;
+            }
+            collapsed.children.push(FolderHierarchy {
+                name,
+                value: child.value,
+                file_count: child.file_count,
+                dir_count: child.dir_count,
+                percent_of_parent: child.percent_of_parent,
+                ..deepest.collapsed_chains()
+            });
+        }
+        collapsed
+    }
+
+    /// The subdirectory of a directory holding nothing else, even among entries left out of the hierarchy.
+    fn only_subdirectory(&self) -> Option<&FolderHierarchy> {
+        match self.children.as_slice() {
+            [only] if self.entry_type == EntryKind::Directory
+                && only.entry_type == EntryKind::Directory
+                && (only.value, only.file_count, only.dir_count + 1) == (self.value, self.file_count, self.dir_count) =>
+            {
+                Some(only)
+            }
+            _ => None,
+        }
+    }
+
+    /// Overall totals for this subtree.
+    pub fn summary(&self) -> ScanSummary {
+        ScanSummary {
+            bytes: self.value,
+            files: self.file_count,
+            dirs: self.dir_count,
+            max_depth: self.depth(),
+            scanned_at: self.scanned_at,
+            scan_duration_ms: self.scan_duration_ms,
+        }
+    }
+
+    /// Approximate bytes of memory this subtree takes up: the nodes themselves, the space
+    /// allocated for their children and the text of their strings.
+    pub fn memory_estimate(&self) -> u64 {
+        let node_size = std::mem::size_of::<FolderHierarchy>();
+        let allocated: usize = self
+            .iter()
+            .map(|node| {
+                node.children.capacity() * node_size
+                    + node.name.capacity()
+                    + node.path.capacity()
+                    + node.symlink_target.as_ref().map_or(0, String::capacity)
+                    + node.category.as_ref().map_or(0, String::capacity)
+                    + node.hash.as_ref().map_or(0, String::capacity)
+            })
+            .sum();
+        (node_size + allocated) as u64
+    }
+
+    /// Levels of descendants kept below this node, 0 for a node without children.
+    fn depth(&self) -> usize {
+        self.iter_with_depth().map(|(depth, _)| depth).max().unwrap_or_default()
+    }
+
+    /// Every node of this subtree, itself first, in depth-first pre-order: each node comes
+    /// before its children, and its children in the order they are kept.
+    pub fn iter(&self) -> FolderHierarchyIter<'_> {
+        FolderHierarchyIter { pending: vec![(0, self)] }
+    }
+
+    /// Same as `iter`, along with the level of each node below this one, this one being level 0.
+    pub fn iter_with_depth(&self) -> impl Iterator<Item = (usize, &FolderHierarchy)> {
+        let mut nodes = self.iter();
+        std::iter::from_fn(move || nodes.next_with_depth())
+    }
+
+    /// The `n` largest files in this subtree, largest first. Only entries kept in the hierarchy are considered.
+    pub fn largest_files(&self, n: usize) -> Vec<FolderHierarchy> {
+        // A min-heap of the largest files seen so far, so the smallest of them is evicted first.
+        let mut largest = BinaryHeap::with_capacity(n + 1);
+        for file in self.files() {
+            largest.push(Reverse(BySize(file)));
+            if largest.len() > n {
+                largest.pop();
+            }
+        }
+
+        let mut largest: Vec<_> = largest.into_iter().map(|Reverse(BySize(file))| file.without_children()).collect();
+        largest.sort_by(|a, b| BySize(b).cmp(&BySize(a)));
+        largest
+    }
+
+    /// The files in this subtree whose name contains `query`, ignoring case, largest first and
+    /// at most `limit` of them. Only entries kept in the hierarchy are considered.
+    pub fn search_files(&self, query: &str, limit: usize) -> Vec<FolderHierarchy> {
+        let query = query.to_lowercase();
+        let mut matches: Vec<_> = self.files().filter(|file| file.name.to_lowercase().contains(&query)).map(BySize).collect();
+        matches.sort_by(|a, b| b.cmp(a));
+        matches.into_iter().take(limit).map(|BySize(file)| file.without_children()).collect()
+    }
+
+    /// Number and total size of the files in this subtree by lowercased extension, files without
+    /// one being grouped under an empty key. Only entries kept in the hierarchy are considered.
+    pub fn extension_histogram(&self) -> BTreeMap<String, ExtensionStats> {
+        let mut histogram = BTreeMap::<String, ExtensionStats>::new();
+        for file in self.files() {
+            let extension = Path::new(&file.name).extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+            let stats = histogram.entry(extension).or_default();
+            stats.count += 1;
+            stats.total_bytes += file.value;
+        }
+        histogram
+    }
+
+    /// Total size of the files in this subtree by the uid owning them. Only files scanned with
+    /// `ScanOptions::read_owners` are counted, and only entries kept in the hierarchy are considered.
+    pub fn usage_by_user(&self) -> BTreeMap<u32, u64> {
+        let mut usage = BTreeMap::new();
+        for file in self.files() {
+            if let Some(uid) = file.uid {
+                *usage.entry(uid).or_default() += file.value;
+            }
+        }
+        usage
+    }
+
+    /// Groups of files in this subtree with the same size and content hash, the groups wasting
+    /// the most space first and the paths of each sorted. Only files hashed by the scan and
+    /// counted with a size above 0 are considered, so later hard links to a file are left out
+    /// with `ScanOptions::dedup_hardlinks`.
+    pub fn duplicate_files(&self) -> Vec<DuplicateFiles> {
+        let mut groups = BTreeMap::<(u64, &str), Vec<String>>::new();
+        for file in self.files().filter(|file| file.value > 0) {
+            if let Some(hash) = &file.hash {
+                groups.entry((file.value, hash)).or_default().push(file.path.clone());
+            }
+        }
+
+        let mut duplicates: Vec<_> = groups
+            .into_iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|((value, hash), mut paths)| {
+                paths.sort();
+                DuplicateFiles { hash: hash.to_owned(), value, paths }
+            })
+            .collect();
+        duplicates.sort_by_key(|group| Reverse(group.value * (group.paths.len() as u64 - 1)));
+        duplicates
+    }
+
+    /// Paths of the directories in this subtree, itself included, that contain no files. With
+    /// `recursive`, a directory holding only such directories counts as well; otherwise only
+    /// directories with no entries at all do. Only files counted by the scan are considered.
+    pub fn empty_directories(&self, recursive: bool) -> Vec<String> {
+        self.iter()
+            .filter(|node| node.entry_type == EntryKind::Directory && node.file_count == 0 && (recursive || node.dir_count == 0))
+            .map(|node| node.path.clone())
+            .collect()
+    }
+
+    /// Replaces the node at `relative`, names below this node separated by `/` as in a root
+    /// key, keeping its name and path, and brings the totals of every node above it in line.
+    /// Returns `false` if there is no such node.
+    fn replace_below(&mut self, relative: &str, mut replacement: FolderHierarchy) -> bool {
+        let mut indices = Vec::new();
+        let mut node = &*self;
+        for name in relative.split('/').filter(|name| !name.is_empty()) {
+            let Some(index) = node.children.iter().position(|child| canonical_name(&child.name) == name) else {
+                return false;
+            };
+            indices.push(index);
+            node = &node.children[index];
+        }
+
+        let (value, file_count, dir_count) = (node.value, node.file_count, node.dir_count);
+        let mut node = &mut *self;
+        for &index in &indices {
+            node.value = node.value - value + replacement.value;
+            node.file_count = node.file_count - file_count + replacement.file_count;
+            node.dir_count = node.dir_count - dir_count + replacement.dir_count;
+            node = &mut node.children[index];
+        }
+        replacement.name = std::mem::take(&mut node.name);
+        replacement.path = std::mem::take(&mut node.path);
+        *node = replacement;
+
+        let mut node = self;
+        for index in indices {
+            node.update_percentages();
+            node = &mut node.children[index];
+        }
+        node.update_percentages();
+        true
+    }
+
+    /// Copy of this subtree with the children of every node in the given order.
+    pub fn sorted(&self, order: SortOrder) -> Self {
+        let mut sorted = self.clone();
+        sorted.sort_children(order);
+        sorted
+    }
+
+    fn sort_children(&mut self, order: SortOrder) {
+        if order == SortOrder::None {
+            return;
+        }
+        let mut pending = vec![self];
+        while let Some(node) = pending.pop() {
+            order.sort(&mut node.children);
+            pending.extend(node.children.iter_mut());
+        }
+    }
+
+    /// Every node of this subtree as a flat list of rows referring to their parent by id.
+    /// Ids are assigned in depth-first order, the root being 0, so parents come before their children.
+    pub fn flattened(&self) -> Vec<FlatEntry> {
+        let mut rows = Vec::new();
+        let mut pending = vec![(self, None)];
+        while let Some((node, parent_id)) = pending.pop() {
+            let id = rows.len();
+            rows.push(FlatEntry { id, parent_id, name: node.name.clone(), value: node.value });
+            pending.extend(node.children.iter().rev().map(|child| (child, Some(id))));
+        }
+        rows
+    }
+
+    /// Every file kept in this subtree, in the order they appear in the hierarchy.
+    fn files(&self) -> impl Iterator<Item = &FolderHierarchy> {
+        self.iter().filter(|node| node.entry_type != EntryKind::Directory && node.children.is_empty())
+    }
+
+    /// Writes one `path,name,size_bytes,modified` CSV row per file in this subtree, after a header row.
+    /// `modified` is in milliseconds since the Unix epoch, and empty when unknown.
+    pub fn write_csv(&self, mut writer: impl std::io::Write) -> io::Result<()> {
+        writeln!(writer, "path,name,size_bytes,modified")?;
+        for file in self.files() {
+            let modified = file.modified.map(|time| epoch_millis::from_time(time).to_string()).unwrap_or_default();
+            writeln!(writer, "{},{},{},{}", csv_field(&file.path), csv_field(&file.name), file.value, modified)?;
+        }
+        writer.flush()
+    }
+
+    /// Writes this subtree in the compact binary format read back by `read_binary`, a header
+    /// holding `SCHEMA_VERSION` followed by the nodes encoded with bincode.
+    pub fn write_binary(&self, mut writer: impl std::io::Write) -> io::Result<()> {
+        writer.write_all(BINARY_MAGIC)?;
+        writer.write_all(&SCHEMA_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, self).map_err(|e| bincode_error(*e))?;
+        writer.flush()
+    }
+
+    /// Reads a subtree written by `write_binary`.
+    ///
+    /// Unlike JSON, the encoding has no field names to go by, so data written with another
+    /// `SCHEMA_VERSION` is rejected with `InvalidData` rather than misread.
+    pub fn read_binary(mut reader: impl std::io::Read) -> io::Result<Self> {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Not a binary directory map"))?;
+        if &header[..4] != BINARY_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a binary directory map"));
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().expect("Four bytes"));
+        if version != SCHEMA_VERSION {
+            let message = format!("Binary directory map has schema version {version}, expected {SCHEMA_VERSION}");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
         }
+        bincode::deserialize_from(reader).map_err(|e| bincode_error(*e))
     }
 
-    fn request_stop(&self) {
-        let mut stop = self.stop_requested.lock().expect("Lock poisoned");
-        *stop = true;
+    /// Adds a finished child's totals to this node, keeping the child itself only if `keep` is set.
+    fn add_child(&mut self, child: FolderHierarchy, keep: bool) {
+        self.value += child.value;
+        self.file_count += child.file_count;
+        self.dir_count += child.dir_count + u64::from(child.entry_type == EntryKind::Directory);
+        self.has_children = true;
+        if keep {
+            self.children.push(child);
+        }
     }
 
-    fn is_stop_requested(&self) -> bool {
-        *self.stop_requested.lock().expect("Lock poisoned")
+    /// Sets the `percent_of_parent` of the children from their share of this node's size,
+    /// once all of them have been added.
+    fn update_percentages(&mut self) {
+        let total = self.value;
+        for child in &mut self.children {
+            child.percent_of_parent = if total == 0 { 0.0 } else { (child.value as f64 / total as f64 * 100.0) as f32 };
+        }
     }
 }
 
-impl Drop for DirectoryScanner {
-    fn drop(&mut self) {
-        println!("Scanner is closing...");
+/// Start of the files written by `FolderHierarchy::write_binary`.
+const BINARY_MAGIC: &[u8; 4] = b"RDSB";
+
+fn bincode_error(error: bincode::ErrorKind) -> io::Error {
+    match error {
+        bincode::ErrorKind::Io(error) => error,
+        other => io::Error::new(io::ErrorKind::InvalidData, other),
+    }
+}
+
+/// Version of the library, as returned by `get_version`.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Version of the JSON shape of a hierarchy, given as `schema_version` at the top of what
+/// `get_directory_map` returns. Bumped whenever nodes gain, lose or change fields, so that hosts
+/// can tell which shape they are parsing. Output without the field is version 1.
+pub const SCHEMA_VERSION: u32 = 6;
+
+/// How the library was built, as returned by `get_build_info`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Target triple, such as `x86_64-unknown-linux-gnu`.
+    pub target: String,
+    /// `debug` or `release`.
+    pub profile: String,
+    /// Cargo features enabled, sorted.
+    pub features: Vec<String>,
+}
+
+/// Describes the build of the library this is running in.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: VERSION.to_owned(),
+        target: env!("BUILD_TARGET").to_owned(),
+        profile: env!("BUILD_PROFILE").to_owned(),
+        features: env!("BUILD_FEATURES").split(',').filter(|feature| !feature.is_empty()).map(str::to_owned).collect(),
+    }
+}
+
+/// How fast a scan is going, as returned by `get_scan_throughput`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScanThroughput {
+    /// Files and directories visited per second.
+    pub entries_per_sec: f64,
+    /// Bytes of files visited per second, going by their length.
+    pub bytes_per_sec: f64,
+    pub elapsed_ms: u64,
+}
+
+/// Iterator over a subtree returned by `FolderHierarchy::iter`.
+pub struct FolderHierarchyIter<'a> {
+    // Nodes still to visit with their level, the next one last.
+    pending: Vec<(usize, &'a FolderHierarchy)>,
+}
+
+impl<'a> FolderHierarchyIter<'a> {
+    fn next_with_depth(&mut self) -> Option<(usize, &'a FolderHierarchy)> {
+        let (depth, node) = self.pending.pop()?;
+        self.pending.extend(node.children.iter().rev().map(|child| (depth + 1, child)));
+        Some((depth, node))
+    }
+}
+
+impl<'a> Iterator for FolderHierarchyIter<'a> {
+    type Item = &'a FolderHierarchy;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_depth().map(|(_, node)| node)
     }
 }
 
-#[async_recursion]
-async fn scan_folder(directory_path: PathBuf, scanner: Arc<DirectoryScanner>) -> io::Result<FolderHierarchy> {
-    let mut entries = fs::read_dir(&directory_path).await?;
-    let mut children = Vec::new();
-    let mut total_size = 0;
+/// Totals of a scanned root, as returned by `get_scan_summary`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScanSummary {
+    pub bytes: u64,
+    pub files: u64,
+    /// Directories below the root, not counting the root itself.
+    pub dirs: u64,
+    /// Deepest level reached in the hierarchy, the root being level 0.
+    pub max_depth: usize,
+    /// `FolderHierarchy::scanned_at` of the root, 0 below it.
+    #[serde(default)]
+    pub scanned_at: u64,
+    /// `FolderHierarchy::scan_duration_ms` of the root, 0 below it.
+    #[serde(default)]
+    pub scan_duration_ms: u64,
+}
+
+/// Files sharing an extension, as counted by `get_extension_histogram`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExtensionStats {
+    pub count: u64,
+    pub total_bytes: u64,
+}
+
+/// Files with the same content, as listed by `get_duplicate_files`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateFiles {
+    pub hash: String,
+    /// Size of each of the files.
+    pub value: u64,
+    pub paths: Vec<String>,
+}
+
+/// A node of the hierarchy as a row of `get_directory_map_flat`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FlatEntry {
+    pub id: usize,
+    /// Id of the containing directory, `None` for the root.
+    pub parent_id: Option<usize>,
+    pub name: String,
+    pub value: u64,
+}
+
+/// What changed between two scans of the same tree, as returned by `get_scan_diff`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanDiff {
+    /// Entries only found in the new scan. Below an added directory, only the directory is listed.
+    pub added: Vec<String>,
+    /// Entries only found in the old scan, listed the same way.
+    pub removed: Vec<String>,
+    /// Entries found in both scans whose size changed, directories included.
+    pub changed: Vec<SizeChange>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SizeChange {
+    pub path: String,
+    pub old_size: u64,
+    pub new_size: u64,
+}
 
-    while let Some(entry) = entries.next_entry().await? {
-        if scanner.is_stop_requested() {
-            println!("Scanning stopped by request.");
-            return Ok(FolderHierarchy::default());
+/// Compares two scans of the same tree, matching entries by their path below the roots.
+/// Every list in the result is sorted by path.
+pub fn diff_scans(old: &FolderHierarchy, new: &FolderHierarchy) -> ScanDiff {
+    let mut diff = ScanDiff::default();
+    let mut pending = vec![(old, new)];
+    while let Some((old, new)) = pending.pop() {
+        if old.value != new.value {
+            diff.changed.push(SizeChange { path: new.path.clone(), old_size: old.value, new_size: new.value });
         }
 
-        let path = entry.path();
-        if path.is_dir() {
-            let child_hierarchy = scan_folder(path, Arc::clone(&scanner)).await?;
-            total_size += child_hierarchy.value;
-            children.push(child_hierarchy);
-        } else if let Ok(metadata) = path.metadata() {
-            total_size += metadata.len();
+        let mut old_children: BTreeMap<&str, &FolderHierarchy> = old.children.iter().map(|child| (child.name.as_str(), child)).collect();
+        for new_child in &new.children {
+            match old_children.remove(new_child.name.as_str()) {
+                Some(old_child) => pending.push((old_child, new_child)),
+                None => diff.added.push(new_child.path.clone()),
+            }
         }
+        diff.removed.extend(old_children.into_values().map(|old_child| old_child.path.clone()));
     }
 
-    let name = directory_path.file_name()
-                  .and_then(|n| n.to_str())
-                  .unwrap_or("")
-                  .to_string();
-    let path = directory_path.to_string_lossy().into_owned();
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort_by(|a, b| a.path.cmp(&b.path));
+    diff
+}
 
-    Ok(FolderHierarchy {
-        value: total_size,
-        name,
-        path,
-        children,
-    })
+/// An entry the scan could not read, as returned by `get_scan_errors`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ScanError {
+    pub path: String,
+    pub message: String,
+    #[serde(default)]
+    pub kind: ScanErrorKind,
+    /// The path as the platform stores it, for `LossyPath` entries whose `path` may be wrong.
+    /// Raw bytes on Unix, and WTF-8, UTF-8 extended to unpaired surrogates, on Windows.
+    #[serde(default)]
+    pub raw_path: Option<Vec<u8>>,
 }
 
-#[no_mangle]
-pub extern "C" fn create_directory_scanner() -> *mut DirectoryScanner {
-    let scanner = DirectoryScanner {
-        directory_map: Arc::new(Mutex::new(FolderHierarchy::default())),
-        stop_requested: Arc::new(Mutex::new(false)),
-    };
+/// How much of what lies below a scanned root its tree holds, as set in
+/// `FolderHierarchy::completeness`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Completeness {
+    /// Every entry the options let through was scanned.
+    Complete,
+    /// Directories at `ScanOptions::max_depth` were counted without keeping their contents.
+    DepthLimited,
+    /// Entries were left out once `ScanOptions::max_nodes` was reached.
+    NodeLimited,
+    /// The scan was stopped, so directories still being scanned were dropped.
+    Stopped,
+    /// The scan ran past `ScanOptions::timeout`, keeping what was published before it.
+    TimedOut,
+}
 
-    let arc = Arc::new(scanner);
+/// What went wrong with an entry, as returned by `get_last_error_code`.
+#[repr(i32)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScanErrorKind {
+    PermissionDenied = 1,
+    NotFound = 2,
+    /// A path passed in by the host was not valid UTF-8.
+    InvalidUtf8Path = 3,
+    /// Any other I/O error.
+    #[default]
+    Io = 4,
+    /// The scan or a read was cut short, such as by the scan's timeout or a read timing out.
+    Interrupted = 5,
+    /// The name of an entry found on disk is not valid UTF-8, so it is reported with
+    /// replacement characters. The entry is still scanned.
+    LossyPath = 6,
+}
 
-    Arc::into_raw(arc) as *mut DirectoryScanner
+impl ScanErrorKind {
+    /// Whether the same read could succeed if tried again, as on a flaky network mount. Only reads
+    /// that were interrupted, timed out or would have blocked are; other errors are not expected to go away.
+    pub fn is_transient(self) -> bool {
+        self == ScanErrorKind::Interrupted
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn free_directory_scanner(scanner_ptr: *mut DirectoryScanner) {
-    // Safety: Ensure the provided pointer is valid and not null
-    if !scanner_ptr.is_null() {
-        // Convert the raw pointer back to an Arc, which will be dropped at the end of this scope
-        // Dropping the last Arc will free the DirectoryScanner
-        unsafe { Arc::from_raw(scanner_ptr) };
+impl From<io::ErrorKind> for ScanErrorKind {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::PermissionDenied => ScanErrorKind::PermissionDenied,
+            io::ErrorKind::NotFound => ScanErrorKind::NotFound,
+            io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => ScanErrorKind::Interrupted,
+            _ => ScanErrorKind::Io,
+        }
     }
 }
 
+#[cfg(unix)]
+fn mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
 
-#[no_mangle]
-pub extern "C" fn scan_directory_async(scanner_ptr: *const Arc<DirectoryScanner>, path_ptr: *const c_char) {
-    let scanner = unsafe {
-        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
-        &*scanner_ptr
-    };
+    Some(metadata.mode())
+}
 
-    let c_str = unsafe { CStr::from_ptr(path_ptr) };
-    let path_str = match c_str.to_str() {
-        Ok(str) => str,
-        Err(_) => {
-            eprintln!("Invalid string passed to scan_directory_async");
-            return;
-        }
-    };
-    let directory_path = PathBuf::from(path_str);
+#[cfg(not(unix))]
+fn mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
 
-    let directory_map_clone = Arc::clone(&scanner.directory_map);
+#[cfg(unix)]
+fn owner(metadata: &std::fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
 
-    let scanner_clone = Arc::clone(scanner);
+    Some((metadata.uid(), metadata.gid()))
+}
 
-    std::thread::spawn(move || {
-        let runtime = Runtime::new().unwrap();
-        runtime.block_on(async {
-            let root_hierarchy = FolderHierarchy {
-                value: 0, 
-                name: directory_path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
-                path: directory_path.to_string_lossy().into_owned(),
-                children: vec![],
-            };
-            let mut entries = fs::read_dir(directory_path.clone()).await.unwrap();
-
-            let mut directory_map = directory_map_clone.lock().unwrap();
-            *directory_map = root_hierarchy;
-
-            while let Some(entry) = entries.next_entry().await.unwrap() {
-                let path = entry.path();
-
-                if path.is_dir() {
-                    let sub_hierarchy = scan_folder(path, Arc::clone(&scanner_clone)).await.unwrap();
-                    directory_map.value += sub_hierarchy.value;
-                    directory_map.children.push(sub_hierarchy);
-                } else {
-                    match path.metadata() {
-                        Ok(metadata) => {
-                            directory_map.value += metadata.len();
-                            let file_entry = FolderHierarchy {
-                                value: metadata.len(),
-                                name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
-                                path: path.parent().unwrap_or_else(|| Path::new("")).to_string_lossy().into_owned(),
-                                children: vec![],
-                            };
-                            directory_map.children.push(file_entry);
-                        },
-                        Err(e) => eprintln!("Failed to read metadata for {:?}: {}", path, e),
-                    }
-                }
-            }
-        });
-    });
+#[cfg(not(unix))]
+fn owner(_metadata: &std::fs::Metadata) -> Option<(u32, u32)> {
+    None
 }
 
-#[no_mangle]
-pub extern "C" fn get_directory_map(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, depth: i32) -> *mut c_char {
-    let scanner = unsafe {
-        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
-        &*scanner_ptr
-    };
+#[cfg(unix)]
+fn device(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
 
-    let path_str = unsafe {
-        assert!(!path_ptr.is_null(), "Path pointer is null.");
-        CStr::from_ptr(path_ptr)
-            .to_str()
-            .expect("Invalid UTF-8 in path")
-            .replace("\\", "/")
-    };
+    Some(metadata.dev())
+}
 
-    // Attempt to acquire the lock.
-    let guard = match scanner.directory_map.lock() {
-        Ok(g) => g,
-        Err(e) => {
-            // Handle lock poisoning or other errors.
-            eprintln!("Failed to lock directory_map: {}", e);
-            return CString::new("{\"error\":\"internal error\"}").unwrap().into_raw();
-        }
-    };
-
-    // Quickly clone the data needed and release the lock.
-    let directory_map = (*guard).clone();
-
-    // Process the directory_map to generate JSON.
-    let json = if directory_map.path.replace("\\", "/") == path_str {
-        let hierarchy = match depth {
-            0 => FolderHierarchy {
-                value: directory_map.value,
-                name: directory_map.name.clone(),
-                path: directory_map.path.clone(),
-                children: directory_map.children.iter().map(|child| FolderHierarchy {
-                    value: child.value,
-                    name: child.name.clone(),
-                    path: child.path.clone(),
-                    children: vec![],
-                }).collect(),
-            },
-            1 => directory_map.clone(),
-            _ => FolderHierarchy::default(),
-        };
-        serde_json::to_string(&hierarchy).unwrap_or_else(|e| format!("{{\"error\": \"Serialization error: {}\"}}", e))
-    } else {
-        format!("{{\"error\": \"Root folder not found\"}}")
+#[cfg(not(unix))]
+fn device(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Whether the directory at `path` is on another device than the directory containing it.
+/// Every directory is vetted on the way down, so this keeps a scan on its root's file system.
+async fn crosses_filesystem(path: &Path, file_system: &dyn FileSystem) -> bool {
+    let Some(parent) = path.parent() else {
+        return false;
     };
+    let device = |path| async move { file_system.metadata(path).await.ok().and_then(|metadata| metadata.device) };
+    device(path).await != device(parent).await
+}
 
-    CString::new(json).unwrap().into_raw()
+/// Bytes allocated to the file on disk.
+#[cfg(unix)]
+fn disk_usage(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.blocks() * 512
 }
 
-pub extern "C" fn stop_scanning(scanner_ptr: *const DirectoryScanner) {
-    if scanner_ptr.is_null() {
-        eprintln!("Scanner pointer is null.");
-        return;
-    }
+#[cfg(not(unix))]
+fn disk_usage(metadata: &std::fs::Metadata) -> u64 {
+    metadata.len()
+}
 
-    let scanner = unsafe { &*scanner_ptr };
-    scanner.request_stop();
+/// `(device, inode)` of a file with more than one hard link.
+#[cfg(unix)]
+fn hard_link(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempdir;
-    use std::fs::File;
-    use std::io::Write;
-    use std::path::PathBuf;
-    use std::ffi::{CString, CStr};
-    use std::thread;
-    use tokio::fs;
-    use std::time::Duration;
-    use std::sync::Arc;
+#[cfg(not(unix))]
+fn hard_link(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
 
-    async fn create_test_directory_structure(base_dir: &PathBuf) -> io::Result<()> {
-        fs::create_dir_all(base_dir.join("subfolder1/subsubfolder1")).await?;
-        fs::create_dir_all(base_dir.join("subfolder2")).await?;
-        fs::create_dir_all(base_dir.join("subfolder2/subsubfolder2a")).await?;
-        fs::create_dir_all(base_dir.join("subfolder2/subsubfolder2b")).await?;
-    
-        let mut file1 = File::create(base_dir.join("subfolder1/test_file1.txt"))?;
-        let mut file2 = File::create(base_dir.join("subfolder1/subsubfolder1/test_file2.txt"))?;
-        let mut file3 = File::create(base_dir.join("subfolder2/test_file3.txt"))?;
-    
-        writeln!(file1, "Hello, world!")?;
-        writeln!(file2, "Hello, Rust!")?;
-        writeln!(file3, "Hello, Testing!")?;
-    
-        Ok(())
+/// What an entry read without following links is. On Windows, directories that are reparse
+/// points, such as junctions, lead elsewhere like symlinks do, so they are recorded as links
+/// and only walked with `ScanOptions::follow_symlinks`.
+#[cfg(windows)]
+fn unfollowed_kind(file_type: EntryKind, metadata: &std::fs::Metadata) -> EntryKind {
+    use std::os::windows::fs::MetadataExt;
+
+    const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+    if metadata.is_dir() && metadata.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT != 0 {
+        EntryKind::Symlink
+    } else {
+        file_type
     }
-    
-    #[tokio::test]
-    async fn test_scan_and_get_directory_map() {
-        let temp_dir = tempdir().expect("Failed to create a temporary directory");
-        let test_path = temp_dir.path();
+}
 
-        create_test_directory_structure(&temp_dir.path().to_path_buf()).await.unwrap();
+#[cfg(not(windows))]
+fn unfollowed_kind(file_type: EntryKind, _metadata: &std::fs::Metadata) -> EntryKind {
+    file_type
+}
 
-        let scanner = Arc::new(DirectoryScanner::new());
+/// What a scan goes by for an entry, as read by a `FileSystem`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntryMetadata {
+    /// What the entry is, after following it if it was read following symlinks.
+    pub kind: EntryKind,
+    /// Length in bytes.
+    pub len: u64,
+    /// Bytes allocated on disk, counted instead of the length with `ScanOptions::on_disk_size`.
+    pub allocated: u64,
+    pub modified: Option<SystemTime>,
+    pub created: Option<SystemTime>,
+    /// Unix mode bits. `None` on other platforms.
+    pub mode: Option<u32>,
+    /// `(uid, gid)` owning the entry on Unix. `None` on other platforms.
+    pub owner: Option<(u32, u32)>,
+    /// `(device, inode)` of a file with more than one hard link, for
+    /// `ScanOptions::dedup_hardlinks`. `None` for other entries.
+    pub hard_link: Option<(u64, u64)>,
+    /// Device holding the entry on Unix, for `ScanOptions::one_filesystem`. `None` on other platforms.
+    pub device: Option<u64>,
+}
 
-        let scanner_arc = &scanner as *const _;
+impl From<&std::fs::Metadata> for EntryMetadata {
+    fn from(metadata: &std::fs::Metadata) -> Self {
+        EntryMetadata {
+            kind: EntryKind::from(metadata.file_type()),
+            len: metadata.len(),
+            allocated: disk_usage(metadata),
+            modified: metadata.modified().ok(),
+            created: metadata.created().ok(),
+            mode: mode(metadata),
+            owner: owner(metadata),
+            hard_link: hard_link(metadata),
+            device: device(metadata),
+        }
+    }
+}
 
-        let test_path_c = CString::new(test_path.to_str().unwrap()).expect("CString::new failed");
+/// An entry listed by `FileSystem::read_dir`.
+#[derive(Debug)]
+pub struct DirectoryEntry {
+    pub path: PathBuf,
+    /// What the entry itself is, symlinks not being followed.
+    pub kind: EntryKind,
+    /// Metadata of the entry itself, symlinks not being followed.
+    pub metadata: io::Result<EntryMetadata>,
+}
 
-        let scanner_ptr = Arc::into_raw(scanner);
+/// Where scans read directories and metadata from, set with
+/// `DirectoryScanner::open_with_file_system`.
+///
+/// `RealFileSystem`, the default, reads the disk through `tokio::fs`, and `MemoryFileSystem`
+/// serves a tree held in memory, so that unreadable directories or huge trees can be simulated.
+/// Symlink targets, file contents read for `ScanOptions::detect_content_type`,
+/// `ScanOptions::descend_archives` and `ScanOptions::hash_files`, and the checks behind `ScanOptions::one_filesystem` and,
+/// on Windows, hidden entries still go to the disk.
+pub trait FileSystem: Send + Sync {
+    /// Opens the directory at `path` for listing its entries.
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<BoxStream<'a, io::Result<DirectoryEntry>>>>;
 
-        scan_directory_async(scanner_arc, test_path_c.as_ptr());
+    /// Reads the metadata of `path`, following symlinks.
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<EntryMetadata>>;
 
-        thread::sleep(Duration::from_millis(10)); // Adjust as necessary.
+    /// Resolves `path` to the entry it leads to, following symlinks, so that two paths to the
+    /// same directory resolve alike.
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>>;
 
-        let scanner = unsafe { Arc::from_raw(scanner_ptr) };
+    /// Reads where the symlink at `path` points, without following it.
+    fn read_link<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>>;
+}
 
-        let result_ptr = get_directory_map(&*scanner, test_path_c.as_ptr(), 0);
-        assert!(!result_ptr.is_null(), "get_directory_map returned a null pointer");
+/// The disk, read through `tokio::fs`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealFileSystem;
 
-        let result_cstr = unsafe { CStr::from_ptr(result_ptr) };
-        let result_str = result_cstr.to_str().unwrap();
+impl FileSystem for RealFileSystem {
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<BoxStream<'a, io::Result<DirectoryEntry>>>> {
+        Box::pin(async move {
+            let entries = fs::read_dir(path).await?;
+            let entries = stream::unfold(Some(entries), |entries| async move {
+                let mut entries = entries?;
+                let entry = match entries.next_entry().await {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => return None,
+                    // Nothing more can be read once listing fails.
+                    Err(e) => return Some((Err(e), None)),
+                };
+                let file_type = entry.file_type().await.map_or(EntryKind::File, EntryKind::from);
+                let metadata = entry.metadata().await;
+                let kind = metadata.as_ref().map_or(file_type, |metadata| unfollowed_kind(file_type, metadata));
+                let metadata = metadata.map(|metadata| EntryMetadata { kind, ..EntryMetadata::from(&metadata) });
+                Some((Ok(DirectoryEntry { path: entry.path(), kind, metadata }), Some(entries)))
+            });
+            Ok(entries.boxed())
+        })
+    }
 
-        let directory_map: FolderHierarchy = serde_json::from_str(result_str).unwrap();
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<EntryMetadata>> {
+        Box::pin(async move { fs::metadata(path).await.map(|metadata| EntryMetadata::from(&metadata)) })
+    }
 
-        assert!(!directory_map.children.is_empty(), "The directory map childrens should not be empty");
-        
-        for folder in &directory_map.children {
-            assert!(
-                folder.children.iter().all(|child| child.children.is_empty()),
-                "All children of the folder should have an empty children array"
-            );
-        }
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+        Box::pin(fs::canonicalize(path))
+    }
+
+    fn read_link<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+        Box::pin(fs::read_link(path))
     }
- 
 }
 
+/// A tree held in memory, for simulating file systems in tests.
+///
+/// Entries are added with their full path, and the directories above them are created as
+/// needed. Symlinks are followed by `metadata` and `canonicalize` like on disk, up to 40 links
+/// deep, so that loops fail the way they do on disk. Paths are taken as given, without resolving
+/// `.` or `..`.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFileSystem {
+    entries: BTreeMap<PathBuf, MemoryEntry>,
+    denied: HashSet<PathBuf>,
+}
 
-fn main() {
-    // This function is a placeholder and won't be used when called from TypeScript.
+#[derive(Clone, Debug)]
+enum MemoryEntry {
+    Directory,
+    File(EntryMetadata),
+    Symlink(PathBuf),
 }
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an empty directory.
+    pub fn add_directory(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        let path = path.into();
+        self.add_parents(&path);
+        self.entries.insert(path, MemoryEntry::Directory);
+        self
+    }
+
+    /// Adds a file of `len` bytes.
+    pub fn add_file(&mut self, path: impl Into<PathBuf>, len: u64) -> &mut Self {
+        self.add_file_with_metadata(path, EntryMetadata { kind: EntryKind::File, len, allocated: len, ..Default::default() })
+    }
+
+    /// Adds a file described by `metadata`, for timestamps, modes or hard links.
+    pub fn add_file_with_metadata(&mut self, path: impl Into<PathBuf>, metadata: EntryMetadata) -> &mut Self {
+        let path = path.into();
+        self.add_parents(&path);
+        self.entries.insert(path, MemoryEntry::File(EntryMetadata { kind: EntryKind::File, ..metadata }));
+        self
+    }
+
+    /// Adds a symlink pointing at `target`, which need not exist.
+    pub fn add_symlink(&mut self, path: impl Into<PathBuf>, target: impl Into<PathBuf>) -> &mut Self {
+        let path = path.into();
+        self.add_parents(&path);
+        self.entries.insert(path, MemoryEntry::Symlink(target.into()));
+        self
+    }
+
+    /// Makes listing the directory at `path` fail with `PermissionDenied`.
+    pub fn deny(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.denied.insert(path.into());
+        self
+    }
+
+    fn add_parents(&mut self, path: &Path) {
+        for parent in path.ancestors().skip(1).filter(|parent| !parent.as_os_str().is_empty()) {
+            self.entries.entry(parent.to_path_buf()).or_insert(MemoryEntry::Directory);
+        }
+    }
+
+    fn own_metadata(entry: &MemoryEntry) -> EntryMetadata {
+        match entry {
+            MemoryEntry::Directory => EntryMetadata { kind: EntryKind::Directory, ..Default::default() },
+            MemoryEntry::File(metadata) => metadata.clone(),
+            MemoryEntry::Symlink(target) => {
+                let len = target.as_os_str().len() as u64;
+                EntryMetadata { kind: EntryKind::Symlink, len, allocated: len, ..Default::default() }
+            }
+        }
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<BoxStream<'a, io::Result<DirectoryEntry>>>> {
+        Box::pin(async move {
+            if self.denied.contains(path) {
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+            match self.entries.get(path) {
+                Some(MemoryEntry::Directory) => {}
+                Some(MemoryEntry::Symlink(target)) if self.metadata(target).await?.kind == EntryKind::Directory => return self.read_dir(target).await,
+                Some(_) => return Err(io::Error::from(io::ErrorKind::NotADirectory)),
+                None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+            }
+            let entries = self.entries.iter().filter(move |(entry_path, _)| entry_path.parent() == Some(path)).map(move |(entry_path, entry)| {
+                let metadata = Self::own_metadata(entry);
+                Ok(DirectoryEntry { path: path.join(entry_path.file_name().unwrap_or_default()), kind: metadata.kind, metadata: Ok(metadata) })
+            });
+            Ok(stream::iter(entries).boxed())
+        })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<EntryMetadata>> {
+        Box::pin(async move {
+            let path = self.canonicalize(path).await?;
+            Ok(Self::own_metadata(&self.entries[&path]))
+        })
+    }
+
+    fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+        Box::pin(async move {
+            let mut path = path.to_path_buf();
+            for _ in 0..40 {
+                match self.entries.get(&path) {
+                    Some(MemoryEntry::Symlink(target)) => path = path.parent().map_or_else(|| target.clone(), |parent| parent.join(target)),
+                    Some(_) => return Ok(path),
+                    None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+                }
+            }
+            Err(io::Error::other("Too many levels of symbolic links"))
+        })
+    }
+
+    fn read_link<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+        Box::pin(async move {
+            match self.entries.get(path) {
+                Some(MemoryEntry::Symlink(target)) => Ok(target.clone()),
+                Some(_) => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+                None => Err(io::Error::from(io::ErrorKind::NotFound)),
+            }
+        })
+    }
+}
+
+/// Formats a size in bytes for display with binary units, such as `1.5 KiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    // Move up a unit whenever rounding would otherwise show 1024.0 of the current one.
+    while size >= 1023.95 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Reads where `path` points if it is a symlink.
+async fn symlink_target(path: &Path, entry_type: EntryKind, file_system: &dyn FileSystem) -> Option<String> {
+    if entry_type != EntryKind::Symlink {
+        return None;
+    }
+    let target = file_system.read_link(path).await.ok()?;
+    Some(display_path(&target))
+}
+
+/// Quotes a CSV field if it contains a separator, a quote or a line break.
+fn csv_field(field: &str) -> std::borrow::Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// Orders entries by size, then by path so that ties are deterministic.
+struct BySize<'a>(&'a FolderHierarchy);
+
+impl Ord for BySize<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.value.cmp(&other.0.value).then_with(|| other.0.path.cmp(&self.0.path))
+    }
+}
+
+impl PartialOrd for BySize<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for BySize<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for BySize<'_> {}
+
+mod epoch_millis {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn from_time(time: SystemTime) -> u64 {
+        time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+
+    pub fn serialize<S: Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        time.map(from_time).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SystemTime>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)))
+    }
+}
+
+/// Order of the children of every directory, as set by `ScanOptions::sort` and passed to
+/// `get_directory_map_sorted`. Entries that compare equal keep their listing order.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    /// The order the file system lists entries in.
+    #[default]
+    None = 0,
+    /// Largest first.
+    SizeDesc = 1,
+    SizeAsc = 2,
+    /// Names compared character by character, so `B` comes before `a`.
+    NameAsc = 3,
+    NameDesc = 4,
+}
+
+impl SortOrder {
+    fn from_i32(value: i32) -> Self {
+        match value {
+            1 => SortOrder::SizeDesc,
+            2 => SortOrder::SizeAsc,
+            3 => SortOrder::NameAsc,
+            4 => SortOrder::NameDesc,
+            _ => SortOrder::None,
+        }
+    }
+
+    fn sort(self, entries: &mut [FolderHierarchy]) {
+        match self {
+            SortOrder::None => {}
+            SortOrder::SizeDesc => entries.sort_by_key(|entry| Reverse(entry.value)),
+            SortOrder::SizeAsc => entries.sort_by_key(|entry| entry.value),
+            SortOrder::NameAsc => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+            SortOrder::NameDesc => entries.sort_by(|a, b| b.name.cmp(&a.name)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScanOptions {
+    /// Deepest level whose children are kept in the hierarchy, the root being level 0.
+    /// Everything below is still walked so that sizes stay exact. `None` keeps the whole tree.
+    pub max_depth: Option<usize>,
+    /// When not empty, only files with one of these extensions are counted.
+    /// Matching ignores case and an optional leading dot.
+    pub include_extensions: Vec<String>,
+    /// Files with one of these extensions are skipped.
+    pub exclude_extensions: Vec<String>,
+    /// When not empty, only files whose path relative to the scanned root matches one of these
+    /// globs are listed, such as `**/*.log` or `cache_*.tmp`. Directories are always walked.
+    pub include_globs: Vec<String>,
+    /// Whether files not matching `include_globs` still count towards their parents' totals.
+    /// Off by default, so totals only cover the matching files.
+    pub count_unmatched_files: bool,
+    /// Descend into symlinked directories. Off by default, in which case links are
+    /// recorded as leaf entries. When on, directories already walked are skipped so
+    /// link cycles terminate. Directory junctions count as links on Windows.
+    pub follow_symlinks: bool,
+    /// Count hidden entries. On by default; when off, dotfiles are skipped, or entries with
+    /// the hidden attribute on Windows, along with everything below them.
+    pub include_hidden: bool,
+    /// Most directories read at the same time. Sibling directories are scanned concurrently,
+    /// and each one being read holds a directory handle open, so this also bounds file descriptors.
+    /// Values below 1 are treated as 1, which scans sequentially.
+    pub max_concurrency: usize,
+    /// Files smaller than this many bytes are left out of `children`. Directories are always walked.
+    pub min_size: Option<u64>,
+    /// Whether files below `min_size` still count towards their parents' totals. On by default,
+    /// so sizes stay exact; turn it off to only total up the files that are listed.
+    pub count_small_files: bool,
+    /// Directories whose name matches one of these globs are neither walked nor counted, e.g.
+    /// `node_modules`, `.*` or `build-[0-9]*`. An invalid glob fails the scan.
+    pub exclude_patterns: Vec<String>,
+    /// Abandons the scan once this much time has passed, leaving the state `TimedOut`.
+    /// Whatever was scanned by then stays in the map.
+    pub timeout: Option<Duration>,
+    /// Count the entries to scan in a quick first pass, so that `scan_progress_percent`
+    /// can tell how far along the scan is. Off by default, since the pass reads every
+    /// directory twice.
+    pub estimate_progress: bool,
+    /// Count the size of a file with several hard links only once, as `du` does. Every link
+    /// is still listed, but only the first one found carries the size. Only supported on Unix.
+    pub dedup_hardlinks: bool,
+    /// Count the space files take up on disk instead of their length, as `du` does. Sparse
+    /// files count for less and small files for a whole block. Falls back to the length on
+    /// platforms other than Unix.
+    pub on_disk_size: bool,
+    /// Stay on the file system of the scanned root, as `du -x` does. Directories on another
+    /// device, such as mount points, are neither walked nor counted. Only supported on Unix.
+    pub one_filesystem: bool,
+    /// Writes the map scanned so far to `snapshot_path` as JSON this often, and once more when
+    /// the scan ends, so that an interrupted scan can be recovered with `load_snapshot`.
+    /// Only used when `snapshot_path` is set too.
+    pub snapshot_interval: Option<Duration>,
+    /// File the snapshots are written to. Each snapshot replaces the previous one as a whole.
+    pub snapshot_path: Option<PathBuf>,
+    /// Reads the first bytes of every non-empty file to tell its `category` from magic numbers,
+    /// regardless of its extension. Off by default, as it costs a read per file.
+    pub detect_content_type: bool,
+    /// Hashes the contents of every file into its `hash`, so that `duplicate_files` can find
+    /// copies. Off by default, as it reads every file in full. Files are hashed on the blocking
+    /// pool, and count against `max_concurrency` like directories being read.
+    pub hash_files: bool,
+    /// Sets the `uid` and `gid` of every entry on Unix, so that `usage_by_user` can tell how
+    /// much each user stores. Taken from the metadata the scan reads anyway, so it costs no
+    /// extra calls; off by default only to keep the output small.
+    pub read_owners: bool,
+    /// How many more times a directory is read when reading it fails with a transient error,
+    /// see `ScanErrorKind::is_transient`, before the error is recorded and the directory skipped.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for every retry after it.
+    pub retry_backoff: Duration,
+    /// Lists the entries of `.zip` files as their children, under virtual paths inside the
+    /// archive, and counts an archive for the uncompressed size of its entries. The archive
+    /// itself stays a `File`. Archives that cannot be read are recorded as errors and counted
+    /// like any other file.
+    pub descend_archives: bool,
+    /// Most nodes kept in the hierarchy, the root included, to bound the memory a huge tree
+    /// takes. Entries are kept as they are found, so the levels closest to the root fill up first.
+    /// Once the limit is reached, further entries are only counted in the totals of their
+    /// nearest kept ancestor, so sizes and counts stay exact. `None` keeps every node.
+    pub max_nodes: Option<usize>,
+    /// Order the children of every directory are kept in.
+    pub sort: SortOrder,
+    /// Keeps only directories in the hierarchy, for a light skeleton of a huge tree. Files are
+    /// still counted in the totals of their directory, but not listed.
+    pub directories_only: bool,
+    /// Builds the new hierarchy apart and swaps it into the map in one go once the scan
+    /// completes, so that readers see either the previous complete tree or the new one, never
+    /// a partial one. A scan that is stopped, times out or fails leaves the previous tree in place.
+    pub atomic_swap: bool,
+    /// Gives the `path` of every node relative to the scanned root, the root itself being `.`,
+    /// so that the output is shorter and does not tell where the tree sits on the machine.
+    pub relative_paths: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            max_depth: None,
+            include_extensions: Vec::new(),
+            exclude_extensions: Vec::new(),
+            include_globs: Vec::new(),
+            count_unmatched_files: false,
+            follow_symlinks: false,
+            include_hidden: true,
+            max_concurrency: 16,
+            min_size: None,
+            count_small_files: true,
+            exclude_patterns: Vec::new(),
+            timeout: None,
+            estimate_progress: false,
+            dedup_hardlinks: false,
+            on_disk_size: false,
+            one_filesystem: false,
+            snapshot_interval: None,
+            snapshot_path: None,
+            detect_content_type: false,
+            hash_files: false,
+            read_owners: false,
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(100),
+            descend_archives: false,
+            max_nodes: None,
+            sort: SortOrder::None,
+            directories_only: false,
+            atomic_swap: false,
+            relative_paths: false,
+        }
+    }
+}
+
+impl ScanOptions {
+    /// Starts a `ScanOptionsBuilder` from the default options.
+    pub fn builder() -> ScanOptionsBuilder {
+        ScanOptionsBuilder::default()
+    }
+}
+
+/// Builds `ScanOptions` one setting at a time, starting from the defaults.
+///
+/// ```
+/// use directory_scanner::{DirectoryScanner, ScanOptions};
+/// use std::time::Duration;
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// std::fs::create_dir_all(dir.path().join("src/target")).unwrap();
+/// std::fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+/// std::fs::write(dir.path().join("src/notes.txt"), "todo").unwrap();
+/// std::fs::write(dir.path().join("src/target/app"), "binary").unwrap();
+///
+/// let options = ScanOptions::builder()
+///     .max_depth(1)
+///     .include_extension("rs")
+///     .exclude("target")
+///     .max_concurrency(4)
+///     .build();
+///
+/// let scanner = DirectoryScanner::open();
+/// scanner.scan_with_options(dir.path(), options);
+/// assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+///
+/// let hierarchy = scanner.directory_map();
+/// assert_eq!((hierarchy.value, hierarchy.file_count), (12, 1));
+/// assert!(hierarchy.children[0].children.is_empty());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptionsBuilder {
+    options: ScanOptions,
+}
+
+impl ScanOptionsBuilder {
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.options.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn include_extension(mut self, extension: impl Into<String>) -> Self {
+        self.options.include_extensions.push(extension.into());
+        self
+    }
+
+    pub fn exclude_extension(mut self, extension: impl Into<String>) -> Self {
+        self.options.exclude_extensions.push(extension.into());
+        self
+    }
+
+    pub fn include_glob(mut self, glob: impl Into<String>) -> Self {
+        self.options.include_globs.push(glob.into());
+        self
+    }
+
+    pub fn count_unmatched_files(mut self, count_unmatched_files: bool) -> Self {
+        self.options.count_unmatched_files = count_unmatched_files;
+        self
+    }
+
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.options.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    pub fn include_hidden(mut self, include_hidden: bool) -> Self {
+        self.options.include_hidden = include_hidden;
+        self
+    }
+
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.options.max_concurrency = max_concurrency;
+        self
+    }
+
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.options.min_size = Some(min_size);
+        self
+    }
+
+    pub fn count_small_files(mut self, count_small_files: bool) -> Self {
+        self.options.count_small_files = count_small_files;
+        self
+    }
+
+    /// Adds a directory name pattern to `exclude_patterns`.
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.options.exclude_patterns.push(pattern.into());
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    pub fn estimate_progress(mut self, estimate_progress: bool) -> Self {
+        self.options.estimate_progress = estimate_progress;
+        self
+    }
+
+    pub fn dedup_hardlinks(mut self, dedup_hardlinks: bool) -> Self {
+        self.options.dedup_hardlinks = dedup_hardlinks;
+        self
+    }
+
+    pub fn on_disk_size(mut self, on_disk_size: bool) -> Self {
+        self.options.on_disk_size = on_disk_size;
+        self
+    }
+
+    pub fn one_filesystem(mut self, one_filesystem: bool) -> Self {
+        self.options.one_filesystem = one_filesystem;
+        self
+    }
+
+    pub fn detect_content_type(mut self, detect_content_type: bool) -> Self {
+        self.options.detect_content_type = detect_content_type;
+        self
+    }
+
+    pub fn hash_files(mut self, hash_files: bool) -> Self {
+        self.options.hash_files = hash_files;
+        self
+    }
+
+    pub fn read_owners(mut self, read_owners: bool) -> Self {
+        self.options.read_owners = read_owners;
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.options.max_retries = max_retries;
+        self
+    }
+
+    pub fn retry_backoff(mut self, retry_backoff: Duration) -> Self {
+        self.options.retry_backoff = retry_backoff;
+        self
+    }
+
+    pub fn descend_archives(mut self, descend_archives: bool) -> Self {
+        self.options.descend_archives = descend_archives;
+        self
+    }
+
+    pub fn max_nodes(mut self, max_nodes: usize) -> Self {
+        self.options.max_nodes = Some(max_nodes);
+        self
+    }
+
+    pub fn sort(mut self, sort: SortOrder) -> Self {
+        self.options.sort = sort;
+        self
+    }
+
+    pub fn directories_only(mut self, directories_only: bool) -> Self {
+        self.options.directories_only = directories_only;
+        self
+    }
+
+    pub fn atomic_swap(mut self, atomic_swap: bool) -> Self {
+        self.options.atomic_swap = atomic_swap;
+        self
+    }
+
+    pub fn relative_paths(mut self, relative_paths: bool) -> Self {
+        self.options.relative_paths = relative_paths;
+        self
+    }
+
+    /// Sets both `snapshot_interval` and `snapshot_path`.
+    pub fn snapshots(mut self, interval: Duration, path: impl Into<PathBuf>) -> Self {
+        self.options.snapshot_interval = Some(interval);
+        self.options.snapshot_path = Some(path.into());
+        self
+    }
+
+    pub fn build(self) -> ScanOptions {
+        self.options
+    }
+}
+
+#[cfg(not(windows))]
+fn is_hidden(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(windows)]
+fn is_hidden(path: &Path) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+    path.symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+impl ScanOptions {
+    fn skips(&self, path: &Path) -> bool {
+        !self.include_hidden && is_hidden(path)
+    }
+
+    fn set_owner(&self, node: &mut FolderHierarchy, metadata: &EntryMetadata) {
+        if self.read_owners {
+            (node.uid, node.gid) = metadata.owner.unzip();
+        }
+    }
+
+    fn keeps_children(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max_depth| depth < max_depth)
+    }
+
+    fn accepts_file(&self, path: &Path) -> bool {
+        let extension = path.extension().map(|e| e.to_string_lossy()).unwrap_or_default();
+        let matches = |wanted: &String| wanted.trim_start_matches('.').eq_ignore_ascii_case(&extension);
+
+        (self.include_extensions.is_empty() || self.include_extensions.iter().any(matches))
+            && !self.exclude_extensions.iter().any(matches)
+    }
+
+    fn is_small(&self, entry: &FolderHierarchy) -> bool {
+        entry.entry_type != EntryKind::Directory && self.min_size.is_some_and(|min_size| entry.value < min_size)
+    }
+
+    fn descends_into(&self, entry: &ListedEntry, metadata: &EntryMetadata) -> bool {
+        (entry.entry_type != EntryKind::Symlink || self.follow_symlinks) && metadata.kind == EntryKind::Directory
+    }
+
+    /// Reads the metadata of a directory entry, following it if it is a symlink to follow.
+    /// The scan goes by this single read for both the entry's type and its size, so that
+    /// they agree even when the entry changes while it is being scanned.
+    async fn entry_metadata(&self, entry: DirectoryEntry, file_system: &dyn FileSystem) -> io::Result<EntryMetadata> {
+        if entry.kind == EntryKind::Symlink && self.follow_symlinks {
+            file_system.metadata(&entry.path).await
+        } else {
+            entry.metadata
+        }
+    }
+
+}
+
+/// State shared by every directory walked during a single scan.
+struct Traversal {
+    visited: Mutex<HashSet<PathBuf>>,
+    /// `(device, inode)` of every hard-linked file counted so far.
+    linked_files: Mutex<HashSet<(u64, u64)>>,
+    open_directories: Semaphore,
+    /// `ScanOptions::include_globs`, compiled once for the whole scan.
+    include_globs: Option<GlobSet>,
+    /// `ScanOptions::exclude_patterns`, compiled once for the whole scan.
+    exclude_patterns: Option<GlobSet>,
+    /// Receives each directory once it is complete, for `DirectoryScanner::scan_streaming`.
+    directory_sink: Option<SyncSender<FolderHierarchy>>,
+    /// Ends the scan at the first file matching, for `DirectoryScanner::find_first`.
+    first_match: Option<FirstMatch>,
+    /// Receives each entry of the scanned root once it is complete, along with whether it is
+    /// listed in the root's children, so that a scan in the background fills in the map as it goes.
+    root_entry_sink: Option<Box<dyn Fn(FolderHierarchy, bool) + Send + Sync>>,
+    max_nodes: Option<usize>,
+    /// Nodes kept so far, the root included, counted against `max_nodes`.
+    kept_nodes: AtomicUsize,
+    /// Set once a node is left out for going over `max_nodes`.
+    nodes_left_out: AtomicBool,
+    /// Scanned root the paths of the nodes are given relative to, for `ScanOptions::relative_paths`.
+    relative_root: Option<PathBuf>,
+}
+
+struct FirstMatch {
+    predicate: Box<dyn Fn(&FolderHierarchy) -> bool + Send + Sync>,
+    found: Mutex<Option<FolderHierarchy>>,
+}
+
+impl Traversal {
+    fn new(options: &ScanOptions) -> Self {
+        Traversal {
+            visited: Mutex::new(HashSet::new()),
+            linked_files: Mutex::new(HashSet::new()),
+            open_directories: Semaphore::new(options.max_concurrency.max(1)),
+            include_globs: glob_set(&options.include_globs),
+            exclude_patterns: glob_set(&options.exclude_patterns),
+            directory_sink: None,
+            first_match: None,
+            root_entry_sink: None,
+            max_nodes: options.max_nodes,
+            kept_nodes: AtomicUsize::new(1),
+            nodes_left_out: AtomicBool::new(false),
+            relative_root: None,
+        }
+    }
+
+    /// `path` as given in the `path` of its node: relative to `relative_root` if set, the root
+    /// itself being `.`.
+    fn node_path(&self, path: &Path) -> String {
+        match self.relative_root.as_deref().and_then(|root| path.strip_prefix(root).ok()) {
+            Some(relative) if relative.as_os_str().is_empty() => ".".to_owned(),
+            Some(relative) => display_path(relative),
+            None => display_path(path),
+        }
+    }
+
+    /// Whether `entry`, just found, still fits within `max_nodes` along with the nodes below it,
+    /// such as the entries of an archive, taking their places if so. Entries are given their
+    /// places as they are found, so that the levels closest to the root are kept first.
+    fn keeps(&self, entry: &FolderHierarchy) -> bool {
+        let Some(max_nodes) = self.max_nodes else {
+            return true;
+        };
+        let nodes = entry.iter().count();
+        let kept = self
+            .kept_nodes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |kept| (kept + nodes <= max_nodes).then_some(kept + nodes))
+            .is_ok();
+        if !kept {
+            self.nodes_left_out.store(true, Ordering::Relaxed);
+        }
+        kept
+    }
+
+    /// How complete `root`, scanned with `options` and without being stopped, turned out.
+    fn completeness(&self, options: &ScanOptions, root: &FolderHierarchy) -> Completeness {
+        if self.nodes_left_out.load(Ordering::Relaxed) {
+            Completeness::NodeLimited
+        } else if options.max_depth.is_some_and(|max_depth| root.iter_with_depth().any(|(depth, node)| depth == max_depth && node.has_children)) {
+            Completeness::DepthLimited
+        } else {
+            Completeness::Complete
+        }
+    }
+
+    /// Gives back the places of a directory that is left out after all, and of the nodes kept below it.
+    fn release(&self, directory: &FolderHierarchy) {
+        if self.max_nodes.is_some() {
+            self.kept_nodes.fetch_sub(directory.iter().count(), Ordering::Relaxed);
+        }
+    }
+
+    fn root_entry_scanned(&self, entry: &FolderHierarchy, listed: bool) {
+        if let Some(root_entry_sink) = &self.root_entry_sink {
+            root_entry_sink(entry.clone(), listed);
+        }
+    }
+
+    fn directory_scanned(&self, directory: &FolderHierarchy) {
+        if let Some(directory_sink) = &self.directory_sink {
+            // Nobody to tell once the receiver is gone, and the scan itself carries on.
+            let _ = directory_sink.send(directory.truncated(1));
+        }
+    }
+
+    /// Whether the file at `path`, found `depth` levels below the scanned root, matches the include globs.
+    fn matches_globs(&self, path: &Path, depth: usize) -> bool {
+        let Some(include_globs) = &self.include_globs else {
+            return true;
+        };
+        let mut names: Vec<_> = path.components().rev().take(depth).collect();
+        names.reverse();
+        include_globs.is_match(names.iter().collect::<PathBuf>())
+    }
+
+    /// Whether the directory at `path` is neither walked nor counted, for its name matching the
+    /// exclude patterns or for being on another file system.
+    async fn excludes_directory(&self, options: &ScanOptions, path: &Path, file_system: &dyn FileSystem) -> bool {
+        self.exclude_patterns.as_ref().is_some_and(|patterns| patterns.is_match(path.file_name().unwrap_or_default()))
+            || (options.one_filesystem && crosses_filesystem(path, file_system).await)
+    }
+
+    /// Whether `entry`, found `depth` levels below the scanned root, is kept in its parent's children.
+    fn lists(&self, options: &ScanOptions, entry: &FolderHierarchy, depth: usize) -> bool {
+        entry.entry_type == EntryKind::Directory || (!options.directories_only && !options.is_small(entry) && self.matches_globs(Path::new(&entry.path), depth))
+    }
+
+    /// Marks the directory as walked, returning `false` if it already was.
+    /// Only symlinks can lead back to a visited directory, so this is a no-op unless they are followed.
+    async fn first_visit(&self, options: &ScanOptions, path: &Path, file_system: &dyn FileSystem) -> bool {
+        if !options.follow_symlinks {
+            return true;
+        }
+        match file_system.canonicalize(path).await {
+            Ok(canonical) => self.visited.lock().expect("Lock poisoned").insert(canonical),
+            Err(_) => true,
+        }
+    }
+
+    /// Returns `false` if another hard link to this file has already been counted.
+    fn first_link(&self, metadata: &EntryMetadata) -> bool {
+        metadata.hard_link.is_none_or(|file| self.linked_files.lock().expect("Lock poisoned").insert(file))
+    }
+}
+
+/// Compiles `patterns`, leaving out invalid ones. `None` when there are none.
+fn glob_set(patterns: &[String]) -> Option<GlobSet> {
+    if patterns.is_empty() {
+        return None;
+    }
+    let mut builder = GlobSetBuilder::new();
+    for glob in patterns.iter().filter_map(|pattern| Glob::new(pattern).ok()) {
+        builder.add(glob);
+    }
+    builder.build().ok()
+}
+
+/// Most directories `DirectoryScanner::scan_streaming` holds for its receiver before the scan waits.
+pub const STREAMED_DIRECTORIES: usize = 256;
+
+/// Lifecycle of the scanner's most recent scan, as reported by `get_scan_state`.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanState {
+    Idle = 0,
+    Scanning = 1,
+    Completed = 2,
+    Stopped = 3,
+    /// The scanned directory itself could not be read.
+    Error = 4,
+    /// The scan ran past `ScanOptions::timeout`.
+    TimedOut = 5,
+}
+
+impl ScanState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ScanState::Scanning,
+            2 => ScanState::Completed,
+            3 => ScanState::Stopped,
+            4 => ScanState::Error,
+            5 => ScanState::TimedOut,
+            _ => ScanState::Idle,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanEventKind {
+    /// The directory has been opened and its entries are being read.
+    Started,
+    /// The directory and everything below it has been scanned.
+    Completed,
+    /// The directory was scanned again after a change to it or below it, in a watched root.
+    Changed,
+}
+
+/// Scan activity delivered to the receivers returned by `DirectoryScanner::subscribe`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ScanEvent {
+    pub kind: ScanEventKind,
+    /// Directory the event is about.
+    pub path: String,
+}
+
+/// Called with the path of every directory once it has been fully scanned.
+/// The string is only valid for the duration of the call.
+pub type ScanCallback = extern "C" fn(*const c_char);
+
+/// Path handed to the file system for `path`.
+///
+/// Absolute paths get the `\\?\` extended-length prefix, or `\\?\UNC\` for network shares,
+/// so that paths longer than `MAX_PATH` can still be read. Entries joined onto the result keep it.
+#[cfg(windows)]
+fn extended_length(path: &Path) -> PathBuf {
+    use std::path::{Component, Prefix};
+
+    let path_str = path.to_string_lossy().replace('/', "\\");
+    match path.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) if path.has_root() => PathBuf::from(format!(r"\\?\{path_str}")),
+            Prefix::UNC(..) => PathBuf::from(format!(r"\\?\UNC\{}", &path_str[2..])),
+            _ => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(not(windows))]
+fn extended_length(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// Path shown to consumers, without the prefix added by `extended_length`.
+#[cfg(windows)]
+fn display_path(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if let Some(share) = path.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{share}")
+    } else {
+        path.strip_prefix(r"\\?\").unwrap_or(&path).to_string()
+    }
+}
+
+#[cfg(not(windows))]
+fn display_path(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// Key under which a scanned root is stored, so that `C:\dir`, `C:/dir` and `C:/dir/` all match.
+/// On Windows and macOS, names are compared in NFC, so that the composed and decomposed forms
+/// of accented names match, and case-insensitively, so there `C:/Dir` matches too. Elsewhere the
+/// two forms are different names, which can sit side by side in a directory, so they are
+/// compared as they are. The `path` of every node keeps the form the file system listed it in.
+fn root_key(path: &str) -> String {
+    let normalized = canonical_name(&path.replace('\\', "/"));
+    match normalized.trim_end_matches('/') {
+        "" => normalized,
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Form a name or path is compared in when looking it up, see `root_key`.
+#[cfg(any(windows, target_os = "macos"))]
+fn canonical_name(path: &str) -> String {
+    path.nfc().collect::<String>().to_lowercase()
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn canonical_name(path: &str) -> String {
+    path.to_owned()
+}
+
+/// JSON object returned to FFI callers in place of a result, e.g. `{"error":"Root folder not found"}`.
+fn error_json(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+/// Serializes a result for FFI callers, reporting a failure as an error object.
+fn to_json(value: &impl Serialize) -> String {
+    serde_json::to_string(value).unwrap_or_else(|e| error_json(&format!("Serialization error: {e}")))
+}
+
+/// A hierarchy serialized with `schema_version` alongside the fields of its root.
+#[derive(Serialize)]
+struct Versioned<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    root: &'a FolderHierarchy,
+}
+
+impl<'a> Versioned<'a> {
+    fn new(root: &'a FolderHierarchy) -> Self {
+        Versioned { schema_version: SCHEMA_VERSION, root }
+    }
+}
+
+/// Finds the entry at `path` in the scanned roots, descending from the deepest root containing it.
+/// Part of `key` below `root_key`, or `None` if `key` is not the key of `root_key` or of an entry below it.
+fn relative_key<'a>(key: &'a str, root_key: &str) -> Option<&'a str> {
+    let relative = key.strip_prefix(root_key)?;
+    if !relative.is_empty() && !relative.starts_with('/') && !root_key.ends_with('/') {
+        return None;
+    }
+    Some(relative)
+}
+
+fn find_entry<'a>(roots: &'a BTreeMap<String, FolderHierarchy>, path: &str) -> Option<&'a FolderHierarchy> {
+    let key = root_key(path);
+    let mut containing: Vec<_> = roots
+        .iter()
+        .filter_map(|(root_key, root)| Some((root_key.len(), root, relative_key(&key, root_key)?)))
+        .collect();
+    containing.sort_by_key(|&(length, ..)| Reverse(length));
+
+    containing.into_iter().find_map(|(_, root, relative)| {
+        relative
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .try_fold(root, |node, name| node.children.iter().find(|child| canonical_name(&child.name) == name))
+    })
+}
+
+pub struct DirectoryScanner {
+    // Read far more often than written, so readers share the lock and only wait for the brief
+    // moments a scan publishes an entry.
+    directory_map: Arc<RwLock<BTreeMap<String, FolderHierarchy>>>,
+    last_root: Mutex<Option<String>>,
+    // Cancelled to stop the scans. Replaced when the scanner is reset or linked to a caller's token.
+    cancellation: Mutex<CancellationToken>,
+    paused: tokio::sync::watch::Sender<bool>,
+    files_scanned: AtomicU64,
+    directories_scanned: AtomicU64,
+    entries_to_scan: AtomicU64,
+    bytes_scanned: AtomicU64,
+    // When the current scan started and, once it is over, when it ended.
+    scan_started: Mutex<Option<Instant>>,
+    scan_ended: Mutex<Option<Instant>>,
+    last_error: Mutex<Option<ScanError>>,
+    scan_errors: Mutex<Vec<ScanError>>,
+    state: AtomicU8,
+    // Held while the state changes or a scan task ends, so that waiters cannot miss either.
+    // Shared with the scan tasks, which signal their end after letting go of the scanner.
+    state_lock: Arc<Mutex<()>>,
+    state_changed: Arc<Condvar>,
+    // Scan tasks spawned and not yet done, waited on by `shutdown`.
+    running_scans: Arc<AtomicUsize>,
+    scan_callback: Mutex<Option<ScanCallback>>,
+    subscribers: Mutex<Vec<Sender<ScanEvent>>>,
+    // Duplicates of the descriptors passed to `scan_fd`, kept open while their scans may be read.
+    #[cfg(unix)]
+    directory_fds: Mutex<Vec<std::os::fd::OwnedFd>>,
+    // Roots kept up to date by `watch`, by root key.
+    watchers: Mutex<BTreeMap<String, Watch>>,
+    // Tasks of the scans started so far, joined by `shutdown`. Finished ones are dropped as new ones start.
+    scan_tasks: Mutex<Vec<tokio::task::JoinHandle<()>>>,
+    // Roots being scanned with `ScanOptions::atomic_swap`, moved into the map once complete.
+    staged_roots: Mutex<BTreeMap<String, FolderHierarchy>>,
+    // Options each root was last scanned with, by root key, reused to bring it up to date.
+    root_options: Mutex<BTreeMap<String, ScanOptions>>,
+    // Path each root was last scanned from, as the caller gave it, by root key.
+    root_paths: Mutex<BTreeMap<String, String>>,
+    // Where directories and metadata are read from.
+    file_system: Arc<dyn FileSystem>,
+    // Only taken out when the scanner is dropped or given another thread count, and missing from
+    // the scanners behind isolated scans.
+    runtime: Mutex<Option<ScannerRuntime>>,
+}
+
+/// Updates of a root kept up to date by `DirectoryScanner::watch`, ended when dropped, changes
+/// not applied yet included.
+struct Watch {
+    _watcher: notify::RecommendedWatcher,
+    stop: CancellationToken,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+/// Where the tasks of a scanner run.
+enum ScannerRuntime {
+    /// Created for the scanner, and shut down along with it.
+    Owned(Runtime),
+    /// Provided by the host, which keeps it running.
+    Shared(Handle),
+}
+
+impl DirectoryScanner {
+    /// Runs on the runtime the caller is in if it can run scans in the background, and on a
+    /// runtime of its own otherwise.
+    fn new() -> Self {
+        match Handle::try_current() {
+            // A current-thread runtime only makes progress while its thread waits on it.
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => Self::with_runtime(Some(ScannerRuntime::Shared(handle))),
+            _ => Self::with_runtime(Some(ScannerRuntime::Owned(owned_runtime(default_scan_threads()).expect("Failed to create the scanner runtime")))),
+        }
+    }
+
+    fn with_runtime(runtime: Option<ScannerRuntime>) -> Self {
+        Self {
+            directory_map: Arc::new(RwLock::new(BTreeMap::new())),
+            last_root: Mutex::new(None),
+            cancellation: Mutex::new(CancellationToken::new()),
+            paused: tokio::sync::watch::Sender::new(false),
+            files_scanned: AtomicU64::new(0),
+            directories_scanned: AtomicU64::new(0),
+            entries_to_scan: AtomicU64::new(0),
+            bytes_scanned: AtomicU64::new(0),
+            scan_started: Mutex::new(None),
+            scan_ended: Mutex::new(None),
+            last_error: Mutex::new(None),
+            scan_errors: Mutex::new(Vec::new()),
+            state: AtomicU8::new(ScanState::Idle as u8),
+            state_lock: Arc::new(Mutex::new(())),
+            state_changed: Arc::new(Condvar::new()),
+            running_scans: Arc::new(AtomicUsize::new(0)),
+            scan_callback: Mutex::new(None),
+            subscribers: Mutex::new(Vec::new()),
+            #[cfg(unix)]
+            directory_fds: Mutex::new(Vec::new()),
+            watchers: Mutex::new(BTreeMap::new()),
+            scan_tasks: Mutex::new(Vec::new()),
+            staged_roots: Mutex::new(BTreeMap::new()),
+            root_options: Mutex::new(BTreeMap::new()),
+            root_paths: Mutex::new(BTreeMap::new()),
+            file_system: Arc::new(RealFileSystem),
+            runtime: Mutex::new(runtime),
+        }
+    }
+
+    /// A scanner without a runtime reading from the same file system, for scans kept apart
+    /// from those tracked by this scanner.
+    fn isolated(&self) -> Arc<Self> {
+        let mut isolated = Self::with_runtime(None);
+        isolated.file_system = Arc::clone(&self.file_system);
+        Arc::new(isolated)
+    }
+
+    /// Spawns the task of a scan, keeping track of it for `shutdown`.
+    fn spawn_scan(&self, scan: impl std::future::Future<Output = ()> + Send + 'static) {
+        // Locked first, so that `set_scan_threads` cannot replace the runtime the task is spawned on.
+        let mut tasks = self.scan_tasks.lock().expect("Lock poisoned");
+        tasks.retain(|task| !task.is_finished());
+        self.running_scans.fetch_add(1, Ordering::AcqRel);
+        let running = RunningScan {
+            running_scans: Arc::clone(&self.running_scans),
+            state_lock: Arc::clone(&self.state_lock),
+            state_changed: Arc::clone(&self.state_changed),
+        };
+        tasks.push(self.runtime().spawn(async move {
+            // Declared first, so that it is dropped last, once the scan has let go of the scanner.
+            let _running = running;
+            scan.await;
+        }));
+    }
+
+    /// Blocks until no scan task is running, or `timeout` has passed. Returns `false` on timeout.
+    fn wait_for_scan_tasks(&self, timeout: Duration) -> bool {
+        let state_lock = self.state_lock.lock().expect("Lock poisoned");
+        let (_state_lock, result) = self
+            .state_changed
+            .wait_timeout_while(state_lock, timeout, |_| self.running_scans.load(Ordering::Acquire) > 0)
+            .expect("Lock poisoned");
+        !result.timed_out()
+    }
+
+    /// Runtime every scan of this scanner is spawned onto.
+    fn runtime(&self) -> Handle {
+        match self.runtime.lock().expect("Lock poisoned").as_ref().expect("Scanner runtime already shut down") {
+            ScannerRuntime::Owned(runtime) => runtime.handle().clone(),
+            ScannerRuntime::Shared(handle) => handle.clone(),
+        }
+    }
+
+    /// Creates a new scanner, shared so that background scans can hold on to it.
+    ///
+    /// Scans run on the multi-threaded Tokio runtime this is called from, if any, and otherwise
+    /// on a runtime created for the scanner and shut down when it is dropped.
+    pub fn open() -> Arc<Self> {
+        Arc::new(Self::new())
+    }
+
+    /// Creates a new scanner whose scans run on a runtime of its own with `threads` worker threads,
+    /// even when called from within a runtime.
+    pub fn open_with_threads(threads: usize) -> io::Result<Arc<Self>> {
+        Ok(Arc::new(Self::with_runtime(Some(ScannerRuntime::Owned(owned_runtime(threads)?)))))
+    }
+
+    /// Creates a new scanner whose scans run on the runtime behind `handle`, which the host
+    /// keeps running. It must be a multi-threaded runtime for scans to go on in the background
+    /// while the caller waits for them.
+    pub fn open_with_handle(handle: Handle) -> Arc<Self> {
+        Arc::new(Self::with_runtime(Some(ScannerRuntime::Shared(handle))))
+    }
+
+    /// Creates a new scanner, like `open`, that reads directories and metadata from
+    /// `file_system` instead of the disk.
+    pub fn open_with_file_system(file_system: Arc<dyn FileSystem>) -> Arc<Self> {
+        let mut scanner = Self::new();
+        scanner.file_system = file_system;
+        Arc::new(scanner)
+    }
+
+    /// Starts scanning `path` in the background and returns immediately.
+    ///
+    /// Each scanned root is kept separately, so several roots can be scanned into the same
+    /// scanner. Scanning a root again replaces its previous result. The partial result can be
+    /// read at any time with [`DirectoryScanner::directory_map_for`].
+    pub fn scan(self: &Arc<Self>, path: impl AsRef<Path>) {
+        self.scan_with_options(path, ScanOptions::default());
+    }
+
+    /// Starts scanning the directory open as `fd`, going through the descriptor instead of
+    /// resolving a path again, so that the scan stays on that directory even if its path is
+    /// renamed or replaced in the meantime. This needs `/dev/fd` to lead into directories, as on Linux.
+    ///
+    /// `fd` is duplicated, so the caller keeps ownership of it and may close it right away.
+    /// The duplicate stays open until the scanner is reset or dropped. The scan is stored under
+    /// the returned path, `/dev/fd/<n>` for the duplicate, and its entries are listed under it.
+    #[cfg(unix)]
+    pub fn scan_fd(self: &Arc<Self>, fd: std::os::fd::BorrowedFd<'_>) -> io::Result<PathBuf> {
+        use std::os::fd::AsRawFd;
+
+        let directory = fd.try_clone_to_owned()?;
+        let path = PathBuf::from(format!("/dev/fd/{}", directory.as_raw_fd()));
+        self.directory_fds.lock().expect("Lock poisoned").push(directory);
+        self.scan(&path);
+        Ok(path)
+    }
+
+    /// Same as [`DirectoryScanner::scan`], with the behaviour tuned by `options`.
+    pub fn scan_with_options(self: &Arc<Self>, path: impl AsRef<Path>, options: ScanOptions) {
+        self.start_scan(path.as_ref(), options, None);
+    }
+
+    /// Same as [`DirectoryScanner::scan`], also sending each directory on the returned channel
+    /// as soon as it has been fully scanned, so that a view can be built up while the scan goes on.
+    ///
+    /// Directories are sent with their direct children, themselves without children, and always
+    /// after every directory below them, the root coming last. The channel closes when the scan
+    /// ends. It holds at most `STREAMED_DIRECTORIES` directories: once it is full, the scan waits
+    /// for the receiver to catch up rather than buffering the whole tree.
+    pub fn scan_streaming(self: &Arc<Self>, path: impl AsRef<Path>) -> Receiver<FolderHierarchy> {
+        let (sender, receiver) = mpsc::sync_channel(STREAMED_DIRECTORIES);
+        self.start_scan(path.as_ref(), ScanOptions::default(), Some(sender));
+        receiver
+    }
+
+    fn start_scan(self: &Arc<Self>, path: &Path, options: ScanOptions, directory_sink: Option<SyncSender<FolderHierarchy>>) {
+        self.begin_scan();
+        if let Some(e) = options.include_globs.iter().chain(&options.exclude_patterns).find_map(|pattern| Glob::new(pattern).err()) {
+            self.record_error(path, &io::Error::new(io::ErrorKind::InvalidInput, e));
+            self.set_state(ScanState::Error);
+            return;
+        }
+
+        let root_path = path.to_path_buf();
+        let root_key = root_key(&root_path.to_string_lossy());
+        let mut root_hierarchy = FolderHierarchy::directory(&root_path);
+        if options.relative_paths {
+            root_hierarchy.path = ".".to_owned();
+        }
+        if options.atomic_swap {
+            self.staged_roots.lock().expect("Lock poisoned").insert(root_key.clone(), root_hierarchy);
+        } else {
+            self.directory_map.write().expect("Lock poisoned").insert(root_key.clone(), root_hierarchy);
+        }
+        *self.last_root.lock().expect("Lock poisoned") = Some(root_key.clone());
+        self.root_options.lock().expect("Lock poisoned").insert(root_key.clone(), options.clone());
+        self.root_paths.lock().expect("Lock poisoned").insert(root_key.clone(), display_path(&root_path));
+
+        let directory_path = extended_length(&root_path);
+        let scanner_clone = Arc::clone(self);
+
+        let mut snapshots = self.start_snapshots(&root_path, &options);
+
+        self.spawn_scan(async move {
+            let scan = async {
+                if options.estimate_progress {
+                    let total = count_entries(directory_path.clone(), &scanner_clone, &options, &Traversal::new(&options)).await;
+                    scanner_clone.entries_to_scan.store(total, Ordering::Relaxed);
+                }
+
+                // Only holds the lock long enough to publish each finished entry, so readers
+                // get a partial snapshot while the scan goes on.
+                let publisher = Arc::clone(&scanner_clone);
+                let published_key = root_key.clone();
+                let traversal = Traversal {
+                    directory_sink,
+                    root_entry_sink: Some(Box::new(move |entry, listed| publisher.update_root(&published_key, |root| root.add_child(entry, listed)))),
+                    relative_root: options.relative_paths.then(|| directory_path.clone()),
+                    ..Traversal::new(&options)
+                };
+                traversal.first_visit(&options, &directory_path, &*scanner_clone.file_system).await;
+                match scan_tree(directory_path.clone(), Arc::clone(&scanner_clone), &options, 0, &traversal).await {
+                    Ok((mut hierarchy, _)) => {
+                        if !options.relative_paths {
+                            hierarchy.path = display_path(&root_path);
+                        }
+                        scanner_clone.update_root(&root_key, |root| *root = hierarchy);
+                        scanner_clone.stamp_root(&root_key, |root| traversal.completeness(&options, root));
+                        scanner_clone.finish_staged_root(&root_key, true);
+
+                        let root = scanner_clone.directory_map.read().expect("Lock poisoned").get(&root_key).map(|root| root.truncated(1));
+                        if let Some(root) = root {
+                            traversal.directory_scanned(&root);
+                        }
+                        finish_snapshots(&mut snapshots).await;
+                        scanner_clone.finish_scan();
+                    }
+                    // Stopped before the root's own entries were read, which leaves what was published of them.
+                    Err(_) if scanner_clone.is_stop_requested() => {
+                        scanner_clone.stamp_root(&root_key, |_| Completeness::Stopped);
+                        scanner_clone.finish_staged_root(&root_key, false);
+                        finish_snapshots(&mut snapshots).await;
+                        scanner_clone.finish_scan();
+                    }
+                    Err(e) => {
+                        scanner_clone.record_error(&directory_path, &e);
+                        scanner_clone.finish_staged_root(&root_key, false);
+                        // A root that could not be read at all is not left in the map as an empty directory.
+                        if !options.atomic_swap {
+                            scanner_clone.directory_map.write().expect("Lock poisoned").remove(&root_key);
+                            scanner_clone.root_options.lock().expect("Lock poisoned").remove(&root_key);
+                            scanner_clone.root_paths.lock().expect("Lock poisoned").remove(&root_key);
+                        }
+                        scanner_clone.set_state(ScanState::Error);
+                    }
+                }
+            };
+
+            match options.timeout {
+                Some(limit) => {
+                    // Entries published before the deadline stay in the map.
+                    if tokio::time::timeout(limit, scan).await.is_err() {
+                        scanner_clone.record_error(&directory_path, &io::Error::new(io::ErrorKind::TimedOut, "Scan timed out"));
+                        scanner_clone.update_root(&root_key, FolderHierarchy::update_percentages);
+                        scanner_clone.stamp_root(&root_key, |_| Completeness::TimedOut);
+                        scanner_clone.finish_staged_root(&root_key, false);
+                        finish_snapshots(&mut snapshots).await;
+                        scanner_clone.set_state(ScanState::TimedOut);
+                    }
+                }
+                None => scan.await,
+            }
+            finish_snapshots(&mut snapshots).await;
+        });
+    }
+
+    /// Starts writing snapshots of `root` as `options` asks, until `finish_snapshots` is called.
+    /// Spawned apart from the scan, so that snapshots go on being written while it is paused.
+    fn start_snapshots(self: &Arc<Self>, root: &Path, options: &ScanOptions) -> Option<(CancellationToken, tokio::task::JoinHandle<()>)> {
+        options.snapshot_path.clone().zip(options.snapshot_interval).map(|(snapshot_path, interval)| {
+            let done = CancellationToken::new();
+            let task = self.runtime().spawn(write_snapshots(Arc::clone(self), root.to_path_buf(), snapshot_path, interval, done.clone()));
+            (done, task)
+        })
+    }
+
+    /// Writes the map of `root` to `snapshot_path`, replacing the previous snapshot only once
+    /// the new one is complete. Failures are logged, as they should not end the scan.
+    async fn write_snapshot(&self, root: &Path, snapshot_path: &Path) {
+        let mut json = Vec::new();
+        let written = match self.write_directory_map(root, &mut json) {
+            Ok(()) => {
+                let partial_path = snapshot_path.with_extension("partial");
+                match fs::write(&partial_path, &json).await {
+                    Ok(()) => fs::rename(&partial_path, snapshot_path).await,
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!("Failed to write snapshot to {}: {}", snapshot_path.display(), e);
+        }
+    }
+
+    /// Scans `path` to completion on this scanner's runtime and returns its hierarchy, without
+    /// storing it in the map.
+    ///
+    /// The scan keeps its own progress, errors and stop flag, so it neither shows up in nor
+    /// contends with the scans tracked by this scanner, and several can run at once from
+    /// different threads. Blocks the calling thread, so it must not be called from async code.
+    /// Only failing to read `path` itself is an error.
+    pub fn scan_isolated(&self, path: impl AsRef<Path>) -> io::Result<FolderHierarchy> {
+        let isolated = self.isolated();
+        let directory_path = extended_length(path.as_ref());
+        let options = ScanOptions::default();
+        let traversal = Traversal::new(&options);
+
+        self.runtime().block_on(async {
+            traversal.first_visit(&options, &directory_path, &*isolated.file_system).await;
+            scan_folder(directory_path, Arc::clone(&isolated), &options, 0, &traversal).await
+        })
+    }
+
+    /// Scans `path` with `options` to completion on the runtime awaiting it, and returns its
+    /// hierarchy, without storing it in the map.
+    ///
+    /// Like `scan_isolated`, the scan is kept apart from those tracked by this scanner, but it
+    /// can be awaited from async code, and combined with `select!` or `join!`. Dropping the
+    /// future ends the scan. Only failing to read `path` itself is an error.
+    pub async fn scan_async(&self, path: impl AsRef<Path>, options: ScanOptions) -> io::Result<FolderHierarchy> {
+        let isolated = self.isolated();
+        let directory_path = extended_length(path.as_ref());
+        let traversal = Traversal { relative_root: options.relative_paths.then(|| directory_path.clone()), ..Traversal::new(&options) };
+        traversal.first_visit(&options, &directory_path, &*isolated.file_system).await;
+
+        scan_folder(directory_path, isolated, &options, 0, &traversal).await
+    }
+
+    /// Walks `path` on this scanner's runtime until it finds a file for which `predicate` holds,
+    /// and returns that file without walking any further, or `None` if no file matches.
+    ///
+    /// Like `scan_isolated`, the walk is kept apart from the scans tracked by this scanner and
+    /// blocks the calling thread. Only failing to read `path` itself is an error.
+    pub fn find_first(&self, path: impl AsRef<Path>, predicate: impl Fn(&FolderHierarchy) -> bool + Send + Sync + 'static) -> io::Result<Option<FolderHierarchy>> {
+        let isolated = self.isolated();
+        self.runtime().block_on(find_first_file(extended_length(path.as_ref()), isolated, Box::new(predicate)))
+    }
+
+    /// Brings a previously scanned root up to date with the options it was scanned with, only
+    /// re-reading directories modified since the last scan. Falls back to a full scan if `path`
+    /// has not been scanned yet.
+    ///
+    /// The cached tree is only replaced once the rescan is done, so it stays whole until then,
+    /// as with `ScanOptions::atomic_swap`, and is kept if the rescan fails, is stopped or times out.
+    pub fn rescan(self: &Arc<Self>, path: impl AsRef<Path>) {
+        let directory_path = path.as_ref().to_path_buf();
+        let root_key = root_key(&display_path(&directory_path));
+        let options = self.root_options.lock().expect("Lock poisoned").get(&root_key).cloned().unwrap_or_default();
+        let cached = match self.directory_map_for(&directory_path) {
+            // Cached paths relative to the root cannot be read again, and `max_nodes` applies to
+            // the whole tree, so those roots are scanned anew.
+            Some(cached) if cached.path == "." || options.max_nodes.is_some() => return self.scan_with_options(directory_path, options),
+            Some(cached) => cached,
+            None => return self.scan(directory_path),
+        };
+
+        self.begin_scan();
+
+        let scanner_clone = Arc::clone(self);
+        let mut snapshots = self.start_snapshots(&directory_path, &options);
+
+        self.spawn_scan(async move {
+            let rescan = async {
+                match rescan_folder(extended_length(&directory_path), cached, Arc::clone(&scanner_clone), &options, 0).await {
+                    Ok(mut hierarchy) => {
+                        hierarchy.path = display_path(&directory_path);
+                        scanner_clone.update_root(&root_key, |root| *root = hierarchy);
+                        scanner_clone.stamp_root(&root_key, |root| Traversal::new(&options).completeness(&options, root));
+                        finish_snapshots(&mut snapshots).await;
+                        scanner_clone.finish_scan();
+                    }
+                    // Stopped before the root was scanned anew, which leaves the cached tree in place.
+                    Err(_) if scanner_clone.is_stop_requested() => {
+                        finish_snapshots(&mut snapshots).await;
+                        scanner_clone.finish_scan();
+                    }
+                    Err(e) => {
+                        scanner_clone.record_error(&directory_path, &e);
+                        scanner_clone.set_state(ScanState::Error);
+                    }
+                }
+            };
+
+            match options.timeout {
+                Some(limit) => {
+                    if tokio::time::timeout(limit, rescan).await.is_err() {
+                        scanner_clone.record_error(&directory_path, &io::Error::new(io::ErrorKind::TimedOut, "Scan timed out"));
+                        scanner_clone.set_state(ScanState::TimedOut);
+                    }
+                }
+                None => rescan.await,
+            }
+            finish_snapshots(&mut snapshots).await;
+        });
+    }
+
+    /// Keeps a scanned root up to date as the file system changes, until `unwatch` is called.
+    ///
+    /// Changes are collected until none has come for `WATCH_DEBOUNCE`, so that a burst of them
+    /// is applied at once. Each directory changed, or holding a changed file, is then scanned
+    /// again on its own, with the options the root was scanned with, and replaces its entry in the map, the totals above it being adjusted to
+    /// match, and a `Changed` event is sent to subscribers. Fails if `path` has not been scanned
+    /// or cannot be watched.
+    pub fn watch(self: &Arc<Self>, path: impl AsRef<Path>) -> io::Result<()> {
+        use notify::Watcher;
+
+        let root_path = path.as_ref().to_path_buf();
+        let root_key = root_key(&root_path.to_string_lossy());
+        if !self.directory_map.read().expect("Lock poisoned").contains_key(&root_key) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Root folder not found"));
+        }
+
+        let (sender, changes) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                // Nobody to tell once the updates have ended.
+                let _ = sender.send(event.paths);
+            }
+            Err(e) => warn!("Failed to watch for changes: {}", e),
+        })
+        .map_err(io::Error::other)?;
+        watcher.watch(&extended_length(&root_path), notify::RecursiveMode::Recursive).map_err(io::Error::other)?;
+
+        // Weak, so that updates waiting for changes do not keep the scanner alive.
+        let stop = CancellationToken::new();
+        self.runtime().spawn(apply_changes(Arc::downgrade(self), root_path, changes, stop.clone()));
+        self.watchers.lock().expect("Lock poisoned").insert(root_key, Watch { _watcher: watcher, stop });
+        Ok(())
+    }
+
+    /// Stops keeping `path` up to date. Returns `false` if it was not being watched.
+    pub fn unwatch(&self, path: impl AsRef<Path>) -> bool {
+        let root_key = root_key(&path.as_ref().to_string_lossy());
+        self.watchers.lock().expect("Lock poisoned").remove(&root_key).is_some()
+    }
+
+    /// Puts `hierarchy`, scanned again, in place of the entry for the same directory under the
+    /// root at `root_key`. Returns `false` if the entry is not in the map.
+    fn replace_entry(&self, root: &str, directory: &Path, hierarchy: FolderHierarchy) -> bool {
+        let key = root_key(&display_path(directory));
+        let Some(relative) = relative_key(&key, root) else {
+            return false;
+        };
+        let mut directory_map = self.directory_map.write().expect("Lock poisoned");
+        directory_map.get_mut(root).is_some_and(|root| root.replace_below(relative, hierarchy))
+    }
+
+    fn begin_scan(&self) {
+        self.clear_progress();
+        self.set_state(ScanState::Scanning);
+    }
+
+    fn clear_progress(&self) {
+        self.files_scanned.store(0, Ordering::Relaxed);
+        self.directories_scanned.store(0, Ordering::Relaxed);
+        self.entries_to_scan.store(0, Ordering::Relaxed);
+        self.bytes_scanned.store(0, Ordering::Relaxed);
+        *self.last_error.lock().expect("Lock poisoned") = None;
+        self.scan_errors.lock().expect("Lock poisoned").clear();
+    }
+
+    /// Runs the scans started from now on on a runtime of the scanner's own with `threads` worker
+    /// threads, fewer of them suiting spinning disks, which slow down when read in many places at once.
+    ///
+    /// Returns `false`, leaving the runtime as it is, if `threads` is 0 or the runtime could not be
+    /// created, and while a scan is running or a root is watched, as their tasks run on the
+    /// runtime being replaced. A runtime provided with [`DirectoryScanner::open_with_handle`] is
+    /// replaced as well, but left running for the host.
+    pub fn set_scan_threads(&self, threads: usize) -> bool {
+        if threads == 0 || !self.watchers.lock().expect("Lock poisoned").is_empty() {
+            return false;
+        }
+        let tasks = self.scan_tasks.lock().expect("Lock poisoned");
+        if tasks.iter().any(|task| !task.is_finished()) {
+            return false;
+        }
+        let Ok(runtime) = owned_runtime(threads) else {
+            return false;
+        };
+        let previous = self.runtime.lock().expect("Lock poisoned").replace(ScannerRuntime::Owned(runtime));
+        drop(tasks);
+        if let Some(ScannerRuntime::Owned(previous)) = previous {
+            previous.shutdown_background();
+        }
+        true
+    }
+
+    /// Returns the scanner to the state it was created in, so it can be reused for unrelated scans.
+    ///
+    /// Every scanned root is dropped, the progress counters and errors are cleared, and a stop
+    /// or pause requested earlier is lifted, which also unlinks a token passed to
+    /// [`DirectoryScanner::set_cancellation_token`]. The scan callback and subscribers are kept.
+    /// Meant to be called once no scan is running; any scan still running is stopped.
+    pub fn reset(&self) {
+        self.directory_map.write().expect("Lock poisoned").clear();
+        self.staged_roots.lock().expect("Lock poisoned").clear();
+        self.root_options.lock().expect("Lock poisoned").clear();
+        self.root_paths.lock().expect("Lock poisoned").clear();
+        *self.last_root.lock().expect("Lock poisoned") = None;
+        std::mem::take(&mut *self.cancellation.lock().expect("Lock poisoned")).cancel();
+        self.resume();
+        self.clear_progress();
+        self.set_state(ScanState::Idle);
+        #[cfg(unix)]
+        self.directory_fds.lock().expect("Lock poisoned").clear();
+        self.watchers.lock().expect("Lock poisoned").clear();
+    }
+
+    fn finish_scan(&self) {
+        self.set_state(if self.is_stop_requested() { ScanState::Stopped } else { ScanState::Completed });
+    }
+
+    fn set_state(&self, state: ScanState) {
+        let _state_lock = self.state_lock.lock().expect("Lock poisoned");
+        let now = Some(Instant::now());
+        let (started, ended) = match state {
+            ScanState::Idle => (None, None),
+            ScanState::Scanning => (now, None),
+            _ => (*self.scan_started.lock().expect("Lock poisoned"), now),
+        };
+        *self.scan_started.lock().expect("Lock poisoned") = started;
+        *self.scan_ended.lock().expect("Lock poisoned") = ended;
+        self.state.store(state as u8, Ordering::Release);
+        self.state_changed.notify_all();
+    }
+
+    /// Blocks until the current scan is no longer running, or `timeout` has passed.
+    /// Returns `false` if the scan was still running at the timeout, and `true` straight
+    /// away if no scan is running.
+    pub fn wait_for_completion(&self, timeout: Duration) -> bool {
+        let state_lock = self.state_lock.lock().expect("Lock poisoned");
+        let (_state_lock, result) = self
+            .state_changed
+            .wait_timeout_while(state_lock, timeout, |_| self.scan_state() == ScanState::Scanning)
+            .expect("Lock poisoned");
+        !result.timed_out()
+    }
+
+    /// Returns where the most recent scan is in its lifecycle.
+    pub fn scan_state(&self) -> ScanState {
+        ScanState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn update_root(&self, root_key: &str, update: impl FnOnce(&mut FolderHierarchy)) {
+        if let Some(root) = self.staged_roots.lock().expect("Lock poisoned").get_mut(root_key) {
+            update(root);
+            return;
+        }
+        if let Some(root) = self.directory_map.write().expect("Lock poisoned").get_mut(root_key) {
+            update(root);
+        }
+    }
+
+    /// Records on the root under `root_key` when the current scan started, how long it took and,
+    /// unless it was stopped, how complete `completeness` finds it. Every scan that leaves a root
+    /// in the map ends here, however it ended.
+    fn stamp_root(&self, root_key: &str, completeness: impl FnOnce(&FolderHierarchy) -> Completeness) {
+        let stopped = self.is_stop_requested();
+        let elapsed = self.scan_started.lock().expect("Lock poisoned").map_or(Duration::ZERO, |started| started.elapsed());
+        let started_at = SystemTime::now().checked_sub(elapsed).unwrap_or(SystemTime::UNIX_EPOCH);
+        self.update_root(root_key, |root| {
+            root.scanned_at = epoch_millis::from_time(started_at);
+            root.scan_duration_ms = elapsed.as_micros().div_ceil(1000) as u64;
+            root.completeness = Some(if stopped { Completeness::Stopped } else { completeness(root) });
+        });
+    }
+
+    /// Ends building the root staged for `ScanOptions::atomic_swap` under `root_key`, if any,
+    /// swapping it into the map if `complete` is set and the scan was not stopped.
+    fn finish_staged_root(&self, root_key: &str, complete: bool) {
+        let Some(root) = self.staged_roots.lock().expect("Lock poisoned").remove(root_key) else {
+            return;
+        };
+        if complete && !self.is_stop_requested() {
+            self.directory_map.write().expect("Lock poisoned").insert(root_key.to_owned(), root);
+        }
+    }
+
+    /// Returns a snapshot of the hierarchy scanned so far for the most recently scanned root.
+    pub fn directory_map(&self) -> FolderHierarchy {
+        let last_root = self.last_root.lock().expect("Lock poisoned").clone();
+        last_root
+            .and_then(|root| self.directory_map_for(root))
+            .unwrap_or_default()
+    }
+
+    /// Returns a snapshot of the hierarchy scanned so far for `root`, if it has been scanned.
+    pub fn directory_map_for(&self, root: impl AsRef<Path>) -> Option<FolderHierarchy> {
+        let root_key = root_key(&root.as_ref().to_string_lossy());
+        self.directory_map.read().expect("Lock poisoned").get(&root_key).cloned()
+    }
+
+    /// Returns a snapshot of the entry at `path`, which can be a scanned root or any entry below one.
+    pub fn subtree(&self, path: impl AsRef<Path>) -> Option<FolderHierarchy> {
+        self.with_entry(path, FolderHierarchy::clone)
+    }
+
+    /// Returns the approximate memory taken by the entry at `path`, see `FolderHierarchy::memory_estimate`.
+    pub fn memory_estimate(&self, path: impl AsRef<Path>) -> Option<u64> {
+        self.with_entry(path, FolderHierarchy::memory_estimate)
+    }
+
+    /// Returns the totals for `path`, a scanned root or a directory below one.
+    pub fn scan_summary(&self, path: impl AsRef<Path>) -> Option<ScanSummary> {
+        self.with_entry(path, FolderHierarchy::summary)
+    }
+
+    /// Returns the `n` largest files under `path`, largest first, if it has been scanned.
+    /// Only the matching files are copied out of the map.
+    pub fn largest_files(&self, path: impl AsRef<Path>, n: usize) -> Option<Vec<FolderHierarchy>> {
+        self.with_entry(path, |entry| entry.largest_files(n))
+    }
+
+    /// Returns the files under `path` whose name contains `query`, see `FolderHierarchy::search_files`.
+    pub fn search_files(&self, path: impl AsRef<Path>, query: &str, limit: usize) -> Option<Vec<FolderHierarchy>> {
+        self.with_entry(path, |entry| entry.search_files(query, limit))
+    }
+
+    /// Returns the groups of identical files under `path`, see `FolderHierarchy::duplicate_files`.
+    pub fn duplicate_files(&self, path: impl AsRef<Path>) -> Option<Vec<DuplicateFiles>> {
+        self.with_entry(path, FolderHierarchy::duplicate_files)
+    }
+
+    /// Adds up the size of the files under `path` by owner, see `FolderHierarchy::usage_by_user`.
+    pub fn usage_by_user(&self, path: impl AsRef<Path>) -> Option<BTreeMap<u32, u64>> {
+        self.with_entry(path, FolderHierarchy::usage_by_user)
+    }
+
+    /// Counts the files under `path` by extension, see `FolderHierarchy::extension_histogram`.
+    pub fn extension_histogram(&self, path: impl AsRef<Path>) -> Option<BTreeMap<String, ExtensionStats>> {
+        self.with_entry(path, FolderHierarchy::extension_histogram)
+    }
+
+    /// Returns the directories under `path` without files, see `FolderHierarchy::empty_directories`.
+    pub fn empty_directories(&self, path: impl AsRef<Path>, recursive: bool) -> Option<Vec<String>> {
+        self.with_entry(path, |entry| entry.empty_directories(recursive))
+    }
+
+    /// Returns the hierarchy under `path` as flat rows, see `FolderHierarchy::flattened`.
+    pub fn flattened(&self, path: impl AsRef<Path>) -> Option<Vec<FlatEntry>> {
+        self.with_entry(path, FolderHierarchy::flattened)
+    }
+
+    /// Returns the hierarchy under `path` with the children of every node in the given order.
+    pub fn sorted(&self, path: impl AsRef<Path>, order: SortOrder) -> Option<FolderHierarchy> {
+        self.with_entry(path, |entry| entry.sorted(order))
+    }
+
+    /// Returns the hierarchy under `path` with single-child directory chains merged, see `FolderHierarchy::collapsed_chains`.
+    pub fn collapsed_chains(&self, path: impl AsRef<Path>) -> Option<FolderHierarchy> {
+        self.with_entry(path, FolderHierarchy::collapsed_chains)
+    }
+
+    /// Returns the hierarchy under `path` with small subtrees folded together, see `FolderHierarchy::pruned_below`.
+    pub fn pruned_below(&self, path: impl AsRef<Path>, min_bytes: u64) -> Option<FolderHierarchy> {
+        self.with_entry(path, |entry| entry.pruned_below(min_bytes))
+    }
+
+    /// Writes every file under `path` to `writer` as CSV, see `FolderHierarchy::write_csv`.
+    ///
+    /// Rows are streamed from the map rather than copied out of it, so a running scan cannot
+    /// publish entries until the writer is done, though other readers are not held up.
+    pub fn export_csv(&self, path: impl AsRef<Path>, writer: impl std::io::Write) -> io::Result<()> {
+        self.with_entry(path, |entry| entry.write_csv(writer))
+            .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::NotFound, "Root folder not found")))
+    }
+
+    /// Writes the hierarchy under `path` to `writer` in binary, see `FolderHierarchy::write_binary`.
+    pub fn export_binary(&self, path: impl AsRef<Path>, writer: impl std::io::Write) -> io::Result<()> {
+        self.with_entry(path, |entry| entry.write_binary(writer))
+            .unwrap_or_else(|| Err(io::Error::new(io::ErrorKind::NotFound, "Root folder not found")))
+    }
+
+    fn with_entry<R>(&self, path: impl AsRef<Path>, f: impl FnOnce(&FolderHierarchy) -> R) -> Option<R> {
+        let directory_map = self.directory_map.read().expect("Lock poisoned");
+        find_entry(&directory_map, &path.as_ref().to_string_lossy()).map(f)
+    }
+
+    /// Writes the JSON for `root` to `writer` without cloning the hierarchy.
+    ///
+    /// The JSON is rendered in memory while the map is locked, and only written out once the
+    /// lock is released, so a slow writer does not hold up the scan.
+    pub fn write_directory_map(&self, root: impl AsRef<Path>, mut writer: impl std::io::Write) -> io::Result<()> {
+        let root_key = root_key(&root.as_ref().to_string_lossy());
+        let json = {
+            let directory_map = self.directory_map.read().expect("Lock poisoned");
+            let hierarchy = directory_map
+                .get(&root_key)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Root folder not found"))?;
+            serde_json::to_vec(hierarchy)?
+        };
+        writer.write_all(&json)?;
+        writer.flush()
+    }
+
+    /// Returns the paths every root held by the scanner was scanned from, as they were passed in.
+    pub fn scanned_roots(&self) -> Vec<String> {
+        let root_paths = self.root_paths.lock().expect("Lock poisoned");
+        let directory_map = self.directory_map.read().expect("Lock poisoned");
+        directory_map.keys().map(|key| root_paths.get(key).unwrap_or(key).clone()).collect()
+    }
+
+    /// Returns how many `(files, directories)` the current scan has visited so far.
+    pub fn scan_progress(&self) -> (u64, u64) {
+        (
+            self.files_scanned.load(Ordering::Relaxed),
+            self.directories_scanned.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Returns how far along the current scan is, from 0 to 100.
+    ///
+    /// Only scans with `ScanOptions::estimate_progress` know their size up front; others stay
+    /// at 0 until they complete. Entries added while scanning could push the count past the
+    /// estimate, so the result is capped at 100.
+    pub fn scan_progress_percent(&self) -> f32 {
+        if self.scan_state() == ScanState::Completed {
+            return 100.0;
+        }
+        let total = self.entries_to_scan.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+        let (files, directories) = self.scan_progress();
+        ((files + directories) as f32 / total as f32 * 100.0).min(100.0)
+    }
+
+    /// Returns how fast the current scan is going, averaged since it started, or over the whole
+    /// scan once it is over. All zeros before the first scan.
+    pub fn scan_throughput(&self) -> ScanThroughput {
+        let Some(started) = *self.scan_started.lock().expect("Lock poisoned") else {
+            return ScanThroughput::default();
+        };
+        let ended = self.scan_ended.lock().expect("Lock poisoned").unwrap_or_else(Instant::now);
+        let elapsed = ended.saturating_duration_since(started);
+        let (files, directories) = self.scan_progress();
+        let bytes = self.bytes_scanned.load(Ordering::Relaxed);
+        let per_sec = |count: u64| match elapsed.as_secs_f64() {
+            0.0 => 0.0,
+            seconds => count as f64 / seconds,
+        };
+        ScanThroughput {
+            entries_per_sec: per_sec(files + directories),
+            bytes_per_sec: per_sec(bytes),
+            elapsed_ms: elapsed.as_millis() as u64,
+        }
+    }
+
+    /// Returns the most recent error hit by the current scan, if any.
+    pub fn last_error(&self) -> Option<String> {
+        let last_error = self.last_error.lock().expect("Lock poisoned");
+        last_error.as_ref().map(|error| format!("{}: {}", error.path, error.message))
+    }
+
+    /// Returns the category of the most recent error hit by the current scan, if any.
+    pub fn last_error_kind(&self) -> Option<ScanErrorKind> {
+        self.last_error.lock().expect("Lock poisoned").as_ref().map(|error| error.kind)
+    }
+
+    /// Returns every error hit by the current scan, in the order they happened.
+    pub fn scan_errors(&self) -> Vec<ScanError> {
+        self.scan_errors.lock().expect("Lock poisoned").clone()
+    }
+
+    /// Returns the paths the current scan was denied access to, once each and in the order they
+    /// were hit, so that a host can offer to scan them again with more privileges.
+    ///
+    /// These are the directories that could not be listed, and files that could not be read for
+    /// [`ScanOptions::hash_files`]. The scan goes on past them, leaving them out of the map.
+    pub fn inaccessible_paths(&self) -> Vec<String> {
+        let mut paths: Vec<String> = Vec::new();
+        for error in self.scan_errors.lock().expect("Lock poisoned").iter() {
+            if error.kind == ScanErrorKind::PermissionDenied && !paths.contains(&error.path) {
+                paths.push(error.path.clone());
+            }
+        }
+        paths
+    }
+
+    fn record_error(&self, path: &Path, error: &io::Error) {
+        let error = ScanError { path: display_path(path), message: error.to_string(), kind: error.kind().into(), raw_path: None };
+        self.push_error(error);
+    }
+
+    /// Records that `path`, passed in by the host, could not be scanned as it is not valid UTF-8.
+    fn record_invalid_path(&self, path: &CStr, error: std::str::Utf8Error) {
+        let error = ScanError {
+            path: display_path(Path::new(&*path.to_string_lossy())),
+            message: error.to_string(),
+            kind: ScanErrorKind::InvalidUtf8Path,
+            raw_path: None,
+        };
+        self.push_error(error);
+    }
+
+    /// Records that `path`, whose name is not valid UTF-8, is only reported approximately.
+    fn record_lossy_path(&self, path: &Path) {
+        let lossy = display_path(path);
+        let error = ScanError {
+            message: format!("Path is not valid UTF-8: {:?} is reported as {}", path.as_os_str(), lossy),
+            path: lossy,
+            kind: ScanErrorKind::LossyPath,
+            raw_path: Some(path.as_os_str().as_encoded_bytes().to_vec()),
+        };
+        self.push_error(error);
+    }
+
+    fn push_error(&self, error: ScanError) {
+        *self.last_error.lock().expect("Lock poisoned") = Some(error.clone());
+        self.scan_errors.lock().expect("Lock poisoned").push(error);
+    }
+
+    /// Registers a callback invoked each time a directory has been fully scanned.
+    ///
+    /// The callback runs on the scanner's background thread, so it must not block for long.
+    pub fn set_scan_callback(&self, callback: Option<ScanCallback>) {
+        *self.scan_callback.lock().expect("Lock poisoned") = callback;
+    }
+
+    fn notify_directory_scanned(&self, path: &Path) {
+        let callback = *self.scan_callback.lock().expect("Lock poisoned");
+        if let Some(callback) = callback {
+            if let Ok(path) = CString::new(display_path(path)) {
+                callback(path.as_ptr());
+            }
+        }
+        self.emit(ScanEventKind::Completed, path);
+    }
+
+    /// Returns a receiver for the events of every scan from now on.
+    /// Dropping the receiver unsubscribes it.
+    pub fn subscribe(&self) -> Receiver<ScanEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().expect("Lock poisoned").push(sender);
+        receiver
+    }
+
+    fn emit(&self, kind: ScanEventKind, path: &Path) {
+        let mut subscribers = self.subscribers.lock().expect("Lock poisoned");
+        if subscribers.is_empty() {
+            return;
+        }
+        let event = ScanEvent { kind, path: display_path(path) };
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Asks a running scan to stop at the next entry.
+    pub fn request_stop(&self) {
+        self.cancellation_token().cancel();
+    }
+
+    /// Stops the scans and watches, then waits up to `timeout` for the tasks of the scans to
+    /// finish, aborting those still running by then. Returns `false` if any had to be aborted.
+    ///
+    /// Blocks the calling thread, so it must not be called from async code. Called by
+    /// `free_directory_scanner`, so that no scan is left running once the scanner is freed.
+    /// The scanner must be `reset` before it can scan again.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        self.request_stop();
+        self.watchers.lock().expect("Lock poisoned").clear();
+        let tasks = std::mem::take(&mut *self.scan_tasks.lock().expect("Lock poisoned"));
+        if self.wait_for_scan_tasks(timeout) {
+            return true;
+        }
+
+        let running: Vec<_> = tasks.into_iter().filter(|task| !task.is_finished()).collect();
+        warn!("Aborting {} scan tasks still running after {timeout:?}", running.len());
+        running.iter().for_each(tokio::task::JoinHandle::abort);
+        // Aborted tasks let go of the scanner the next time they are polled, which is right away
+        // unless they are stuck on a blocking call.
+        self.wait_for_scan_tasks(ABORT_GRACE);
+        false
+    }
+
+    pub fn is_stop_requested(&self) -> bool {
+        self.cancellation.lock().expect("Lock poisoned").is_cancelled()
+    }
+
+    /// Returns the token that stops this scanner's scans when cancelled, just like
+    /// [`DirectoryScanner::request_stop`]. Scans stop promptly, without waiting on directories
+    /// still being read.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.lock().expect("Lock poisoned").clone()
+    }
+
+    /// Stops this scanner's scans once `token` is cancelled, so that a scan can be cancelled
+    /// along with the rest of an async application. Stopping the scanner does not cancel `token`.
+    ///
+    /// Scans already running keep the token they started with, which is cancelled along with the
+    /// new one, so they stop on `token` or [`DirectoryScanner::request_stop`] too.
+    pub fn set_cancellation_token(&self, token: &CancellationToken) {
+        let linked = token.child_token();
+        let previous = std::mem::replace(&mut *self.cancellation.lock().expect("Lock poisoned"), linked.clone());
+        if previous.is_cancelled() {
+            return;
+        }
+        self.runtime().spawn(async move {
+            tokio::select! {
+                () = linked.cancelled() => previous.cancel(),
+                // Stopped on its own, or by `reset`.
+                () = previous.cancelled() => {}
+            }
+        });
+    }
+
+    /// Makes a running scan wait at the next entry until [`DirectoryScanner::resume`] is called.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Waits until the scanner is resumed or its scans are stopped, yielding the runtime thread meanwhile.
+    async fn wait_while_paused(&self) {
+        if !self.is_paused() {
+            return;
+        }
+        let mut paused = self.paused.subscribe();
+        let cancellation = self.cancellation_token();
+        tokio::select! {
+            // The sender lives as long as the scanner, so this only ends once resumed.
+            _ = paused.wait_for(|paused| !paused) => {}
+            () = cancellation.cancelled() => {}
+        }
+    }
+}
+
+/// Held by a spawned scan task until it is done or dropped, when it wakes up `shutdown`.
+struct RunningScan {
+    running_scans: Arc<AtomicUsize>,
+    state_lock: Arc<Mutex<()>>,
+    state_changed: Arc<Condvar>,
+}
+
+impl Drop for RunningScan {
+    fn drop(&mut self) {
+        let _state_lock = self.state_lock.lock().expect("Lock poisoned");
+        self.running_scans.fetch_sub(1, Ordering::AcqRel);
+        self.state_changed.notify_all();
+    }
+}
+
+impl Drop for DirectoryScanner {
+    fn drop(&mut self) {
+        debug!("Scanner is closing...");
+        if let Some(ScannerRuntime::Owned(runtime)) = self.runtime.get_mut().expect("Lock poisoned").take() {
+            // The last reference can be released from async code, even from one of our own scans,
+            // where a blocking shutdown would panic.
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Scans `path` to completion on the calling thread and returns its whole hierarchy.
+///
+/// Only failing to read `path` itself is an error; entries below it that cannot be read are skipped.
+///
+/// ```
+/// let dir = tempfile::tempdir().unwrap();
+/// std::fs::create_dir(dir.path().join("docs")).unwrap();
+/// std::fs::write(dir.path().join("docs/notes.txt"), "hello").unwrap();
+///
+/// let hierarchy = directory_scanner::scan_blocking(dir.path()).unwrap();
+/// assert_eq!(hierarchy.value, 5);
+/// assert_eq!(hierarchy.children[0].name, "docs");
+/// assert_eq!(hierarchy.children[0].children[0].name, "notes.txt");
+/// ```
+pub fn scan_blocking(path: impl AsRef<Path>) -> io::Result<FolderHierarchy> {
+    let runtime = Builder::new_current_thread().enable_all().build()?;
+    let scanner = Arc::new(DirectoryScanner::with_runtime(None));
+    let directory_path = extended_length(path.as_ref());
+    let options = ScanOptions::default();
+    let traversal = Traversal::new(&options);
+
+    runtime.block_on(async {
+        traversal.first_visit(&options, &directory_path, &*scanner.file_system).await;
+        scan_folder(directory_path, Arc::clone(&scanner), &options, 0, &traversal).await
+    })
+}
+
+/// Scans `directory_path` until a file matching `predicate` is found, and returns it.
+async fn find_first_file(directory_path: PathBuf, scanner: Arc<DirectoryScanner>, predicate: Box<dyn Fn(&FolderHierarchy) -> bool + Send + Sync>) -> io::Result<Option<FolderHierarchy>> {
+    let options = ScanOptions::default();
+    let first_match = FirstMatch { predicate, found: Mutex::new(None) };
+    let traversal = Traversal { first_match: Some(first_match), ..Traversal::new(&options) };
+    traversal.first_visit(&options, &directory_path, &*scanner.file_system).await;
+
+    match scan_folder(directory_path, Arc::clone(&scanner), &options, 0, &traversal).await {
+        // Stopped by finding the file before `directory_path` was scanned in full.
+        Err(_) if scanner.is_stop_requested() => {}
+        result => drop(result?),
+    }
+    Ok(traversal.first_match.and_then(|first_match| first_match.found.into_inner().expect("Lock poisoned")))
+}
+
+/// Reads a hierarchy written by a scan with `ScanOptions::snapshot_path`, or by `write_directory_map`.
+pub fn load_snapshot(path: impl AsRef<Path>) -> io::Result<FolderHierarchy> {
+    let json = std::fs::read(path)?;
+    hierarchy_from_json(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Parses a hierarchy serialized as JSON however deeply it nests, as deep as the trees a scan
+/// can walk, growing the stack as needed instead of stopping at serde_json's recursion limit.
+fn hierarchy_from_json(json: &[u8]) -> serde_json::Result<FolderHierarchy> {
+    let mut deserializer = serde_json::Deserializer::from_slice(json);
+    deserializer.disable_recursion_limit();
+    let hierarchy = FolderHierarchy::deserialize(serde_stacker::Deserializer::new(&mut deserializer))?;
+    deserializer.end()?;
+    Ok(hierarchy)
+}
+
+/// Writes a snapshot of `root` every `interval`, and a last one once `done` is cancelled.
+async fn write_snapshots(scanner: Arc<DirectoryScanner>, root: PathBuf, snapshot_path: PathBuf, interval: Duration, done: CancellationToken) {
+    let mut ticks = tokio::time::interval(interval.max(Duration::from_millis(1)));
+    // The first tick completes right away, when there is nothing to save yet.
+    ticks.tick().await;
+    loop {
+        let finished = tokio::select! {
+            _ = ticks.tick() => false,
+            _ = done.cancelled() => true,
+        };
+        scanner.write_snapshot(&root, &snapshot_path).await;
+        if finished {
+            return;
+        }
+    }
+}
+
+/// Stops the snapshots of a scan, if it takes any, once their last one is written.
+async fn finish_snapshots(snapshots: &mut Option<(CancellationToken, tokio::task::JoinHandle<()>)>) {
+    if let Some((done, task)) = snapshots.take() {
+        done.cancel();
+        // Only fails if the runtime is shutting down, in which case there is nothing left to wait for.
+        let _ = task.await;
+    }
+}
+
+/// Entries a scan handles in a row before letting the other tasks on its runtime run, so that a
+/// directory with many entries ready at once does not hold up a single-threaded runtime.
+pub const YIELD_INTERVAL: usize = 256;
+
+/// Lets the other tasks on the runtime run once every `YIELD_INTERVAL` entries, `handled`
+/// being the number handled so far.
+async fn yield_periodically(handled: usize) {
+    if handled % YIELD_INTERVAL == YIELD_INTERVAL - 1 {
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Counts the directories and files a scan of `directory_path` will visit, itself included.
+async fn count_entries(directory_path: PathBuf, scanner: &DirectoryScanner, options: &ScanOptions, traversal: &Traversal) -> u64 {
+    let cancellation = scanner.cancellation_token();
+    let mut count = 0;
+    let mut pending = vec![directory_path];
+    while let Some(directory_path) = pending.pop() {
+        scanner.wait_while_paused().await;
+        if cancellation.is_cancelled() {
+            return count;
+        }
+        count += 1;
+        // Given up as soon as a stop is requested, even while a slow directory is being opened.
+        let Some(listing) = cancellation.run_until_cancelled(scanner.file_system.read_dir(&directory_path)).await else {
+            return count;
+        };
+        let Ok(mut entries) = listing else {
+            continue;
+        };
+        let mut handled = 0;
+        while let Some(Ok(entry)) = entries.next().await {
+            yield_periodically(handled).await;
+            handled += 1;
+            if cancellation.is_cancelled() {
+                return count;
+            }
+            if options.skips(&entry.path) {
+                continue;
+            }
+            let entry = ListedEntry::read(entry, &*scanner.file_system, options).await;
+            match &entry.metadata {
+                Ok(metadata) if options.descends_into(&entry, metadata) => {
+                    if !traversal.excludes_directory(options, &entry.path, &*scanner.file_system).await && traversal.first_visit(options, &entry.path, &*scanner.file_system).await {
+                        pending.push(entry.path);
+                    }
+                }
+                _ => count += 1,
+            }
+        }
+    }
+    count
+}
+
+/// Reads the entries of `directory_path` that `options` lets through.
+///
+/// The directory handle is closed before returning, so callers can go on to the entries
+/// without keeping a file descriptor open for every level.
+async fn list_entries(directory_path: &Path, scanner: &DirectoryScanner, options: &ScanOptions) -> io::Result<Vec<ListedEntry>> {
+    let cancellation = scanner.cancellation_token();
+    let mut entries = read_dir_retrying(directory_path, &*scanner.file_system, options).await?;
+    scanner.directories_scanned.fetch_add(1, Ordering::Relaxed);
+    scanner.emit(ScanEventKind::Started, directory_path);
+
+    let mut listed = Vec::new();
+    for handled in 0.. {
+        yield_periodically(handled).await;
+        let entry = match entries.next().await {
+            Some(Ok(entry)) => entry,
+            None => break,
+            Some(Err(e)) => {
+                scanner.record_error(directory_path, &e);
+                break;
+            }
+        };
+        scanner.wait_while_paused().await;
+        if cancellation.is_cancelled() {
+            break;
+        }
+
+        if options.skips(&entry.path) {
+            continue;
+        }
+        if entry.path.file_name().is_some_and(|name| name.to_str().is_none()) {
+            scanner.record_lossy_path(&entry.path);
+        }
+        listed.push(ListedEntry::read(entry, &*scanner.file_system, options).await);
+    }
+    Ok(listed)
+}
+
+/// Opens `directory_path` for listing, trying again after transient failures with exponential backoff.
+async fn read_dir_retrying<'a>(directory_path: &'a Path, file_system: &'a dyn FileSystem, options: &ScanOptions) -> io::Result<BoxStream<'a, io::Result<DirectoryEntry>>> {
+    let mut retries = 0;
+    let mut backoff = options.retry_backoff;
+    loop {
+        match file_system.read_dir(directory_path).await {
+            Err(e) if retries < options.max_retries && ScanErrorKind::from(e.kind()).is_transient() => {
+                debug!("Retrying {} after {}", directory_path.display(), e);
+                tokio::time::sleep(backoff).await;
+                retries += 1;
+                backoff = backoff.saturating_mul(2);
+            }
+            read => return read,
+        }
+    }
+}
+
+/// An entry found in a directory, with the metadata the scan goes by.
+struct ListedEntry {
+    path: PathBuf,
+    entry_type: EntryKind,
+    metadata: io::Result<EntryMetadata>,
+}
+
+impl ListedEntry {
+    async fn read(entry: DirectoryEntry, file_system: &dyn FileSystem, options: &ScanOptions) -> Self {
+        let (path, entry_type) = (entry.path.clone(), entry.kind);
+        let metadata = options.entry_metadata(entry, file_system).await;
+        ListedEntry { path, entry_type, metadata }
+    }
+}
+
+/// Bytes read from the start of a file to recognise its content, enough for the formats `infer` knows.
+const CONTENT_SNIFF_BYTES: u64 = 8192;
+
+/// Kind of content of the file at `path` going by its first bytes, or `None` if it is not
+/// recognised or cannot be read.
+async fn content_category(path: &Path) -> Option<String> {
+    use tokio::io::AsyncReadExt;
+
+    let file = fs::File::open(path).await.ok()?;
+    let mut head = Vec::new();
+    file.take(CONTENT_SNIFF_BYTES).read_to_end(&mut head).await.ok()?;
+    let category = match infer::get(&head)?.matcher_type() {
+        infer::MatcherType::Image => "image",
+        infer::MatcherType::Video => "video",
+        infer::MatcherType::Audio => "audio",
+        infer::MatcherType::Doc | infer::MatcherType::Book => "document",
+        infer::MatcherType::Archive => "archive",
+        infer::MatcherType::Font => "font",
+        infer::MatcherType::Text => "text",
+        infer::MatcherType::App => "application",
+        infer::MatcherType::Custom => return None,
+    };
+    Some(category.to_owned())
+}
+
+/// xxHash3 of the contents of the file at `path`, as 16 hexadecimal digits.
+async fn content_hash(path: &Path) -> io::Result<String> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        use std::{hash::Hasher, io::Read};
+
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = twox_hash::XxHash3_64::new();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match file.read(&mut buffer)? {
+                0 => return Ok(format!("{:016x}", hasher.finish())),
+                read => hasher.write(&buffer[..read]),
+            }
+        }
+    })
+    .await?
+}
+
+/// Entries of a zip archive below one of its directories, the archive itself at the top.
+#[derive(Default)]
+struct ArchiveDirectory {
+    directories: BTreeMap<String, ArchiveDirectory>,
+    /// Names and uncompressed sizes, in the order of the archive.
+    files: Vec<(String, u64)>,
+}
+
+impl ArchiveDirectory {
+    /// Lists the entries of the zip archive at `path`. Entries whose names would lead out of
+    /// the archive, such as `../file`, are left out.
+    async fn read(path: &Path) -> io::Result<Self> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?).map_err(io::Error::other)?;
+            let mut root = ArchiveDirectory::default();
+            for index in 0..archive.len() {
+                let entry = archive.by_index_raw(index).map_err(io::Error::other)?;
+                let Some(name) = entry.enclosed_name() else { continue };
+                let mut components: Vec<String> = name.iter().map(|component| component.to_string_lossy().into_owned()).collect();
+                let file = if entry.is_dir() { None } else { components.pop() };
+                let directory = components.into_iter().fold(&mut root, |directory, component| directory.directories.entry(component).or_default());
+                if let Some(file) = file {
+                    directory.files.push((file, entry.size()));
+                }
+            }
+            Ok(root)
+        })
+        .await?
+    }
+
+    /// Adds the entries to `hierarchy`, the node of the directory at `path`, be it the archive
+    /// or a directory inside it.
+    fn add_to(self, hierarchy: &mut FolderHierarchy, path: &Path) {
+        for (name, directory) in self.directories {
+            let directory_path = path.join(&name);
+            let mut child = FolderHierarchy::directory(&directory_path);
+            directory.add_to(&mut child, &directory_path);
+            hierarchy.add_child(child, true);
+        }
+        for (name, size) in self.files {
+            let child = FolderHierarchy {
+                value: size,
+                path: display_path(&path.join(&name)),
+                name,
+                entry_type: EntryKind::File,
+                file_count: 1,
+                ..Default::default()
+            };
+            hierarchy.add_child(child, true);
+        }
+        hierarchy.update_percentages();
+    }
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// Builds the entry for a file or an unfollowed link, or `None` if it is filtered out or cannot be read.
+async fn scan_leaf(path: &Path, entry_type: EntryKind, metadata: &EntryMetadata, scanner: &DirectoryScanner, options: &ScanOptions, depth: usize, traversal: &Traversal) -> Option<FolderHierarchy> {
+    scanner.files_scanned.fetch_add(1, Ordering::Relaxed);
+    scanner.bytes_scanned.fetch_add(metadata.len, Ordering::Relaxed);
+    if !options.accepts_file(path) || (!options.count_unmatched_files && !traversal.matches_globs(path, depth)) {
+        return None;
+    }
+
+    let mut file = FolderHierarchy::file(path, entry_type, metadata);
+    file.path = traversal.node_path(path);
+    file.symlink_target = symlink_target(path, entry_type, &*scanner.file_system).await;
+    options.set_owner(&mut file, metadata);
+    if options.on_disk_size {
+        file.value = metadata.allocated;
+    }
+    if options.is_small(&file) && !options.count_small_files {
+        return None;
+    }
+    if options.dedup_hardlinks && !traversal.first_link(metadata) {
+        file.value = 0;
+    }
+    if options.detect_content_type && metadata.kind == EntryKind::File && metadata.len > 0 {
+        file.category = content_category(path).await;
+    }
+    if options.hash_files && metadata.kind == EntryKind::File {
+        let _permit = traversal.open_directories.acquire().await.expect("Semaphore closed");
+        match content_hash(path).await {
+            Ok(hash) => file.hash = Some(hash),
+            Err(e) => scanner.record_error(path, &e),
+        }
+    }
+    // Later links to an archive already counted keep their size of 0.
+    if options.descend_archives && metadata.kind == EntryKind::File && file.value > 0 && is_zip(path) {
+        match ArchiveDirectory::read(path).await {
+            Ok(archive) => {
+                file.value = 0;
+                file.file_count = 0;
+                // Entries are given paths below the archive's, relative or not.
+                let archive_path = PathBuf::from(&file.path);
+                archive.add_to(&mut file, &archive_path);
+                file.sort_children(options.sort);
+            }
+            Err(e) => scanner.record_error(path, &e),
+        }
+    }
+    if let Some(first_match) = &traversal.first_match {
+        if (first_match.predicate)(&file) {
+            first_match.found.lock().expect("Lock poisoned").get_or_insert_with(|| file.clone());
+            scanner.request_stop();
+        }
+    }
+    Some(file)
+}
+
+/// Entry for a file scanned as if it were a directory, a common mistake that should not fail the
+/// scan. The file is matched against the filters by its name, like the entries of a directory,
+/// and reported with nothing counted if they leave it out.
+async fn scan_root_file(path: &Path, metadata: &EntryMetadata, scanner: &DirectoryScanner, options: &ScanOptions, traversal: &Traversal) -> FolderHierarchy {
+    match scan_leaf(path, EntryKind::File, metadata, scanner, options, 1, traversal).await {
+        Some(file) => file,
+        None => FolderHierarchy { value: 0, file_count: 0, path: traversal.node_path(path), ..FolderHierarchy::file(path, EntryKind::File, metadata) },
+    }
+}
+
+/// A directory found by `scan_folder`, kept until everything below it has been scanned.
+struct PendingDirectory {
+    path: PathBuf,
+    depth: usize,
+    parent: Option<usize>,
+    hierarchy: FolderHierarchy,
+    /// Entries in listing order, once the directory has been read.
+    children: Option<Vec<PendingChild>>,
+    /// Subdirectories that are not done yet.
+    remaining: usize,
+    /// The finished hierarchy, until the parent picks it up.
+    result: Option<FolderHierarchy>,
+    /// Cleared when a stop leaves out the directory or anything below it.
+    complete: bool,
+    /// Whether the directory has a place in its parent's children, given when it was found.
+    kept: bool,
+}
+
+// Most children are leaves, which would only be moved out of a box again once their directory is done.
+#[allow(clippy::large_enum_variant)]
+enum PendingChild {
+    /// A leaf, and whether it has a place in its parent's children.
+    Leaf(FolderHierarchy, bool),
+    /// Index of the subdirectory in the directories of the scan.
+    Directory(usize),
+}
+
+impl PendingDirectory {
+    fn new(path: PathBuf, entry_type: EntryKind, depth: usize, parent: Option<usize>, traversal: &Traversal) -> Self {
+        let mut hierarchy = FolderHierarchy::directory(&path);
+        hierarchy.path = traversal.node_path(&path);
+        hierarchy.entry_type = entry_type;
+        PendingDirectory { path, depth, parent, hierarchy, children: None, remaining: 0, result: None, complete: true, kept: true }
+    }
+}
+
+/// Opens `path` and reads its metadata and entries, holding one of the traversal's directory permits meanwhile.
+async fn read_directory(index: usize, path: PathBuf, scanner: &DirectoryScanner, options: &ScanOptions, traversal: &Traversal) -> (usize, io::Result<(EntryMetadata, Vec<ListedEntry>)>) {
+    let _permit = traversal.open_directories.acquire().await.expect("Semaphore closed");
+    let listing = match scanner.file_system.metadata(&path).await {
+        Ok(metadata) => list_entries(&path, scanner, options).await.map(|entries| (metadata, entries)),
+        Err(e) => Err(e),
+    };
+    (index, listing)
+}
+
+/// Scans `directory_path` and everything below it.
+///
+/// Directories are read from a work queue instead of by recursion, so that no depth of tree can
+/// overflow the stack. Up to `max_concurrency` of them are read at once, and each is assembled
+/// into its parent as soon as everything below it has been scanned.
+///
+/// A file passed in place of the directory is reported on its own, see `scan_root_file`.
+///
+/// Once a stop is requested, every directory that was not scanned in full is dropped, so that
+/// only complete branches are kept below `directory_path`. `directory_path` itself is kept to
+/// hold them, unless the stop came before its own entries were read, when `Interrupted` is returned.
+async fn scan_folder(directory_path: PathBuf, scanner: Arc<DirectoryScanner>, options: &ScanOptions, depth: usize, traversal: &Traversal) -> io::Result<FolderHierarchy> {
+    scan_tree(directory_path, scanner, options, depth, traversal).await.map(|(hierarchy, _)| hierarchy)
+}
+
+/// Does the work of `scan_folder`, also telling whether `directory_path` was scanned in full.
+async fn scan_tree(directory_path: PathBuf, scanner: Arc<DirectoryScanner>, options: &ScanOptions, depth: usize, traversal: &Traversal) -> io::Result<(FolderHierarchy, bool)> {
+    let metadata = scanner.file_system.metadata(&directory_path).await?;
+    if metadata.kind != EntryKind::Directory {
+        return Ok((scan_root_file(&directory_path, &metadata, &scanner, options, traversal).await, true));
+    }
+    let cancellation = scanner.cancellation_token();
+    let mut directories = vec![PendingDirectory::new(directory_path.clone(), EntryKind::Directory, depth, None, traversal)];
+    let mut queue = VecDeque::from([(0, directory_path)]);
+    let mut reading = FuturesUnordered::new();
+    let mut in_flight = HashSet::new();
+
+    loop {
+        while reading.len() < options.max_concurrency.max(1) {
+            let Some((index, path)) = queue.pop_front() else {
+                break;
+            };
+            if cancellation.is_cancelled() {
+                // Not read at all, so it is left out like the entries after it.
+                directories[index].complete = false;
+                settle(&mut directories, index, false, &scanner, options, traversal);
+                continue;
+            }
+            in_flight.insert(index);
+            reading.push(read_directory(index, path, &scanner, options, traversal));
+        }
+
+        let next = tokio::select! {
+            biased;
+            next = reading.next() => next,
+            _ = cancellation.cancelled() => {
+                // Directories still being read are left out too, so a stop does not wait on a slow file system.
+                reading.clear();
+                for index in std::mem::take(&mut in_flight) {
+                    directories[index].complete = false;
+                    settle(&mut directories, index, false, &scanner, options, traversal);
+                }
+                continue;
+            }
+        };
+        let Some((index, listing)) = next else {
+            break;
+        };
+        in_flight.remove(&index);
+        let (metadata, entries) = match listing {
+            Ok(listing) => listing,
+            Err(e) if index == 0 => return Err(e),
+            Err(e) => {
+                scanner.record_error(&directories[index].path, &e);
+                settle(&mut directories, index, false, &scanner, options, traversal);
+                continue;
+            }
+        };
+
+        let (queued, pending) = (queue.len(), directories.len());
+        let depth = directories[index].depth + 1;
+        let keeps_children = directories[index].kept && options.keeps_children(directories[index].depth);
+        let mut children = Vec::with_capacity(entries.len());
+        for (handled, entry) in entries.into_iter().enumerate() {
+            yield_periodically(handled).await;
+            if cancellation.is_cancelled() {
+                break;
+            }
+            let entry_metadata = match &entry.metadata {
+                Ok(entry_metadata) => entry_metadata,
+                Err(e) => {
+                    // Most likely removed since the directory was listed.
+                    scanner.record_error(&entry.path, e);
+                    continue;
+                }
+            };
+            if !options.descends_into(&entry, entry_metadata) {
+                let leaf = scan_leaf(&entry.path, entry.entry_type, entry_metadata, &scanner, options, depth, traversal);
+                // Hashing or opening an archive can take a while, so a stop does not wait for it.
+                let Some(leaf) = cancellation.run_until_cancelled(leaf).await else {
+                    break;
+                };
+                if let Some(leaf) = leaf {
+                    let keep = keeps_children && traversal.lists(options, &leaf, depth) && traversal.keeps(&leaf);
+                    if index == 0 {
+                        traversal.root_entry_scanned(&leaf, keep);
+                    }
+                    children.push(PendingChild::Leaf(leaf, keep));
+                }
+            } else if !traversal.excludes_directory(options, &entry.path, &*scanner.file_system).await && traversal.first_visit(options, &entry.path, &*scanner.file_system).await {
+                let child = directories.len();
+                let mut directory = PendingDirectory::new(entry.path.clone(), entry.entry_type, depth, Some(index), traversal);
+                directory.hierarchy.symlink_target = symlink_target(&entry.path, entry.entry_type, &*scanner.file_system).await;
+                directory.kept = keeps_children && traversal.keeps(&directory.hierarchy);
+                directories.push(directory);
+                children.push(PendingChild::Directory(child));
+                queue.push_back((child, entry.path));
+            }
+        }
+        if cancellation.is_cancelled() {
+            // Left out along with the subdirectories it queued, rather than kept with only some of
+            // its entries, so that a stopped scan only keeps directories scanned in full.
+            queue.truncate(queued);
+            directories.truncate(pending);
+            directories[index].complete = false;
+            settle(&mut directories, index, false, &scanner, options, traversal);
+            continue;
+        }
+
+        let directory = &mut directories[index];
+        directory.hierarchy.modified = metadata.modified;
+        directory.hierarchy.created = metadata.created;
+        directory.hierarchy.mode = metadata.mode;
+        options.set_owner(&mut directory.hierarchy, &metadata);
+        directory.remaining = children.iter().filter(|child| matches!(child, PendingChild::Directory(_))).count();
+        directory.children = Some(children);
+        if directory.remaining == 0 {
+            settle(&mut directories, index, true, &scanner, options, traversal);
+        }
+    }
+
+    let complete = directories[0].complete;
+    let hierarchy = directories[0].result.take().ok_or_else(|| io::Error::new(io::ErrorKind::Interrupted, "Scan stopped"))?;
+    Ok((hierarchy, complete))
+}
+
+/// Finishes the directory at `index` once nothing below it is pending, then every ancestor left
+/// waiting only for it. A directory that was not `read` is dropped from its parent, and so is
+/// one left incomplete by a stop, though the scanned root is still finished to hold what was kept.
+fn settle(directories: &mut [PendingDirectory], mut index: usize, mut read: bool, scanner: &DirectoryScanner, options: &ScanOptions, traversal: &Traversal) {
+    loop {
+        if read {
+            let mut hierarchy = std::mem::take(&mut directories[index].hierarchy);
+            let mut complete = true;
+            for child in directories[index].children.take().unwrap_or_default() {
+                let (child, keep) = match child {
+                    PendingChild::Leaf(leaf, keep) => (leaf, keep),
+                    PendingChild::Directory(child) => match directories[child].result.take() {
+                        Some(result) => (result, directories[child].kept),
+                        None => {
+                            complete &= directories[child].complete;
+                            continue;
+                        }
+                    },
+                };
+                hierarchy.add_child(child, keep);
+            }
+            options.sort.sort(&mut hierarchy.children);
+            hierarchy.update_percentages();
+            directories[index].complete = complete;
+            if complete {
+                // The scanned root is streamed by the caller instead, once it has been stamped.
+                if let Some(parent) = directories[index].parent {
+                    traversal.directory_scanned(&hierarchy);
+                    if parent == 0 {
+                        traversal.root_entry_scanned(&hierarchy, directories[index].kept);
+                    }
+                }
+                scanner.notify_directory_scanned(&directories[index].path);
+                directories[index].result = Some(hierarchy);
+            } else if directories[index].parent.is_none() {
+                directories[index].result = Some(hierarchy);
+            } else if directories[index].kept {
+                traversal.release(&hierarchy);
+            }
+        } else if directories[index].kept {
+            traversal.release(&directories[index].hierarchy);
+        }
+
+        let Some(parent) = directories[index].parent else {
+            return;
+        };
+        let parent_directory = &mut directories[parent];
+        parent_directory.remaining -= 1;
+        if parent_directory.children.is_none() || parent_directory.remaining > 0 {
+            return;
+        }
+        index = parent;
+        read = true;
+    }
+}
+
+/// Worker threads of the runtime a scanner creates for itself, unless told otherwise with
+/// `DirectoryScanner::set_scan_threads`: one per core, as Tokio does.
+pub fn default_scan_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, usize::from)
+}
+
+/// A multi-threaded runtime with `threads` worker threads, for a scanner to own.
+fn owned_runtime(threads: usize) -> io::Result<Runtime> {
+    Builder::new_multi_thread().worker_threads(threads).enable_all().thread_name("directory-scanner").build()
+}
+
+/// How long `free_directory_scanner` waits for running scans to stop before aborting them.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `DirectoryScanner::shutdown` lets aborted tasks wind down.
+const ABORT_GRACE: Duration = Duration::from_millis(100);
+
+/// How long `DirectoryScanner::watch` waits for more changes before applying those it has.
+pub const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Applies the changes reported for the watched root at `root_path`, until `stop` is cancelled
+/// or the scanner is dropped.
+async fn apply_changes(scanner: std::sync::Weak<DirectoryScanner>, root_path: PathBuf, mut changes: tokio::sync::mpsc::UnboundedReceiver<Vec<PathBuf>>, stop: CancellationToken) {
+    let watched_key = root_key(&root_path.to_string_lossy());
+    loop {
+        let Some(Some(mut changed)) = stop.run_until_cancelled(changes.recv()).await else {
+            return;
+        };
+        while let Some(Ok(Some(more))) = stop.run_until_cancelled(tokio::time::timeout(WATCH_DEBOUNCE, changes.recv())).await {
+            changed.extend(more);
+        }
+        let Some(scanner) = scanner.upgrade().filter(|_| !stop.is_cancelled()) else {
+            return;
+        };
+
+        let options = scanner.root_options.lock().expect("Lock poisoned").get(&watched_key).cloned().unwrap_or_default();
+        // `max_nodes` applies to the whole tree, so such roots are scanned again as a whole.
+        if options.max_nodes.is_some() {
+            changed = vec![root_path.clone()];
+        }
+
+        // The directories to scan again, leaving out those inside another one.
+        let mut directories = BTreeMap::new();
+        for path in changed {
+            let directory = match scanner.file_system.metadata(&path).await {
+                Ok(metadata) if metadata.kind == EntryKind::Directory => path,
+                _ => path.parent().map(Path::to_path_buf).unwrap_or(path),
+            };
+            directories.insert(root_key(&display_path(&directory)), directory);
+        }
+        let mut outermost: Vec<(String, PathBuf)> = Vec::new();
+        for (key, directory) in directories {
+            if outermost.last().is_none_or(|(outer, _)| relative_key(&key, outer).is_none()) {
+                outermost.push((key, directory));
+            }
+        }
+
+        // Scanned apart, so that updates leave the progress and errors of the last scan alone.
+        let isolated = scanner.isolated();
+        for (_, mut directory) in outermost {
+            // Entries new to the map are picked up by scanning the closest directory it has.
+            loop {
+                let key = root_key(&display_path(&directory));
+                let Some(relative) = relative_key(&key, &watched_key) else {
+                    break;
+                };
+                // Scanned at its own level with the options of the root, so that filters and `max_depth` still hold.
+                let depth = relative.split('/').filter(|name| !name.is_empty()).count();
+                let traversal = Traversal { relative_root: options.relative_paths.then(|| root_path.clone()), ..Traversal::new(&options) };
+                if let Ok(hierarchy) = scan_folder(directory.clone(), Arc::clone(&isolated), &options, depth, &traversal).await {
+                    if stop.is_cancelled() {
+                        return;
+                    }
+                    if scanner.replace_entry(&watched_key, &directory, hierarchy) {
+                        scanner.emit(ScanEventKind::Changed, &directory);
+                        break;
+                    }
+                }
+                match directory.parent() {
+                    Some(parent) => directory = parent.to_path_buf(),
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+/// Rescans `directory_path` reusing `cached` wherever the directory has not been modified since.
+///
+/// A directory's modification time only changes when entries are added, removed or renamed in it,
+/// so unchanged directories keep their cached file entries and only their subdirectories are checked.
+async fn rescan_folder(directory_path: PathBuf, cached: FolderHierarchy, scanner: Arc<DirectoryScanner>, options: &ScanOptions, depth: usize) -> io::Result<FolderHierarchy> {
+    let metadata = scanner.file_system.metadata(&directory_path).await?;
+    let modified = metadata.modified;
+    if modified.is_none() || modified != cached.modified || metadata.kind != EntryKind::Directory || !lists_everything(&cached) {
+        return scan_folder(directory_path, scanner, options, depth, &Traversal::new(options)).await;
+    }
+
+    // Unchanged directories being rebuilt, innermost last, with the cached children left to check.
+    let mut unchanged = vec![(rebuilt_without_children(&cached), cached.children.into_iter())];
+    loop {
+        let (hierarchy, remaining) = unchanged.last_mut().expect("At least the root is being rebuilt");
+        let Some(child) = remaining.next() else {
+            let (mut finished, _) = unchanged.pop().expect("At least the root is being rebuilt");
+            options.sort.sort(&mut finished.children);
+            finished.update_percentages();
+            match unchanged.last_mut() {
+                Some((parent, _)) => parent.add_child(finished, true),
+                None => return Ok(finished),
+            }
+            continue;
+        };
+        if child.entry_type != EntryKind::Directory {
+            hierarchy.add_child(child, true);
+            continue;
+        }
+
+        let child_path = extended_length(Path::new(&child.path));
+        let modified = match scanner.file_system.metadata(&child_path).await {
+            Ok(metadata) => metadata.modified,
+            Err(e) => {
+                scanner.record_error(&child_path, &e);
+                continue;
+            }
+        };
+        if modified.is_some() && modified == child.modified && lists_everything(&child) {
+            unchanged.push((rebuilt_without_children(&child), child.children.into_iter()));
+            continue;
+        }
+        match scan_folder(child_path.clone(), Arc::clone(&scanner), options, depth + unchanged.len(), &Traversal::new(options)).await {
+            Ok(rescanned) => unchanged.last_mut().expect("At least the root is being rebuilt").0.add_child(rescanned, true),
+            Err(_) if scanner.is_stop_requested() => {}
+            Err(e) => scanner.record_error(&child_path, &e),
+        }
+    }
+}
+
+/// Whether the children of a cached directory add up to its totals, so that it can be rebuilt
+/// from them. Those of a directory cut off by `max_depth` or with files left out of its children,
+/// such as by `min_size`, do not, and the directory is scanned again instead.
+fn lists_everything(cached: &FolderHierarchy) -> bool {
+    let (value, file_count, dir_count) = cached.children.iter().fold((0, 0, 0), |(value, file_count, dir_count), child| {
+        (value + child.value, file_count + child.file_count, dir_count + child.dir_count + u64::from(child.entry_type == EntryKind::Directory))
+    });
+    (value, file_count, dir_count) == (cached.value, cached.file_count, cached.dir_count)
+}
+
+/// Copy of a cached directory with its totals cleared, ready to have its children added back.
+fn rebuilt_without_children(cached: &FolderHierarchy) -> FolderHierarchy {
+    let mut hierarchy = cached.without_children();
+    hierarchy.value = 0;
+    hierarchy.file_count = 0;
+    hierarchy.dir_count = 0;
+    hierarchy.has_children = false;
+    hierarchy
+}
+
+#[no_mangle]
+pub extern "C" fn create_directory_scanner() -> *mut DirectoryScanner {
+    Arc::into_raw(DirectoryScanner::open()) as *mut DirectoryScanner
+}
+
+#[no_mangle]
+pub extern "C" fn free_directory_scanner(scanner_ptr: *mut DirectoryScanner) {
+    // Safety: Ensure the provided pointer is valid and not null
+    if !scanner_ptr.is_null() {
+        // Convert the raw pointer back to an Arc, which will be dropped at the end of this scope
+        // Dropping the last Arc will free the DirectoryScanner
+        let scanner = unsafe { Arc::from_raw(scanner_ptr) };
+        // The scans hold references of their own, so stop them first rather than leave them running.
+        scanner.shutdown(SHUTDOWN_TIMEOUT);
+    }
+}
+
+/// Returns the version of the library, to be released with `free_directory_map_string`.
+#[no_mangle]
+pub extern "C" fn get_version() -> *mut c_char {
+    CString::new(VERSION).unwrap().into_raw()
+}
+
+/// Returns a JSON object with the `version`, `target`, `profile` and `features` the library was built with.
+#[no_mangle]
+pub extern "C" fn get_build_info() -> *mut c_char {
+    CString::new(to_json(&build_info())).unwrap().into_raw()
+}
+
+
+#[no_mangle]
+pub extern "C" fn scan_directory_async(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        // The pointer came from `Arc::into_raw`, so take an extra reference for the scan thread.
+        Arc::increment_strong_count(scanner_ptr);
+        Arc::from_raw(scanner_ptr)
+    };
+
+    let c_str = unsafe { CStr::from_ptr(path_ptr) };
+    let path_str = match c_str.to_str() {
+        Ok(str) => str,
+        Err(e) => {
+            scanner.record_invalid_path(c_str, e);
+            return;
+        }
+    };
+
+    scanner.scan(path_str);
+}
+
+/// Unix only. Starts scanning the directory open as `fd`, see `DirectoryScanner::scan_fd`, and
+/// returns the path the scan is stored under, to be released with `free_directory_map_string`.
+/// Returns null if `fd` cannot be duplicated, with the error available from `get_last_error`.
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn scan_from_fd(scanner_ptr: *const DirectoryScanner, fd: std::os::raw::c_int) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        // The pointer came from `Arc::into_raw`, so take an extra reference for the scan thread.
+        Arc::increment_strong_count(scanner_ptr);
+        Arc::from_raw(scanner_ptr)
+    };
+
+    if fd < 0 {
+        scanner.record_error(Path::new("/dev/fd"), &io::Error::from(io::ErrorKind::InvalidInput));
+        return std::ptr::null_mut();
+    }
+    // Safety: the caller keeps `fd` open for the duration of the call, and it is only duplicated.
+    match scanner.scan_fd(unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) }) {
+        Ok(path) => CString::new(display_path(&path)).unwrap().into_raw(),
+        Err(e) => {
+            scanner.record_error(Path::new(&format!("/dev/fd/{fd}")), &e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Keeps the scanned root at `path_ptr` up to date as the file system changes, see
+/// `DirectoryScanner::watch`. Returns `false` if it cannot be watched, with the error available
+/// from `get_last_error`.
+#[no_mangle]
+pub extern "C" fn watch(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) -> bool {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        // The pointer came from `Arc::into_raw`, so take an extra reference to use it as one.
+        Arc::increment_strong_count(scanner_ptr);
+        Arc::from_raw(scanner_ptr)
+    };
+
+    let Ok(path_str) = requested_path(&scanner, path_ptr) else {
+        return false;
+    };
+
+    match scanner.watch(path_str) {
+        Ok(()) => true,
+        Err(e) => {
+            scanner.record_error(Path::new(path_str), &e);
+            false
+        }
+    }
+}
+
+/// Stops keeping `path_ptr` up to date. Returns `false` if it was not being watched.
+#[no_mangle]
+pub extern "C" fn unwatch(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) -> bool {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let Ok(path_str) = requested_path(scanner, path_ptr) else {
+        return false;
+    };
+
+    scanner.unwatch(path_str)
+}
+
+/// Same as `scan_directory_async`, but takes the path as `len` UTF-16 code units, as Windows
+/// stores it. File names that are not valid Unicode, which a UTF-8 string cannot carry, are kept
+/// as they are for reading the disk, and only shown with replacement characters in the map.
+#[cfg(windows)]
+#[no_mangle]
+pub extern "C" fn scan_directory_async_wide(scanner_ptr: *const DirectoryScanner, path_ptr: *const u16, len: usize) {
+    use std::os::windows::ffi::OsStringExt;
+
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        // The pointer came from `Arc::into_raw`, so take an extra reference for the scan thread.
+        Arc::increment_strong_count(scanner_ptr);
+        Arc::from_raw(scanner_ptr)
+    };
+
+    let wide_path = unsafe {
+        assert!(!path_ptr.is_null(), "Path pointer is null.");
+        std::slice::from_raw_parts(path_ptr, len)
+    };
+
+    scanner.scan(PathBuf::from(std::ffi::OsString::from_wide(wide_path)));
+}
+
+#[no_mangle]
+pub extern "C" fn scan_directory_with_filter(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, extensions_ptr: *const c_char, exclude: bool) {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        Arc::increment_strong_count(scanner_ptr);
+        Arc::from_raw(scanner_ptr)
+    };
+
+    let (path_c_str, extensions_c_str) = unsafe { (CStr::from_ptr(path_ptr), CStr::from_ptr(extensions_ptr)) };
+    let (path_str, extensions_str) = match (path_c_str.to_str(), extensions_c_str.to_str()) {
+        (Ok(path), Ok(extensions)) => (path, extensions),
+        (Err(e), _) => {
+            scanner.record_invalid_path(path_c_str, e);
+            return;
+        }
+        (_, Err(e)) => {
+            scanner.record_error(Path::new(&*path_c_str.to_string_lossy()), &io::Error::new(io::ErrorKind::InvalidData, e));
+            return;
+        }
+    };
+
+    let extensions: Vec<String> = extensions_str
+        .split(',')
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty())
+        .collect();
+
+    let mut options = ScanOptions::default();
+    if exclude {
+        options.exclude_extensions = extensions;
+    } else {
+        options.include_extensions = extensions;
+    }
+
+    scanner.scan_with_options(path_str, options);
+}
+
+#[no_mangle]
+pub extern "C" fn rescan_directory_async(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        Arc::increment_strong_count(scanner_ptr);
+        Arc::from_raw(scanner_ptr)
+    };
+
+    let c_str = unsafe { CStr::from_ptr(path_ptr) };
+    let path_str = match c_str.to_str() {
+        Ok(str) => str,
+        Err(e) => {
+            scanner.record_invalid_path(c_str, e);
+            return;
+        }
+    };
+
+    scanner.rescan(path_str);
+}
+
+#[no_mangle]
+pub extern "C" fn get_directory_map(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, depth: i32) -> *mut c_char {
+    let json = match requested_entry(scanner_ptr, path_ptr, depth) {
+        Ok(hierarchy) => to_json(&Versioned::new(&hierarchy)),
+        Err(json) => json,
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Same as `get_directory_map`, with every node also carrying its size formatted for display.
+#[no_mangle]
+pub extern "C" fn get_directory_map_pretty(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, depth: i32) -> *mut c_char {
+    let json = match requested_entry(scanner_ptr, path_ptr, depth) {
+        Ok(hierarchy) => match serde_json::to_value(Versioned::new(&hierarchy)) {
+            Ok(mut value) => {
+                add_human_sizes(&mut value);
+                to_json(&value)
+            }
+            Err(e) => error_json(&format!("Serialization error: {e}")),
+        },
+        Err(json) => json,
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Reads a path passed in by the host. One that is not valid UTF-8 is recorded as an
+/// `InvalidUtf8Path` error, and the error JSON to hand back instead is returned.
+fn requested_path<'a>(scanner: &DirectoryScanner, path_ptr: *const c_char) -> Result<&'a str, String> {
+    let path = unsafe {
+        assert!(!path_ptr.is_null(), "Path pointer is null.");
+        CStr::from_ptr(path_ptr)
+    };
+    path.to_str().map_err(|e| {
+        scanner.record_invalid_path(path, e);
+        error_json("Invalid UTF-8 in path")
+    })
+}
+
+/// Copies the entry asked for by `get_directory_map`, or returns the error JSON to hand back instead.
+fn requested_entry(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, depth: i32) -> Result<FolderHierarchy, String> {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = requested_path(scanner, path_ptr)?.replace("\\", "/");
+
+    // Attempt to acquire the lock.
+    let guard = match scanner.directory_map.read() {
+        Ok(g) => g,
+        Err(e) => {
+            // Handle lock poisoning or other errors.
+            error!("Failed to lock directory_map: {}", e);
+            return Err(error_json("internal error"));
+        }
+    };
+
+    // Only copy the levels asked for, then release the lock before serializing.
+    // `depth` counts the levels included below the entry's children, negative meaning all of them.
+    let entry = find_entry(&guard, &path_str).map(|entry| match usize::try_from(depth) {
+        Ok(depth) => entry.truncated(depth + 1),
+        Err(_) => entry.clone(),
+    });
+    drop(guard);
+
+    entry.ok_or_else(|| error_json("Root folder not found"))
+}
+
+/// Adds a `value_human` next to the `value` of every node in a serialized hierarchy.
+fn add_human_sizes(node: &mut serde_json::Value) {
+    let Some(node) = node.as_object_mut() else {
+        return;
+    };
+    if let Some(value) = node.get("value").and_then(serde_json::Value::as_u64) {
+        node.insert("value_human".to_owned(), human_size(value).into());
+    }
+    if let Some(serde_json::Value::Array(children)) = node.get_mut("children") {
+        children.iter_mut().for_each(add_human_sizes);
+    }
+}
+
+/// Returns a JSON array with the `n` largest files scanned under `path_ptr`, largest first.
+#[no_mangle]
+pub extern "C" fn get_largest_files(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, n: usize) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.largest_files(path_str, n) {
+        Some(files) => to_json(&files),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns a JSON array with the files scanned under `path_ptr` whose name contains `query_ptr`,
+/// ignoring case, largest first. `limit` caps the number of files returned, 0 for no limit.
+#[no_mangle]
+pub extern "C" fn search_files(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, query_ptr: *const c_char, limit: usize) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+    let query = unsafe {
+        assert!(!query_ptr.is_null(), "Query pointer is null.");
+        match CStr::from_ptr(query_ptr).to_str() {
+            Ok(query) => query,
+            Err(_) => return CString::new(error_json("Invalid UTF-8 in query")).unwrap().into_raw(),
+        }
+    };
+
+    let limit = if limit == 0 { usize::MAX } else { limit };
+    let json = match scanner.search_files(path_str, query, limit) {
+        Some(files) => to_json(&files),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns the hierarchy under `path_ptr` as JSON with, in each directory, the children totalling less than
+/// `min_bytes` folded into a single `(other)` entry.
+#[no_mangle]
+pub extern "C" fn prune_below(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, min_bytes: u64) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.pruned_below(path_str, min_bytes) {
+        Some(hierarchy) => to_json(&hierarchy),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns the hierarchy under `path_ptr` as JSON with chains of directories holding a single directory merged.
+#[no_mangle]
+pub extern "C" fn get_directory_map_collapsed(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.collapsed_chains(path_str) {
+        Some(hierarchy) => to_json(&hierarchy),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns the hierarchy under `path_ptr` as JSON with children in the `SortOrder` given by `order`.
+#[no_mangle]
+pub extern "C" fn get_directory_map_sorted(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, order: i32) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.sorted(path_str, SortOrder::from_i32(order)) {
+        Some(hierarchy) => to_json(&hierarchy),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns a JSON object with the totals of the scan under `path_ptr`, without serializing the hierarchy.
+#[no_mangle]
+pub extern "C" fn get_scan_summary(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.scan_summary(path_str) {
+        Some(summary) => to_json(&summary),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns the approximate bytes of memory the map of `path_ptr` takes up, or 0 if it has not been
+/// scanned or is not valid UTF-8.
+#[no_mangle]
+pub extern "C" fn get_map_memory_estimate(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) -> u64 {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let Ok(path_str) = requested_path(scanner, path_ptr) else {
+        return 0;
+    };
+
+    scanner.memory_estimate(path_str).unwrap_or_default()
+}
+
+/// Returns a JSON object mapping each lowercased extension of the files under `path_ptr` to their count and total size.
+#[no_mangle]
+pub extern "C" fn get_extension_histogram(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.extension_histogram(path_str) {
+        Some(histogram) => to_json(&histogram),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns the total size of the files under `path_ptr` by uid, for scans with `read_owners`.
+#[no_mangle]
+pub extern "C" fn get_usage_by_user(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.usage_by_user(path_str) {
+        Some(usage) => to_json(&usage),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns a JSON array of the groups of files scanned under `path_ptr` with the same size and
+/// content hash, each with its `hash`, `value` and `paths`. Only scans with `hash_files` find any.
+#[no_mangle]
+pub extern "C" fn get_duplicate_files(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.duplicate_files(path_str) {
+        Some(duplicates) => to_json(&duplicates),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns the path of the first file found under `path_ptr` larger than `min_bytes`, to be
+/// released with `free_directory_map_string`, or null if there is none or the path is not valid UTF-8.
+#[no_mangle]
+pub extern "C" fn find_first_larger_than(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, min_bytes: u64) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let Ok(path_str) = requested_path(scanner, path_ptr) else {
+        return std::ptr::null_mut();
+    };
+
+    match scanner.find_first(path_str, move |file| file.value > min_bytes) {
+        Ok(Some(file)) => CString::new(file.path).unwrap().into_raw(),
+        Ok(None) | Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Returns a JSON array with the paths of the directories under `path_ptr` that contain no files.
+#[no_mangle]
+pub extern "C" fn get_empty_directories(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, recursive: bool) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.empty_directories(path_str, recursive) {
+        Some(paths) => to_json(&paths),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Returns the hierarchy under `path_ptr` as a flat JSON array of `{id, parent_id, name, value}` rows.
+#[no_mangle]
+pub extern "C" fn get_directory_map_flat(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let path_str = match requested_path(scanner, path_ptr) {
+        Ok(path) => path,
+        Err(json) => return CString::new(json).unwrap().into_raw(),
+    };
+
+    let json = match scanner.flattened(path_str) {
+        Some(rows) => to_json(&rows),
+        None => error_json("Root folder not found"),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+#[no_mangle]
+pub extern "C" fn get_scan_diff(old_json_ptr: *const c_char, new_json_ptr: *const c_char) -> *mut c_char {
+    let (old_json, new_json) = unsafe {
+        assert!(!old_json_ptr.is_null() && !new_json_ptr.is_null(), "JSON pointer is null.");
+        (CStr::from_ptr(old_json_ptr), CStr::from_ptr(new_json_ptr))
+    };
+
+    let parse = |json: &CStr| -> Result<FolderHierarchy, String> {
+        let json = json.to_str().map_err(|e| format!("Invalid UTF-8 in JSON: {e}"))?;
+        hierarchy_from_json(json.as_bytes()).map_err(|e| format!("Invalid directory map: {e}"))
+    };
+    let json = match (parse(old_json), parse(new_json)) {
+        (Ok(old), Ok(new)) => to_json(&diff_scans(&old, &new)),
+        (Err(message), _) | (_, Err(message)) => error_json(&message),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Frees a string returned by `get_version`, `get_build_info`, `scan_from_fd`, `get_directory_map` and its
+/// `_pretty`, `_collapsed`, `_sorted` and `_flat` variants, `get_largest_files`, `search_files`, `prune_below`,
+/// `get_scan_summary`, `get_extension_histogram`, `get_usage_by_user`, `get_duplicate_files`,
+/// `find_first_larger_than`, `get_empty_directories`, `get_scan_diff`, `import_binary`, `list_scanned_roots`,
+/// `get_scan_throughput`, `get_last_error`, `get_inaccessible_paths` or `get_scan_errors`.
+/// Each returned pointer must be passed here exactly once; null pointers are ignored.
+#[no_mangle]
+pub extern "C" fn free_directory_map_string(string_ptr: *mut c_char) {
+    if !string_ptr.is_null() {
+        // The pointer came from `CString::into_raw`, so taking it back frees it at the end of this scope.
+        unsafe { drop(CString::from_raw(string_ptr)) };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_directory_map_streaming(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, out_path_ptr: *const c_char) -> bool {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let (path_str, out_path_str) = unsafe {
+        assert!(!path_ptr.is_null() && !out_path_ptr.is_null(), "Path pointer is null.");
+        match (CStr::from_ptr(path_ptr).to_str(), CStr::from_ptr(out_path_ptr).to_str()) {
+            (Ok(path), Ok(out_path)) => (path, out_path),
+            _ => {
+                warn!("Invalid string passed to get_directory_map_streaming");
+                return false;
+            }
+        }
+    };
+
+    let result = std::fs::File::create(out_path_str)
+        .and_then(|file| scanner.write_directory_map(path_str, std::io::BufWriter::new(file)));
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            scanner.record_error(Path::new(out_path_str), &e);
+            false
+        }
+    }
+}
+
+/// Writes every file scanned under `path_ptr` as a CSV row to `out_path_ptr`.
+#[no_mangle]
+pub extern "C" fn export_csv(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, out_path_ptr: *const c_char) -> bool {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let (path_str, out_path_str) = unsafe {
+        assert!(!path_ptr.is_null() && !out_path_ptr.is_null(), "Path pointer is null.");
+        match (CStr::from_ptr(path_ptr).to_str(), CStr::from_ptr(out_path_ptr).to_str()) {
+            (Ok(path), Ok(out_path)) => (path, out_path),
+            _ => {
+                warn!("Invalid string passed to export_csv");
+                return false;
+            }
+        }
+    };
+
+    let result = std::fs::File::create(out_path_str)
+        .and_then(|file| scanner.export_csv(path_str, std::io::BufWriter::new(file)));
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            scanner.record_error(Path::new(out_path_str), &e);
+            false
+        }
+    }
+}
+
+/// Writes the hierarchy scanned under `path_ptr` to `out_path_ptr` in binary.
+#[no_mangle]
+pub extern "C" fn export_binary(scanner_ptr: *const DirectoryScanner, path_ptr: *const c_char, out_path_ptr: *const c_char) -> bool {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let (path_str, out_path_str) = unsafe {
+        assert!(!path_ptr.is_null() && !out_path_ptr.is_null(), "Path pointer is null.");
+        match (CStr::from_ptr(path_ptr).to_str(), CStr::from_ptr(out_path_ptr).to_str()) {
+            (Ok(path), Ok(out_path)) => (path, out_path),
+            _ => {
+                warn!("Invalid string passed to export_binary");
+                return false;
+            }
+        }
+    };
+
+    let result = std::fs::File::create(out_path_str)
+        .and_then(|file| scanner.export_binary(path_str, std::io::BufWriter::new(file)));
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            scanner.record_error(Path::new(out_path_str), &e);
+            false
+        }
+    }
+}
+
+/// Reads a file written by `export_binary` back, returning the hierarchy as JSON.
+#[no_mangle]
+pub extern "C" fn import_binary(out_path_ptr: *const c_char) -> *mut c_char {
+    let out_path_str = unsafe {
+        assert!(!out_path_ptr.is_null(), "Path pointer is null.");
+        match CStr::from_ptr(out_path_ptr).to_str() {
+            Ok(out_path) => out_path,
+            Err(_) => return CString::new(error_json("Invalid UTF-8 in path")).unwrap().into_raw(),
+        }
+    };
+
+    let result = std::fs::File::open(out_path_str).and_then(|file| FolderHierarchy::read_binary(std::io::BufReader::new(file)));
+    let json = match result {
+        Ok(hierarchy) => to_json(&Versioned::new(&hierarchy)),
+        Err(e) => error_json(&e.to_string()),
+    };
+
+    CString::new(json).unwrap().into_raw()
+}
+
+#[no_mangle]
+pub extern "C" fn list_scanned_roots(scanner_ptr: *const DirectoryScanner) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let json = to_json(&scanner.scanned_roots());
+    CString::new(json).unwrap().into_raw()
+}
+
+#[no_mangle]
+pub extern "C" fn get_scan_progress(scanner_ptr: *const DirectoryScanner, files_ptr: *mut u64, directories_ptr: *mut u64) {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let (files, directories) = scanner.scan_progress();
+    unsafe {
+        if !files_ptr.is_null() {
+            *files_ptr = files;
+        }
+        if !directories_ptr.is_null() {
+            *directories_ptr = directories;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn get_scan_progress_percent(scanner_ptr: *const DirectoryScanner) -> f32 {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    scanner.scan_progress_percent()
+}
+
+/// Returns a JSON object with the `entries_per_sec`, `bytes_per_sec` and `elapsed_ms` of the current scan.
+#[no_mangle]
+pub extern "C" fn get_scan_throughput(scanner_ptr: *const DirectoryScanner) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    CString::new(to_json(&scanner.scan_throughput())).unwrap().into_raw()
+}
+
+#[no_mangle]
+pub extern "C" fn get_scan_state(scanner_ptr: *const DirectoryScanner) -> i32 {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    scanner.scan_state() as i32
+}
+
+#[no_mangle]
+pub extern "C" fn wait_for_scan_completion(scanner_ptr: *const DirectoryScanner, timeout_ms: u64) -> bool {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    scanner.wait_for_completion(Duration::from_millis(timeout_ms))
+}
+
+#[no_mangle]
+pub extern "C" fn get_last_error(scanner_ptr: *const DirectoryScanner) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    let message = scanner.last_error().unwrap_or_default();
+    CString::new(message).unwrap_or_default().into_raw()
+}
+
+/// Returns the `ScanErrorKind` of the most recent error hit by the current scan: 0 if there was none,
+/// 1 permission denied, 2 not found, 3 an invalid UTF-8 path passed in, 4 any other I/O error,
+/// 5 an interrupted scan or 6 an entry whose name is not valid UTF-8.
+#[no_mangle]
+pub extern "C" fn get_last_error_code(scanner_ptr: *const DirectoryScanner) -> i32 {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    scanner.last_error_kind().map_or(0, |kind| kind as i32)
+}
+
+#[no_mangle]
+pub extern "C" fn get_inaccessible_paths(scanner_ptr: *const DirectoryScanner) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    CString::new(to_json(&scanner.inaccessible_paths())).unwrap().into_raw()
+}
+
+/// Returns a JSON array of `{"path", "message", "kind", "raw_path"}` objects for every error hit by the current scan.
+#[no_mangle]
+pub extern "C" fn get_scan_errors(scanner_ptr: *const DirectoryScanner) -> *mut c_char {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    CString::new(to_json(&scanner.scan_errors())).unwrap().into_raw()
+}
+
+#[no_mangle]
+pub extern "C" fn pause_scanning(scanner_ptr: *const DirectoryScanner) {
+    if scanner_ptr.is_null() {
+        error!("Scanner pointer is null.");
+        return;
+    }
+
+    let scanner = unsafe { &*scanner_ptr };
+    scanner.pause();
+}
+
+#[no_mangle]
+pub extern "C" fn resume_scanning(scanner_ptr: *const DirectoryScanner) {
+    if scanner_ptr.is_null() {
+        error!("Scanner pointer is null.");
+        return;
+    }
+
+    let scanner = unsafe { &*scanner_ptr };
+    scanner.resume();
+}
+
+#[no_mangle]
+pub extern "C" fn set_scan_callback(scanner_ptr: *const DirectoryScanner, callback: Option<ScanCallback>) {
+    if scanner_ptr.is_null() {
+        error!("Scanner pointer is null.");
+        return;
+    }
+
+    let scanner = unsafe { &*scanner_ptr };
+    scanner.set_scan_callback(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn set_scan_threads(scanner_ptr: *const DirectoryScanner, threads: usize) -> bool {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    scanner.set_scan_threads(threads)
+}
+
+#[no_mangle]
+pub extern "C" fn reset_scanner(scanner_ptr: *const DirectoryScanner) {
+    let scanner = unsafe {
+        assert!(!scanner_ptr.is_null(), "Scanner pointer is null.");
+        &*scanner_ptr
+    };
+
+    scanner.reset();
+}
+
+#[no_mangle]
+pub extern "C" fn stop_scanning(scanner_ptr: *const DirectoryScanner) {
+    if scanner_ptr.is_null() {
+        error!("Scanner pointer is null.");
+        return;
+    }
+
+    let scanner = unsafe { &*scanner_ptr };
+    scanner.request_stop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use std::ffi::{CString, CStr};
+    use std::thread;
+    use tokio::fs;
+    use std::time::Duration;
+
+    async fn create_test_directory_structure(base_dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(base_dir.join("subfolder1/subsubfolder1")).await?;
+        fs::create_dir_all(base_dir.join("subfolder2")).await?;
+        fs::create_dir_all(base_dir.join("subfolder2/subsubfolder2a")).await?;
+        fs::create_dir_all(base_dir.join("subfolder2/subsubfolder2b")).await?;
+    
+        let mut file1 = File::create(base_dir.join("subfolder1/test_file1.txt"))?;
+        let mut file2 = File::create(base_dir.join("subfolder1/subsubfolder1/test_file2.txt"))?;
+        let mut file3 = File::create(base_dir.join("subfolder2/test_file3.txt"))?;
+    
+        writeln!(file1, "Hello, world!")?;
+        writeln!(file2, "Hello, Rust!")?;
+        writeln!(file3, "Hello, Testing!")?;
+    
+        Ok(())
+    }
+    
+    #[tokio::test]
+    async fn test_roots_that_cannot_be_read_are_not_kept() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        let missing = temp_dir.path().join("missing");
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        scanner.scan(&missing);
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        assert_eq!(scanner.scan_state(), ScanState::Error);
+        assert_eq!(scanner.last_error_kind(), Some(ScanErrorKind::NotFound));
+        assert!(scanner.directory_map_for(&missing).is_none());
+        assert_eq!(scanner.scanned_roots(), [temp_dir.path().to_string_lossy()]);
+    }
+
+    #[tokio::test]
+    async fn test_scan_async_returns_the_tree() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner = DirectoryScanner::open();
+        let missing = temp_dir.path().join("missing");
+        let (hierarchy, missing) = tokio::join!(scanner.scan_async(temp_dir.path(), ScanOptions::default()), scanner.scan_async(&missing, ScanOptions::default()));
+        let hierarchy = hierarchy.unwrap();
+        assert_eq!((hierarchy.value, hierarchy.file_count, hierarchy.dir_count), (14 + 13 + 16, 3, 5));
+        let mut names: Vec<_> = hierarchy.iter().map(|node| node.name.as_str()).collect();
+        names.sort_unstable();
+        let root_name = temp_dir.path().file_name().unwrap().to_str().unwrap();
+        let mut expected = [root_name, "subfolder1", "subsubfolder1", "test_file1.txt", "test_file2.txt", "subfolder2", "subsubfolder2a", "subsubfolder2b", "test_file3.txt"];
+        expected.sort_unstable();
+        assert_eq!(names, expected);
+        let subfolder1 = hierarchy.children.iter().find(|child| child.name == "subfolder1").unwrap();
+        assert_eq!((subfolder1.value, subfolder1.file_count, subfolder1.dir_count), (14 + 13, 2, 1));
+        assert_eq!(missing.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        // Kept apart from the scans the scanner tracks.
+        assert!(scanner.directory_map_for(temp_dir.path()).is_none());
+        assert!(scanner.scan_errors().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scan_and_get_directory_map() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let test_path = temp_dir.path();
+
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+
+        let test_path_c = CString::new(test_path.to_str().unwrap()).expect("CString::new failed");
+
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+
+        thread::sleep(Duration::from_millis(10)); // Adjust as necessary.
+
+        let result_ptr = get_directory_map(scanner_ptr, test_path_c.as_ptr(), 0);
+        assert!(!result_ptr.is_null(), "get_directory_map returned a null pointer");
+
+        let result_cstr = unsafe { CStr::from_ptr(result_ptr) };
+        let result_str = result_cstr.to_str().unwrap();
+
+        let directory_map: FolderHierarchy = serde_json::from_str(result_str).unwrap();
+
+        assert!(!directory_map.children.is_empty(), "The directory map childrens should not be empty");
+        free_directory_map_string(result_ptr);
+        
+        for folder in &directory_map.children {
+            assert!(
+                folder.children.iter().all(|child| child.children.is_empty()),
+                "All children of the folder should have an empty children array"
+            );
+        }
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_human_size_picks_binary_units() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(1024 * 1024 - 1), "1.0 MiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024 / 2), "2.5 GiB");
+        assert_eq!(human_size(u64::MAX), "16.0 EiB");
+    }
+
+    #[test]
+    fn test_directory_maps_carry_the_schema_version() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::create_dir(temp_dir.path().join("docs")).unwrap();
+        std::fs::write(temp_dir.path().join("docs/notes.txt"), b"notes").unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        for get in [get_directory_map, get_directory_map_pretty] {
+            let result_ptr = get(scanner_ptr, test_path_c.as_ptr(), -1);
+            let json = unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap().to_owned();
+            free_directory_map_string(result_ptr);
+            let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(value["schema_version"], SCHEMA_VERSION);
+            assert_eq!(value["value"], 5);
+            // Only the top level carries it, and the nodes still parse as they did.
+            assert!(value["children"][0].get("schema_version").is_none());
+            assert_eq!(serde_json::from_str::<FolderHierarchy>(&json).unwrap().children[0].name, "docs");
+        }
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_pretty_directory_map_adds_human_sizes() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir(temp_dir.path().join("docs")).await.unwrap();
+        fs::write(temp_dir.path().join("docs/notes.txt"), vec![b'x'; 1536]).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let result_ptr = get_directory_map_pretty(scanner_ptr, test_path_c.as_ptr(), -1);
+        let json: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(json["value"], 1536);
+        assert_eq!(json["value_human"], "1.5 KiB");
+        assert_eq!(json["children"][0]["children"][0]["value_human"], "1.5 KiB");
+
+        let missing_c = CString::new(temp_dir.path().join("missing").to_str().unwrap()).unwrap();
+        let result_ptr = get_directory_map_pretty(scanner_ptr, missing_c.as_ptr(), -1);
+        assert_eq!(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap(), r#"{"error":"Root folder not found"}"#);
+        free_directory_map_string(result_ptr);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_freeing_a_scanner_stops_its_scans() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::create_dir(temp_dir.path().join("docs")).unwrap();
+        std::fs::write(temp_dir.path().join("docs/notes.txt"), b"notes").unwrap();
+
+        // Paused, so that the scan is still running when the scanner is freed.
+        let scanner_ptr = create_directory_scanner();
+        pause_scanning(scanner_ptr);
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        let scanner = unsafe {
+            Arc::increment_strong_count(scanner_ptr);
+            Arc::from_raw(scanner_ptr)
+        };
+        let weak = Arc::downgrade(&scanner);
+        drop(scanner);
+        assert!(weak.upgrade().is_some(), "The scan holds on to the scanner");
+
+        // Once freed, nothing is left holding the scanner.
+        free_directory_scanner(scanner_ptr);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_running_scans() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::write(temp_dir.path().join("file.txt"), b"data").unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.pause();
+        scanner.scan(temp_dir.path());
+        scanner.rescan(temp_dir.path());
+        // Paused scans are woken up to notice the stop.
+        assert!(scanner.shutdown(Duration::from_secs(10)));
+        assert!(scanner.scan_tasks.lock().unwrap().is_empty());
+        assert_ne!(scanner.scan_state(), ScanState::Scanning);
+    }
+
+    /// Reads from `inner` after blocking the thread for `delay`, as a networked file system stuck
+    /// in a system call would. `blocked` is set once a read is under way.
+    struct BlockingFileSystem {
+        inner: MemoryFileSystem,
+        delay: Duration,
+        blocked: AtomicBool,
+    }
+
+    impl FileSystem for BlockingFileSystem {
+        fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<BoxStream<'a, io::Result<DirectoryEntry>>>> {
+            self.blocked.store(true, Ordering::Release);
+            thread::sleep(self.delay);
+            self.inner.read_dir(path)
+        }
+
+        fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<EntryMetadata>> {
+            self.inner.metadata(path)
+        }
+
+        fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+            self.inner.canonicalize(path)
+        }
+
+        fn read_link<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+            self.inner.read_link(path)
+        }
+    }
+
+    #[test]
+    fn test_shutdown_gives_up_on_scans_stuck_past_the_timeout() {
+        let root = PathBuf::from("/virtual");
+        let mut inner = MemoryFileSystem::new();
+        inner.add_file(root.join("file.bin"), 4);
+
+        let file_system = Arc::new(BlockingFileSystem { inner, delay: Duration::from_millis(500), blocked: AtomicBool::new(false) });
+        let scanner = DirectoryScanner::open_with_file_system(Arc::clone(&file_system) as Arc<dyn FileSystem>);
+        scanner.scan(&root);
+        while !file_system.blocked.load(Ordering::Acquire) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let started = Instant::now();
+        assert!(!scanner.shutdown(Duration::from_millis(50)));
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_scan_completion() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        let scanner_ptr = create_directory_scanner();
+        assert!(wait_for_scan_completion(scanner_ptr, 0), "Nothing to wait for before the first scan");
+
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        assert_eq!(get_scan_state(scanner_ptr), ScanState::Completed as i32);
+
+        let directory_map = unsafe { &*scanner_ptr }.directory_map();
+        assert_eq!((directory_map.file_count, directory_map.dir_count), (3, 5));
+        assert_eq!(directory_map.value, 43);
+
+        unsafe { &*scanner_ptr }.pause();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(!wait_for_scan_completion(scanner_ptr, 50), "A paused scan cannot complete");
+        resume_scanning(scanner_ptr);
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_safe_scan_api() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(temp_dir.path());
+
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        let directory_map = scanner.directory_map();
+        assert_eq!(directory_map.path, temp_dir.path().to_string_lossy());
+        assert_eq!(directory_map.children.len(), 2);
+        assert_eq!(directory_map.value, 14 + 13 + 16);
+    }
+
+    #[test]
+    fn test_isolated_scans_run_concurrently_outside_the_map() {
+        let temp_dirs: Vec<_> = (1..=3u8)
+            .map(|n| {
+                let temp_dir = tempdir().expect("Failed to create a temporary directory");
+                std::fs::create_dir(temp_dir.path().join("nested")).unwrap();
+                std::fs::write(temp_dir.path().join("nested/data.bin"), vec![n; usize::from(n) * 100]).unwrap();
+                temp_dir
+            })
+            .collect();
+
+        let scanner = DirectoryScanner::open();
+        let hierarchies: Vec<FolderHierarchy> = thread::scope(|scope| {
+            let scans: Vec<_> = temp_dirs.iter().map(|temp_dir| scope.spawn(|| scanner.scan_isolated(temp_dir.path()))).collect();
+            scans.into_iter().map(|scan| scan.join().unwrap().unwrap()).collect()
+        });
+
+        for ((n, temp_dir), hierarchy) in (1..=3).zip(&temp_dirs).zip(&hierarchies) {
+            assert_eq!(hierarchy.path, temp_dir.path().to_string_lossy());
+            assert_eq!(hierarchy.value, n * 100);
+            assert_eq!(hierarchy.children[0].children[0].path, temp_dir.path().join("nested/data.bin").to_string_lossy());
+        }
+        assert!(scanner.scanned_roots().is_empty());
+        assert_eq!(scanner.scan_progress(), (0, 0));
+        assert_eq!(scanner.scan_state(), ScanState::Idle);
+
+        assert_eq!(scanner.scan_isolated(temp_dirs[0].path().join("missing")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(scanner.scan_errors().is_empty());
+    }
+
+    #[test]
+    fn test_streamed_directories_rebuild_the_scanned_tree() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..5 {
+            std::fs::create_dir_all(temp_dir.path().join(format!("folder{i}/nested"))).unwrap();
+            std::fs::write(temp_dir.path().join(format!("folder{i}/nested/file.txt")), vec![0; i * 10]).unwrap();
+            std::fs::write(temp_dir.path().join(format!("folder{i}/top.txt")), b"top").unwrap();
+        }
+
+        let scanner = DirectoryScanner::open();
+        let streamed: Vec<FolderHierarchy> = scanner.scan_streaming(temp_dir.path()).iter().collect();
+        assert_eq!(scanner.scan_state(), ScanState::Completed);
+        assert_eq!(streamed.len(), 1 + 5 * 2);
+
+        // Every directory arrives after the ones below it.
+        let mut by_path = HashMap::new();
+        for directory in streamed {
+            for child in &directory.children {
+                assert!(child.children.is_empty());
+                assert!(child.entry_type != EntryKind::Directory || by_path.contains_key(&child.path), "{} streamed before {}", directory.path, child.path);
+            }
+            by_path.insert(directory.path.clone(), directory);
+        }
+
+        fn rebuild(path: &str, by_path: &HashMap<String, FolderHierarchy>) -> FolderHierarchy {
+            let mut directory = by_path[path].clone();
+            for child in &mut directory.children {
+                if child.entry_type == EntryKind::Directory {
+                    *child = FolderHierarchy { percent_of_parent: child.percent_of_parent, ..rebuild(&child.path, by_path) };
+                }
+            }
+            directory
+        }
+        let rebuilt = rebuild(&temp_dir.path().to_string_lossy(), &by_path);
+        assert_eq!(rebuilt, scanner.directory_map());
+        assert_eq!(rebuilt.value, (10 + 20 + 30 + 40) + 5 * 3);
+    }
+
+    #[tokio::test]
+    async fn test_file_and_directory_counts() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        std::fs::write(temp_dir.path().join("top_level.txt"), "top").unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let result_ptr = get_directory_map(scanner_ptr, test_path_c.as_ptr(), 0);
+        let directory_map: FolderHierarchy = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        assert_eq!(directory_map.file_count, 4);
+        assert_eq!(directory_map.dir_count, 5);
+
+        let subfolder1 = directory_map.children.iter().find(|c| c.name == "subfolder1").unwrap();
+        assert_eq!((subfolder1.file_count, subfolder1.dir_count), (2, 1));
+        let file = directory_map.children.iter().find(|c| c.name == "top_level.txt").unwrap();
+        assert_eq!((file.file_count, file.dir_count), (1, 0));
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_get_directory_map_depths() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir_all(temp_dir.path().join("a/b/c")).await.unwrap();
+        std::fs::write(temp_dir.path().join("a/b/c/file.txt"), [0u8; 42]).unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let fetch = |depth: i32| -> FolderHierarchy {
+            let result_ptr = get_directory_map(scanner_ptr, test_path_c.as_ptr(), depth);
+            serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap()
+        };
+        let levels = |mut node: &FolderHierarchy| {
+            let mut levels = 0;
+            while let Some(child) = node.children.first() {
+                assert_eq!(child.value, 42, "truncated nodes should keep their full size");
+                node = child;
+                levels += 1;
+            }
+            levels
+        };
+
+        assert_eq!(levels(&fetch(0)), 1);
+        assert_eq!(levels(&fetch(1)), 2);
+        assert_eq!(levels(&fetch(2)), 3);
+        assert_eq!(levels(&fetch(-1)), 4);
+        assert_eq!(fetch(-1).value, 42);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_truncated_directories_tell_whether_they_have_children() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::create_dir_all(temp_dir.path().join("full/inner")).unwrap();
+        std::fs::create_dir(temp_dir.path().join("empty")).unwrap();
+        std::fs::write(temp_dir.path().join("file.txt"), b"data").unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        let result_ptr = get_directory_map(scanner_ptr, test_path_c.as_ptr(), 0);
+        let hierarchy: FolderHierarchy = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+
+        let child = |name: &str| hierarchy.children.iter().find(|c| c.name == name).unwrap();
+        assert!(hierarchy.has_children);
+        assert!(child("full").has_children);
+        assert!(child("full").children.is_empty());
+        assert!(!child("empty").has_children);
+        assert!(!child("file.txt").has_children);
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_get_directory_map_streaming_writes_full_map() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let out_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let out_path = out_dir.path().join("map.json");
+        let out_path_c = CString::new(out_path.to_str().unwrap()).unwrap();
+        assert!(get_directory_map_streaming(scanner_ptr, test_path_c.as_ptr(), out_path_c.as_ptr()));
+
+        let streamed: FolderHierarchy = serde_json::from_str(&std::fs::read_to_string(&out_path).unwrap()).unwrap();
+        let scanner = unsafe { &*scanner_ptr };
+        assert_eq!(serde_json::to_string(&streamed).unwrap(), serde_json::to_string(&scanner.directory_map()).unwrap());
+
+        let missing_c = CString::new(temp_dir.path().join("missing").to_str().unwrap()).unwrap();
+        assert!(!get_directory_map_streaming(scanner_ptr, missing_c.as_ptr(), out_path_c.as_ptr()));
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_scans_share_the_scanner_runtime() {
+        let first_dir = tempdir().expect("Failed to create a temporary directory");
+        let second_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(first_dir.path()).await.unwrap();
+        create_test_directory_structure(second_dir.path()).await.unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(first_dir.path());
+        scanner.scan(second_dir.path());
+        // The state only follows the latest scan, so wait for the tasks of both.
+        assert!(scanner.wait_for_scan_tasks(Duration::from_secs(10)));
+
+        assert_eq!(scanner.directory_map_for(first_dir.path()).unwrap().value, 14 + 13 + 16);
+        assert_eq!(scanner.directory_map_for(second_dir.path()).unwrap().value, 14 + 13 + 16);
+        assert!(scanner.last_error().is_none());
+
+        // Dropping the scanner from async code must not panic either.
+        drop(scanner);
+    }
+
+    #[tokio::test]
+    async fn test_get_directory_map_of_an_interior_directory() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let interior = temp_dir.path().join("subfolder2");
+        let interior_c = CString::new(format!("{}/", interior.to_str().unwrap())).unwrap();
+        let result_ptr = get_directory_map(scanner_ptr, interior_c.as_ptr(), 0);
+        let subtree: FolderHierarchy = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(subtree.path, interior.to_string_lossy());
+        assert_eq!(subtree.value, 16);
+        assert_eq!(subtree.children.len(), 3);
+        assert!(subtree.children.iter().all(|child| child.children.is_empty()));
+
+        let deep = temp_dir.path().join("subfolder1/subsubfolder1/test_file2.txt");
+        assert_eq!(get_subtree_value(scanner_ptr, &deep), Some(13));
+        let sibling_prefix = PathBuf::from(format!("{}1", temp_dir.path().join("subfolder1").display()));
+        assert_eq!(get_subtree_value(scanner_ptr, &sibling_prefix), None);
+        assert_eq!(get_subtree_value(scanner_ptr, &temp_dir.path().join("subfolder1/missing")), None);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    fn get_subtree_value(scanner_ptr: *mut DirectoryScanner, path: &Path) -> Option<u64> {
+        let path_c = CString::new(path.to_str().unwrap()).unwrap();
+        let result_ptr = get_directory_map(scanner_ptr, path_c.as_ptr(), -1);
+        let json: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        json["value"].as_u64()
+    }
+
+    #[tokio::test]
+    async fn test_export_csv() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        fs::write(temp_dir.path().join("subfolder2/report, \"final\".txt"), vec![b'x'; 42]).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let out_dir = tempdir().unwrap();
+        let out_path = out_dir.path().join("files.csv");
+        let out_path_c = CString::new(out_path.to_str().unwrap()).unwrap();
+        assert!(export_csv(scanner_ptr, test_path_c.as_ptr(), out_path_c.as_ptr()));
+
+        let csv = std::fs::read_to_string(&out_path).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("path,name,size_bytes,modified"));
+        let rows: Vec<&str> = lines.collect();
+        assert_eq!(rows.len(), 4);
+
+        let quoted = rows.iter().find(|row| row.contains("final")).unwrap();
+        let expected_path = temp_dir.path().join("subfolder2/report, \"final\".txt");
+        let expected_prefix = format!("\"{}\",\"report, \"\"final\"\".txt\",42,", expected_path.to_str().unwrap().replace('"', "\"\""));
+        assert!(quoted.starts_with(&expected_prefix), "{quoted}");
+        let file3 = rows.iter().find(|row| row.contains("test_file3.txt")).unwrap();
+        let fields: Vec<&str> = file3.split(',').collect();
+        assert_eq!(fields[2], "16");
+        assert!(fields[3].parse::<u64>().is_ok());
+
+        let missing_c = CString::new(temp_dir.path().join("missing").to_str().unwrap()).unwrap();
+        assert!(!export_csv(scanner_ptr, missing_c.as_ptr(), out_path_c.as_ptr()));
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_binary_export_round_trips_the_hierarchy() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::create_dir_all(temp_dir.path().join("docs/nested")).unwrap();
+        std::fs::write(temp_dir.path().join("docs/a.txt"), [0u8; 20]).unwrap();
+        std::fs::write(temp_dir.path().join("docs/nested/b.txt"), [0u8; 30]).unwrap();
+        std::fs::write(temp_dir.path().join("top.txt"), [0u8; 10]).unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let scanner = unsafe { &*scanner_ptr };
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        let out_dir = tempdir().unwrap();
+        let out_path = out_dir.path().join("map.bin");
+        let out_path_c = CString::new(out_path.to_str().unwrap()).unwrap();
+        assert!(export_binary(scanner_ptr, test_path_c.as_ptr(), out_path_c.as_ptr()));
+
+        // Times are kept to the millisecond, as in JSON.
+        let json = serde_json::to_string(&scanner.directory_map_for(temp_dir.path()).unwrap()).unwrap();
+        let expected: FolderHierarchy = serde_json::from_str(&json).unwrap();
+        let imported = FolderHierarchy::read_binary(std::fs::File::open(&out_path).unwrap()).unwrap();
+        assert_eq!(imported, expected);
+        assert!((std::fs::metadata(&out_path).unwrap().len() as usize) < json.len());
+
+        let result_ptr = import_binary(out_path_c.as_ptr());
+        let value: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+        assert_eq!(serde_json::from_value::<FolderHierarchy>(value).unwrap(), expected);
+
+        // Files written with another schema version, or not by `export_binary`, are rejected.
+        let mut bytes = std::fs::read(&out_path).unwrap();
+        bytes[4..8].copy_from_slice(&(SCHEMA_VERSION - 1).to_le_bytes());
+        let error = FolderHierarchy::read_binary(bytes.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("schema version"), "{error}");
+        assert_eq!(FolderHierarchy::read_binary(json.as_bytes()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let missing_c = CString::new(temp_dir.path().join("missing").to_str().unwrap()).unwrap();
+        assert!(!export_binary(scanner_ptr, missing_c.as_ptr(), out_path_c.as_ptr()));
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_roots_are_kept_separately() {
+        let first_dir = tempdir().expect("Failed to create a temporary directory");
+        let second_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(first_dir.path()).await.unwrap();
+        std::fs::write(second_dir.path().join("only_file.txt"), [0u8; 5]).unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let first_path_c = CString::new(first_dir.path().to_str().unwrap()).unwrap();
+        let second_path_c = CString::new(second_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, first_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        scan_directory_async(scanner_ptr, second_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        for (path_c, expected_value) in [(&first_path_c, 14 + 13 + 16), (&second_path_c, 5)] {
+            let result_ptr = get_directory_map(scanner_ptr, path_c.as_ptr(), 1);
+            let result_str = unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap();
+            let directory_map: FolderHierarchy = serde_json::from_str(result_str).unwrap();
+            assert_eq!(directory_map.value, expected_value);
+        }
+
+        let roots_ptr = list_scanned_roots(scanner_ptr);
+        let roots: Vec<String> = serde_json::from_str(unsafe { CStr::from_ptr(roots_ptr) }.to_str().unwrap()).unwrap();
+        assert_eq!(roots.len(), 2);
+        assert!(roots.contains(&first_dir.path().to_string_lossy().into_owned()));
+        assert!(roots.contains(&second_dir.path().to_string_lossy().into_owned()));
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_rescan_only_rereads_modified_directories() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.scan_progress(), (3, 6));
+
+        std::fs::write(temp_dir.path().join("subfolder2/subsubfolder2a/new_file.txt"), [0u8; 100]).unwrap();
+        scanner.rescan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        // Only subsubfolder2a was modified, so it is the only directory read again.
+        assert_eq!(scanner.scan_progress(), (1, 1));
+
+        let directory_map = scanner.directory_map();
+        assert_eq!(directory_map.value, 14 + 13 + 16 + 100);
+        let subfolder2 = directory_map.children.iter().find(|c| c.name == "subfolder2").unwrap();
+        assert_eq!(subfolder2.value, 16 + 100);
+        assert!(subfolder2.modified.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rescan_keeps_the_options_of_the_scan() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        std::fs::write(temp_dir.path().join(".hidden"), [0u8; 50]).unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions::builder().max_depth(1).include_hidden(false).build());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let scanned = scanner.directory_map_for(temp_dir.path()).unwrap();
+
+        std::fs::write(temp_dir.path().join("subfolder2/subsubfolder2a/new_file.txt"), [0u8; 100]).unwrap();
+        scanner.rescan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let rescanned = scanner.directory_map_for(temp_dir.path()).unwrap();
+
+        assert_eq!(rescanned.value, 14 + 13 + 16 + 100);
+        assert_eq!(rescanned.completeness, Some(Completeness::DepthLimited));
+        let names = |root: &FolderHierarchy| root.children.iter().map(|child| child.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&rescanned), names(&scanned));
+        assert!(rescanned.children.iter().all(|child| child.children.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_rescan_keeps_the_timeout_and_snapshots_of_the_scan() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let scanned = temp_dir.path().join("scanned");
+        std::fs::create_dir(&scanned).unwrap();
+        create_test_directory_structure(&scanned).await.unwrap();
+        let snapshot_path = temp_dir.path().join("snapshot.json");
+
+        let scanner = DirectoryScanner::open();
+        let options = ScanOptions::builder().timeout(Duration::from_millis(500)).snapshots(Duration::from_secs(3600), &snapshot_path).build();
+        scanner.scan_with_options(&scanned, options);
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        // The last snapshot is taken as the rescan ends.
+        std::fs::write(scanned.join("subfolder2/subsubfolder2a/new_file.txt"), [0u8; 100]).unwrap();
+        scanner.rescan(&scanned);
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.scan_state(), ScanState::Completed);
+        assert_eq!(load_snapshot(&snapshot_path).unwrap().value, 14 + 13 + 16 + 100);
+
+        // A rescan that times out leaves the cached tree whole.
+        std::fs::write(scanned.join("subfolder2/subsubfolder2a/other_file.txt"), [0u8; 50]).unwrap();
+        scanner.pause();
+        scanner.rescan(&scanned);
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.scan_state(), ScanState::TimedOut);
+        assert!(scanner.last_error().unwrap().contains("timed out"));
+        assert_eq!(scanner.directory_map_for(&scanned).unwrap().value, 14 + 13 + 16 + 100);
+        assert_eq!(load_snapshot(&snapshot_path).unwrap().value, 14 + 13 + 16 + 100);
+    }
+
+    #[tokio::test]
+    async fn test_scan_progress_counts_and_resets() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+
+        for _ in 0..2 {
+            scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+            assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+            let (mut files, mut directories) = (0, 0);
+            get_scan_progress(scanner_ptr, &mut files, &mut directories);
+            assert_eq!(files, 3);
+            assert_eq!(directories, 6);
+        }
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    lazy_static::lazy_static! {
+        static ref SCANNED_DIRECTORIES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+
+    extern "C" fn record_scanned_directory(path_ptr: *const c_char) {
+        let path = unsafe { CStr::from_ptr(path_ptr) }.to_string_lossy().into_owned();
+        SCANNED_DIRECTORIES.lock().unwrap().push(path);
+    }
+
+    #[tokio::test]
+    async fn test_scan_callback_reports_each_directory() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        set_scan_callback(scanner_ptr, Some(record_scanned_directory));
+
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let scanned = SCANNED_DIRECTORIES.lock().unwrap().clone();
+        for folder in ["subfolder1", "subfolder1/subsubfolder1", "subfolder2/subsubfolder2b"] {
+            let expected = temp_dir.path().join(folder).to_string_lossy().into_owned();
+            assert!(scanned.contains(&expected), "{} was not reported", expected);
+        }
+        assert_eq!(scanned.last().unwrap(), &temp_dir.path().to_string_lossy());
+
+        set_scan_callback(scanner_ptr, None);
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_scan_state_transitions() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        assert_eq!(get_scan_state(scanner_ptr), ScanState::Idle as i32);
+
+        // Pausing up front keeps the scan running until we let it go.
+        pause_scanning(scanner_ptr);
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(!wait_for_scan_completion(scanner_ptr, 50), "A paused scan cannot complete");
+        assert_eq!(get_scan_state(scanner_ptr), ScanState::Scanning as i32);
+
+        resume_scanning(scanner_ptr);
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        assert_eq!(get_scan_state(scanner_ptr), ScanState::Completed as i32);
+
+        let missing_path_c = CString::new(temp_dir.path().join("missing").to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, missing_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        assert_eq!(get_scan_state(scanner_ptr), ScanState::Error as i32);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_version_matches_the_package() {
+        let version_ptr = get_version();
+        assert_eq!(unsafe { CStr::from_ptr(version_ptr) }.to_str().unwrap(), env!("CARGO_PKG_VERSION"));
+        free_directory_map_string(version_ptr);
+
+        let info_ptr = get_build_info();
+        let info: BuildInfo = serde_json::from_str(unsafe { CStr::from_ptr(info_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(info_ptr);
+        assert_eq!(info, build_info());
+        assert_eq!(info.version, VERSION);
+        assert!(info.target.starts_with(std::env::consts::ARCH), "{}", info.target);
+        assert_eq!(info.profile, if cfg!(debug_assertions) { "debug" } else { "release" });
+    }
+
+    #[tokio::test]
+    async fn test_free_directory_map_string() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        for result_ptr in [
+            get_directory_map(scanner_ptr, test_path_c.as_ptr(), -1),
+            list_scanned_roots(scanner_ptr),
+            get_last_error(scanner_ptr),
+        ] {
+            assert!(!result_ptr.is_null());
+            free_directory_map_string(result_ptr);
+        }
+        free_directory_map_string(std::ptr::null_mut());
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_get_largest_files() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        fs::write(temp_dir.path().join("big.bin"), vec![0; 100]).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let result_ptr = get_largest_files(scanner_ptr, test_path_c.as_ptr(), 3);
+        let files: Vec<FolderHierarchy> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        let sizes: Vec<u64> = files.iter().map(|f| f.value).collect();
+        assert_eq!(sizes, [100, 16, 14]);
+        assert_eq!(files[0].path, temp_dir.path().join("big.bin").to_string_lossy());
+
+        let result_ptr = get_largest_files(scanner_ptr, test_path_c.as_ptr(), 10);
+        let files: Vec<FolderHierarchy> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(files.len(), 4);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_search_files_matches_names_ignoring_case() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::create_dir_all(temp_dir.path().join("reports/archive")).unwrap();
+        std::fs::write(temp_dir.path().join("reports/Q1-Report.pdf"), vec![0; 30]).unwrap();
+        std::fs::write(temp_dir.path().join("reports/archive/q4-report.pdf"), vec![0; 50]).unwrap();
+        std::fs::write(temp_dir.path().join("reports/summary.txt"), vec![0; 80]).unwrap();
+        std::fs::write(temp_dir.path().join("report-notes.txt"), vec![0; 10]).unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let search = |query: &str, limit: usize| -> Vec<String> {
+            let query_c = CString::new(query).unwrap();
+            let result_ptr = search_files(scanner_ptr, test_path_c.as_ptr(), query_c.as_ptr(), limit);
+            let files: Vec<FolderHierarchy> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+            free_directory_map_string(result_ptr);
+            files.into_iter().map(|file| file.name).collect()
+        };
+        // Directories are not matched, and the largest files come first.
+        assert_eq!(search("REPORT", 0), ["q4-report.pdf", "Q1-Report.pdf", "report-notes.txt"]);
+        assert_eq!(search("report", 2), ["q4-report.pdf", "Q1-Report.pdf"]);
+        assert!(search("missing", 0).is_empty());
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_prune_below_folds_small_subtrees() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        fs::write(temp_dir.path().join("big.bin"), vec![0; 100]).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let result_ptr = prune_below(scanner_ptr, test_path_c.as_ptr(), 20);
+        let pruned: FolderHierarchy = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(pruned.value, 143);
+
+        let names: Vec<&str> = pruned.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names.len(), 3);
+        assert!(names.contains(&"big.bin") && names.contains(&"subfolder1"));
+        let other = pruned.children.iter().find(|c| c.name == "(other)").unwrap();
+        assert_eq!((other.value, other.file_count, other.dir_count), (16, 1, 3));
+        assert!(other.children.is_empty());
+        assert_eq!(pruned.children.iter().map(|c| c.value).sum::<u64>(), pruned.value);
+
+        let subfolder1 = pruned.children.iter().find(|c| c.name == "subfolder1").unwrap();
+        assert_eq!(subfolder1.children.len(), 1);
+        assert_eq!((subfolder1.children[0].value, subfolder1.children[0].file_count), (27, 2));
+
+        let scanner = unsafe { &*scanner_ptr };
+        assert_eq!(scanner.pruned_below(temp_dir.path(), 0), scanner.subtree(temp_dir.path()));
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_single_child_chains_are_collapsed() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let deepest = temp_dir.path().join("com/example/app/model");
+        std::fs::create_dir_all(&deepest).unwrap();
+        std::fs::write(deepest.join("User.java"), [0u8; 30]).unwrap();
+        std::fs::write(deepest.join("Order.java"), [0u8; 20]).unwrap();
+        // A directory holding files besides its only subdirectory ends its chain.
+        std::fs::create_dir_all(temp_dir.path().join("lib/native")).unwrap();
+        std::fs::write(temp_dir.path().join("lib/native/libapp.so"), [0u8; 8]).unwrap();
+        std::fs::write(temp_dir.path().join("lib/README"), [0u8; 2]).unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let result_ptr = get_directory_map_collapsed(scanner_ptr, test_path_c.as_ptr());
+        let collapsed: FolderHierarchy = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!((collapsed.value, collapsed.file_count, collapsed.dir_count), (60, 4, 6));
+
+        let chain = collapsed.children.iter().find(|c| c.name.starts_with("com")).unwrap();
+        assert_eq!(chain.name, "com/example/app/model");
+        assert_eq!(PathBuf::from(&chain.path), deepest);
+        assert_eq!((chain.value, chain.file_count, chain.dir_count), (50, 2, 3));
+        let mut files: Vec<_> = chain.children.iter().map(|c| c.name.as_str()).collect();
+        files.sort_unstable();
+        assert_eq!(files, ["Order.java", "User.java"]);
+
+        let lib = collapsed.children.iter().find(|c| c.name.starts_with("lib")).unwrap();
+        assert_eq!((lib.name.as_str(), lib.children.len()), ("lib", 2));
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_nodes_report_their_share_of_the_parent() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir(temp_dir.path().join("nested")).await.unwrap();
+        fs::create_dir(temp_dir.path().join("empty")).await.unwrap();
+        fs::write(temp_dir.path().join("quarter.bin"), [0u8; 25]).await.unwrap();
+        fs::write(temp_dir.path().join("nested/rest.bin"), [0u8; 75]).await.unwrap();
+        fs::write(temp_dir.path().join("empty/nothing.bin"), []).await.unwrap();
+
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.percent_of_parent, 100.0);
+        let share = |name: &str| hierarchy.children.iter().find(|c| c.name == name).unwrap().percent_of_parent;
+        assert_eq!(share("quarter.bin"), 25.0);
+        assert_eq!(share("nested"), 75.0);
+        assert_eq!(share("empty"), 0.0);
+        let nested = hierarchy.children.iter().find(|c| c.name == "nested").unwrap();
+        assert_eq!(nested.children[0].percent_of_parent, 100.0);
+        let empty = hierarchy.children.iter().find(|c| c.name == "empty").unwrap();
+        assert_eq!(empty.children[0].percent_of_parent, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_children_are_sorted_by_size() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        for (name, size) in [("medium.bin", 20), ("large.bin", 100), ("small.bin", 1)] {
+            fs::write(temp_dir.path().join(name), vec![0; size]).await.unwrap();
+        }
+
+        let options = ScanOptions { sort: SortOrder::SizeDesc, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.children[0].name, "large.bin");
+        for node in hierarchy.iter() {
+            assert!(node.children.windows(2).all(|pair| pair[0].value >= pair[1].value), "{} is not sorted", node.path);
+        }
+
+        // Background scans sort the root's children too.
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), options);
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let mapped = scanner.directory_map_for(temp_dir.path()).unwrap();
+        assert_eq!(mapped.children, hierarchy.children);
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        let result_ptr = get_directory_map_sorted(scanner_ptr, test_path_c.as_ptr(), SortOrder::NameAsc as i32);
+        let sorted: FolderHierarchy = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        let names: Vec<&str> = sorted.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["large.bin", "medium.bin", "small.bin", "subfolder1", "subfolder2"]);
+
+        let scanner = unsafe { &*scanner_ptr };
+        let sorted = scanner.sorted(temp_dir.path(), SortOrder::SizeAsc).unwrap();
+        assert_eq!(sorted.children.last().unwrap().name, "large.bin");
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_get_scan_summary() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let result_ptr = get_scan_summary(scanner_ptr, test_path_c.as_ptr());
+        let summary: ScanSummary = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        let ScanSummary { scanned_at, scan_duration_ms, .. } = summary;
+        assert_eq!(summary, ScanSummary { bytes: 14 + 13 + 16, files: 3, dirs: 5, max_depth: 3, scanned_at, scan_duration_ms });
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_scans_record_when_they_ran_and_for_how_long() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::create_dir(temp_dir.path().join("docs")).unwrap();
+        std::fs::write(temp_dir.path().join("docs/a.txt"), [0u8; 20]).unwrap();
+
+        let scanner = DirectoryScanner::open();
+        let before = epoch_millis::from_time(SystemTime::now());
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let after = epoch_millis::from_time(SystemTime::now());
+
+        let root = scanner.directory_map_for(temp_dir.path()).unwrap();
+        assert!((before..=after).contains(&root.scanned_at), "{before} <= {} <= {after}", root.scanned_at);
+        assert!(root.scan_duration_ms > 0 && root.scan_duration_ms <= after - before + 1);
+        // Only the root is stamped.
+        assert_eq!((root.children[0].scanned_at, root.children[0].scan_duration_ms), (0, 0));
+
+        let summary = scanner.scan_summary(temp_dir.path()).unwrap();
+        assert_eq!((summary.scanned_at, summary.scan_duration_ms), (root.scanned_at, root.scan_duration_ms));
+    }
+
+    #[tokio::test]
+    async fn test_scanned_roots_tell_whether_their_tree_is_complete() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        let scan = |options: ScanOptions| {
+            let scanner = DirectoryScanner::open();
+            scanner.scan_with_options(temp_dir.path(), options);
+            assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+            scanner.directory_map_for(temp_dir.path()).unwrap()
+        };
+
+        let root = scan(ScanOptions::default());
+        assert_eq!(root.completeness, Some(Completeness::Complete));
+        assert_eq!(root.children[0].completeness, None);
+
+        // subfolder1 holds a nested directory that is counted but not kept.
+        assert_eq!(scan(ScanOptions { max_depth: Some(1), ..Default::default() }).completeness, Some(Completeness::DepthLimited));
+        // Deep enough for every directory, so nothing is cut off.
+        assert_eq!(scan(ScanOptions { max_depth: Some(3), ..Default::default() }).completeness, Some(Completeness::Complete));
+        assert_eq!(scan(ScanOptions { max_nodes: Some(3), ..Default::default() }).completeness, Some(Completeness::NodeLimited));
+    }
+
+    #[test]
+    fn test_memory_estimate_grows_with_the_map() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::write(temp_dir.path().join("file.txt"), b"data").unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(get_map_memory_estimate(scanner_ptr, test_path_c.as_ptr()), 0);
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        let small = get_map_memory_estimate(scanner_ptr, test_path_c.as_ptr());
+        let node_size = std::mem::size_of::<FolderHierarchy>() as u64;
+        assert!(small >= 2 * node_size, "{small} bytes for 2 nodes");
+
+        for i in 0..100 {
+            std::fs::write(temp_dir.path().join(format!("file{i}.txt")), b"data").unwrap();
+        }
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        let large = get_map_memory_estimate(scanner_ptr, test_path_c.as_ptr());
+        assert!(large >= small + 100 * node_size, "{large} bytes for 102 nodes, {small} for 2");
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_get_extension_histogram() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        fs::write(temp_dir.path().join("movie.MOV"), vec![0; 1000]).await.unwrap();
+        fs::write(temp_dir.path().join("subfolder2/clip.mov"), vec![0; 500]).await.unwrap();
+        fs::write(temp_dir.path().join("subfolder1/Makefile"), b"all:").await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let result_ptr = get_extension_histogram(scanner_ptr, test_path_c.as_ptr());
+        let histogram: BTreeMap<String, ExtensionStats> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(histogram, BTreeMap::from([
+            (String::new(), ExtensionStats { count: 1, total_bytes: 4 }),
+            ("mov".to_string(), ExtensionStats { count: 2, total_bytes: 1500 }),
+            ("txt".to_string(), ExtensionStats { count: 3, total_bytes: 14 + 13 + 16 }),
+        ]));
+
+        let subfolder_c = CString::new(temp_dir.path().join("subfolder2").to_str().unwrap()).unwrap();
+        let result_ptr = get_extension_histogram(scanner_ptr, subfolder_c.as_ptr());
+        let histogram: BTreeMap<String, ExtensionStats> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(histogram.len(), 2);
+        assert_eq!(histogram["mov"], ExtensionStats { count: 1, total_bytes: 500 });
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_hierarchy_iterator_visits_every_node_in_pre_order() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let directory_map = scanner.directory_map();
+
+        // The root, 5 directories and 3 files.
+        assert_eq!(directory_map.iter().count(), 9);
+        assert_eq!(directory_map.iter().filter(|node| node.entry_type == EntryKind::File).count(), 3);
+        assert_eq!(directory_map.iter().map(|node| u64::from(node.entry_type == EntryKind::File) * node.value).sum::<u64>(), directory_map.value);
+
+        let nodes: Vec<_> = directory_map.iter_with_depth().map(|(depth, node)| (depth, node.path.clone())).collect();
+        assert_eq!(nodes[0], (0, directory_map.path.clone()));
+        for (index, (depth, path)) in nodes.iter().enumerate().skip(1) {
+            // Every node follows its parent, one level up.
+            let parent = Path::new(path).parent().unwrap().to_string_lossy();
+            let (parent_depth, _) = nodes[..index].iter().rev().find(|(_, earlier)| *earlier == parent).unwrap();
+            assert_eq!(*depth, parent_depth + 1);
+        }
+        let deep = nodes.iter().find(|(_, path)| path.ends_with("test_file2.txt")).unwrap();
+        assert_eq!(deep.0, 3);
+    }
+
+    lazy_static::lazy_static! {
+        static ref SCAN_THREAD_NAMES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    }
+
+    extern "C" fn record_scan_thread_name(_path_ptr: *const c_char) {
+        let name = thread::current().name().unwrap_or_default().to_owned();
+        SCAN_THREAD_NAMES.lock().unwrap().insert(name);
+    }
+
+    #[test]
+    fn test_scans_run_on_the_host_runtime() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::create_dir_all(temp_dir.path().join("ambient/folder")).unwrap();
+        std::fs::create_dir_all(temp_dir.path().join("handle/folder")).unwrap();
+        std::fs::write(temp_dir.path().join("ambient/folder/file.txt"), [0u8; 10]).unwrap();
+        std::fs::write(temp_dir.path().join("handle/folder/file.txt"), [0u8; 20]).unwrap();
+        let host = Builder::new_multi_thread().worker_threads(2).thread_name("host-runtime").enable_all().build().unwrap();
+
+        // Opened from within the host's runtime, and with a handle to it from outside.
+        let ambient = host.block_on(async { DirectoryScanner::open() });
+        let handed = DirectoryScanner::open_with_handle(host.handle().clone());
+        for (scanner, folder, size) in [(&ambient, "ambient", 10), (&handed, "handle", 20)] {
+            SCAN_THREAD_NAMES.lock().unwrap().clear();
+            scanner.set_scan_callback(Some(record_scan_thread_name));
+            scanner.scan(temp_dir.path().join(folder));
+            assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+            assert_eq!(scanner.directory_map().value, size);
+            assert_eq!(*SCAN_THREAD_NAMES.lock().unwrap(), HashSet::from(["host-runtime".to_owned()]));
+        }
+
+        // The host's runtime outlives the scanners.
+        drop((ambient, handed));
+        assert_eq!(host.block_on(async { tokio::spawn(async { 1 }).await.unwrap() }), 1);
+    }
+
+    #[test]
+    fn test_find_first_stops_at_the_first_match() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..100 {
+            let folder = temp_dir.path().join(format!("folder{i}"));
+            std::fs::create_dir(&folder).unwrap();
+            for j in 0..10 {
+                std::fs::write(folder.join(format!("file{j}.txt")), [0u8; 10]).unwrap();
+            }
+        }
+        std::fs::write(temp_dir.path().join("large.bin"), vec![0u8; 5000]).unwrap();
+
+        // The large file sits at the top, so no folder has to be read to find it.
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let scanner = Arc::new(DirectoryScanner::with_runtime(None));
+        let predicate = Box::new(|file: &FolderHierarchy| file.value > 1000);
+        let found = runtime.block_on(find_first_file(temp_dir.path().to_path_buf(), Arc::clone(&scanner), predicate)).unwrap();
+        assert_eq!(found.unwrap().name, "large.bin");
+        let (files, directories) = scanner.scan_progress();
+        assert_eq!(directories, 1);
+        assert!(files <= 101, "{files} files were visited");
+
+        let scanner_ptr = create_directory_scanner();
+        let path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let found_ptr = find_first_larger_than(scanner_ptr, path_c.as_ptr(), 1000);
+        assert_eq!(Path::new(unsafe { CStr::from_ptr(found_ptr) }.to_str().unwrap()), temp_dir.path().join("large.bin"));
+        free_directory_map_string(found_ptr);
+        assert!(find_first_larger_than(scanner_ptr, path_c.as_ptr(), 5000).is_null());
+        // The search is not tracked as a scan.
+        assert_eq!(get_scan_state(scanner_ptr), ScanState::Idle as i32);
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_get_empty_directories() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        fs::create_dir_all(temp_dir.path().join("nested/inner/innermost")).await.unwrap();
+        fs::create_dir_all(temp_dir.path().join("nested/other")).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let empty_directories = |recursive| {
+            let result_ptr = get_empty_directories(scanner_ptr, test_path_c.as_ptr(), recursive);
+            let paths: Vec<String> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+            free_directory_map_string(result_ptr);
+            let mut relative: Vec<_> = paths.iter().map(|path| Path::new(path).strip_prefix(temp_dir.path()).unwrap().to_path_buf()).collect();
+            relative.sort();
+            relative
+        };
+
+        let leaves = ["nested/inner/innermost", "nested/other", "subfolder2/subsubfolder2a", "subfolder2/subsubfolder2b"].map(PathBuf::from);
+        assert_eq!(empty_directories(false), leaves);
+
+        // Directories holding only empty directories count too, but not those next to a file.
+        let mut effectively_empty = vec![PathBuf::from("nested"), PathBuf::from("nested/inner")];
+        effectively_empty.extend(leaves);
+        effectively_empty.sort();
+        assert_eq!(empty_directories(true), effectively_empty);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_get_directory_map_flat_keeps_parent_links() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let result_ptr = get_directory_map_flat(scanner_ptr, test_path_c.as_ptr());
+        let rows: Vec<FlatEntry> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+
+        // The root, 5 directories and 3 files, each with a unique id.
+        assert_eq!(rows.len(), 9);
+        assert_eq!((rows[0].id, rows[0].parent_id, rows[0].value), (0, None, 14 + 13 + 16));
+        assert!(rows.iter().enumerate().all(|(index, row)| row.id == index));
+
+        let by_name: HashMap<&str, &FlatEntry> = rows.iter().map(|row| (row.name.as_str(), row)).collect();
+        let parent_of = |name: &str| by_name[name].parent_id.map(|id| rows[id].name.as_str());
+        assert_eq!(parent_of("subfolder1"), Some(rows[0].name.as_str()));
+        assert_eq!(parent_of("test_file1.txt"), Some("subfolder1"));
+        assert_eq!(parent_of("test_file2.txt"), Some("subsubfolder1"));
+        assert_eq!(parent_of("subsubfolder1"), Some("subfolder1"));
+        assert_eq!(parent_of("subsubfolder2b"), Some("subfolder2"));
+
+        // Every directory's value is the sum of its children's.
+        for row in &rows {
+            let children: Vec<_> = rows.iter().filter(|child| child.parent_id == Some(row.id)).collect();
+            if !children.is_empty() {
+                assert_eq!(row.value, children.iter().map(|child| child.value).sum::<u64>());
+            }
+        }
+
+        let missing_c = CString::new(temp_dir.path().join("missing").to_str().unwrap()).unwrap();
+        let result_ptr = get_directory_map_flat(scanner_ptr, missing_c.as_ptr());
+        assert!(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap().contains("error"));
+        free_directory_map_string(result_ptr);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_get_scan_diff_reports_added_removed_and_grown_entries() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let scan = || {
+            scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+            assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+            get_directory_map(scanner_ptr, test_path_c.as_ptr(), -1)
+        };
+
+        let old_ptr = scan();
+        fs::write(temp_dir.path().join("subfolder2/test_file3.txt"), b"Hello, Testing! Grown").await.unwrap();
+        fs::write(temp_dir.path().join("subfolder1/added.txt"), b"new").await.unwrap();
+        fs::remove_dir(temp_dir.path().join("subfolder2/subsubfolder2a")).await.unwrap();
+        let new_ptr = scan();
+
+        let diff_ptr = get_scan_diff(old_ptr, new_ptr);
+        let diff: ScanDiff = serde_json::from_str(unsafe { CStr::from_ptr(diff_ptr) }.to_str().unwrap()).unwrap();
+        let path = |relative: &str| temp_dir.path().join(relative).to_string_lossy().into_owned();
+        assert_eq!(diff.added, [path("subfolder1/added.txt")]);
+        assert_eq!(diff.removed, [path("subfolder2/subsubfolder2a")]);
+        assert_eq!(diff.changed, [
+            SizeChange { path: temp_dir.path().to_string_lossy().into_owned(), old_size: 43, new_size: 51 },
+            SizeChange { path: path("subfolder1"), old_size: 27, new_size: 30 },
+            SizeChange { path: path("subfolder2"), old_size: 16, new_size: 21 },
+            SizeChange { path: path("subfolder2/test_file3.txt"), old_size: 16, new_size: 21 },
+        ]);
+
+        let unchanged_ptr = get_scan_diff(new_ptr, new_ptr);
+        let unchanged: ScanDiff = serde_json::from_str(unsafe { CStr::from_ptr(unchanged_ptr) }.to_str().unwrap()).unwrap();
+        assert_eq!(unchanged, ScanDiff::default());
+
+        let invalid_c = CString::new("not json").unwrap();
+        let error_ptr = get_scan_diff(invalid_c.as_ptr(), new_ptr);
+        assert!(unsafe { CStr::from_ptr(error_ptr) }.to_str().unwrap().starts_with(r#"{"error":"Invalid directory map"#));
+
+        for string_ptr in [old_ptr, new_ptr, diff_ptr, unchanged_ptr, error_ptr] {
+            free_directory_map_string(string_ptr);
+        }
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_errors_are_valid_json() {
+        let scanner_ptr = create_directory_scanner();
+        let missing_c = CString::new("/no/such/root").unwrap();
+
+        for result_ptr in [
+            get_directory_map(scanner_ptr, missing_c.as_ptr(), 0),
+            get_largest_files(scanner_ptr, missing_c.as_ptr(), 5),
+            get_scan_summary(scanner_ptr, missing_c.as_ptr()),
+        ] {
+            let error: serde_json::Value = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+            free_directory_map_string(result_ptr);
+            assert_eq!(error, serde_json::json!({ "error": "Root folder not found" }));
+        }
+        assert_eq!(error_json("say \"hi\""), r#"{"error":"say \"hi\""}"#);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_scan_timeout_keeps_partial_map() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..500 {
+            fs::create_dir(temp_dir.path().join(format!("folder{i}"))).await.unwrap();
+            fs::write(temp_dir.path().join(format!("folder{i}/file.txt")), b"data").await.unwrap();
+        }
+
+        // Pausing stands in for a stalled mount: the deadline passes before the scan can go on.
+        let scanner = DirectoryScanner::open();
+        scanner.pause();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions { timeout: Some(Duration::from_millis(20)), ..Default::default() });
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        scanner.resume();
+
+        assert_eq!(scanner.scan_state(), ScanState::TimedOut);
+        assert!(scanner.last_error().unwrap().contains("timed out"));
+        let partial = scanner.directory_map_for(temp_dir.path()).unwrap();
+        assert!(partial.value < 500 * 4);
+        assert_eq!(partial.completeness, Some(Completeness::TimedOut));
+        assert!(partial.scanned_at > 0);
+        assert!(partial.scan_duration_ms >= 20, "Took {} ms", partial.scan_duration_ms);
+
+        scanner.scan_with_options(temp_dir.path(), ScanOptions { timeout: Some(Duration::from_secs(10)), ..Default::default() });
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.scan_state(), ScanState::Completed);
+        assert_eq!(scanner.directory_map().value, 500 * 4);
+    }
+
+    #[tokio::test]
+    async fn test_scan_progress_percent_increases_to_100() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..200 {
+            fs::create_dir(temp_dir.path().join(format!("folder{i}"))).await.unwrap();
+            fs::write(temp_dir.path().join(format!("folder{i}/file.txt")), b"data").await.unwrap();
+        }
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions { max_concurrency: 1, estimate_progress: true, ..Default::default() });
+        let mut readings = vec![];
+        while scanner.scan_state() == ScanState::Scanning {
+            readings.push(scanner.scan_progress_percent());
+            thread::sleep(Duration::from_millis(1));
+        }
+        readings.push(scanner.scan_progress_percent());
+
+        assert!(readings.windows(2).all(|pair| pair[0] <= pair[1]), "{readings:?}");
+        assert!(readings.iter().all(|percent| (0.0..=100.0).contains(percent)));
+        assert_eq!(readings.last(), Some(&100.0));
+        // The first pass counts the root, each folder and each file.
+        assert_eq!(scanner.entries_to_scan.load(Ordering::Relaxed), 1 + 200 * 2);
+    }
+
+    #[test]
+    fn test_counting_entries_waits_while_paused_and_stops() {
+        let root = PathBuf::from("/virtual");
+        let mut file_system = MemoryFileSystem::new();
+        for i in 0..10 {
+            file_system.add_file(root.join(format!("folder{i}/file.bin")), 4);
+        }
+
+        let scanner = DirectoryScanner::open_with_file_system(Arc::new(file_system));
+        scanner.pause();
+        scanner.scan_with_options(&root, ScanOptions::builder().estimate_progress(true).build());
+        assert!(!scanner.wait_for_completion(Duration::from_millis(100)));
+        assert_eq!(scanner.scan_state(), ScanState::Scanning);
+        assert_eq!(scanner.entries_to_scan.load(Ordering::Relaxed), 0);
+        assert_eq!(scanner.scan_progress(), (0, 0));
+
+        scanner.request_stop();
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.scan_state(), ScanState::Stopped);
+        assert_eq!(scanner.scan_progress(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_scan_throughput() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..50 {
+            let folder = temp_dir.path().join(format!("folder{}", i));
+            std::fs::create_dir(&folder).unwrap();
+            for j in 0..20 {
+                std::fs::write(folder.join(format!("file{}.txt", j)), [0u8; 100]).unwrap();
+            }
+        }
+
+        let scanner_ptr = create_directory_scanner();
+        let scanner = unsafe { &*scanner_ptr };
+        assert_eq!(scanner.scan_throughput(), ScanThroughput::default());
+
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let result_ptr = get_scan_throughput(scanner_ptr);
+        let throughput: ScanThroughput = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert!(throughput.entries_per_sec > 0.0);
+        assert!(throughput.bytes_per_sec > 0.0);
+        // 100 bytes for every file visited.
+        assert!((throughput.bytes_per_sec / throughput.entries_per_sec) > 90.0);
+
+        // The clock stops with the scan.
+        let finished = scanner.scan_throughput();
+        assert_eq!(finished.elapsed_ms, throughput.elapsed_ms);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(scanner.scan_throughput(), finished);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_watched_roots_follow_changes() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let folder = temp_dir.path().join("folder");
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::write(folder.join("file.txt"), [0u8; 10]).unwrap();
+        std::fs::write(temp_dir.path().join("top.txt"), [0u8; 5]).unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let scanner = unsafe { &*scanner_ptr };
+        let path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let missing_c = CString::new(temp_dir.path().join("missing").to_str().unwrap()).unwrap();
+        assert!(!watch(scanner_ptr, path_c.as_ptr()), "Only scanned roots can be watched");
+        scan_directory_async(scanner_ptr, path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        assert!(!watch(scanner_ptr, missing_c.as_ptr()));
+        let events = scanner.subscribe();
+        assert!(watch(scanner_ptr, path_c.as_ptr()));
+
+        // A burst of writes to a file, and a new directory, are applied in place.
+        for size in [100, 1000, 50] {
+            std::fs::write(folder.join("file.txt"), vec![0u8; size]).unwrap();
+        }
+        std::fs::create_dir(temp_dir.path().join("new")).unwrap();
+        std::fs::write(temp_dir.path().join("new/added.txt"), [0u8; 7]).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while scanner.directory_map().value != 50 + 5 + 7 && Instant::now() < deadline {
+            assert_eq!(events.recv_timeout(Duration::from_secs(10)).unwrap().kind, ScanEventKind::Changed);
+        }
+        let directory_map = scanner.directory_map();
+        assert_eq!((directory_map.value, directory_map.file_count, directory_map.dir_count), (62, 3, 2));
+        assert_eq!(directory_map.path, temp_dir.path().to_str().unwrap());
+        assert_eq!(scanner.subtree(&folder).unwrap().value, 50);
+        assert_eq!(scanner.subtree(temp_dir.path().join("new/added.txt")).unwrap().value, 7);
+
+        assert!(unwatch(scanner_ptr, path_c.as_ptr()));
+        assert!(!unwatch(scanner_ptr, path_c.as_ptr()));
+        std::fs::write(temp_dir.path().join("top.txt"), [0u8; 500]).unwrap();
+        thread::sleep(WATCH_DEBOUNCE * 3);
+        assert_eq!(scanner.directory_map().value, 62);
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_watched_roots_keep_the_options_of_the_scan() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let nested = temp_dir.path().join("folder/nested");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(nested.join("file.txt"), [0u8; 10]).unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions::builder().max_depth(1).include_hidden(false).build());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let events = scanner.subscribe();
+        scanner.watch(temp_dir.path()).unwrap();
+
+        std::fs::write(nested.join("more.txt"), [0u8; 20]).unwrap();
+        std::fs::write(temp_dir.path().join("folder/.hidden"), [0u8; 40]).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while scanner.directory_map().value != 10 + 20 && Instant::now() < deadline {
+            assert_eq!(events.recv_timeout(Duration::from_secs(10)).unwrap().kind, ScanEventKind::Changed);
+        }
+
+        // Leaves time for changes reported late to be applied too.
+        thread::sleep(WATCH_DEBOUNCE * 3);
+
+        // The hidden file stays out, and the folder is still cut off below the first level.
+        let directory_map = scanner.directory_map();
+        assert_eq!((directory_map.value, directory_map.file_count, directory_map.dir_count), (30, 2, 2));
+        assert_eq!(directory_map.children.len(), 1);
+        assert!(directory_map.children[0].children.is_empty());
+        assert!(scanner.unwatch(temp_dir.path()));
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_receive_events_with_paths() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner = DirectoryScanner::open();
+        let events = scanner.subscribe();
+        drop(scanner.subscribe());
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        let events: Vec<ScanEvent> = events.try_iter().collect();
+        let root = temp_dir.path().to_string_lossy().into_owned();
+        assert_eq!(events.first(), Some(&ScanEvent { kind: ScanEventKind::Started, path: root.clone() }));
+        assert_eq!(events.last(), Some(&ScanEvent { kind: ScanEventKind::Completed, path: root }));
+
+        let subsubfolder1 = temp_dir.path().join("subfolder1/subsubfolder1").to_string_lossy().into_owned();
+        let position = |kind| events.iter().position(|e| e.kind == kind && e.path == subsubfolder1).unwrap();
+        assert!(position(ScanEventKind::Started) < position(ScanEventKind::Completed));
+        assert_eq!(events.len(), 2 * 6);
+        // The dropped receiver was unsubscribed on the first event.
+        assert_eq!(scanner.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_can_be_reloaded() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let scanned = temp_dir.path().join("scanned");
+        std::fs::create_dir(&scanned).unwrap();
+        create_test_directory_structure(&scanned).await.unwrap();
+        let snapshot_path = temp_dir.path().join("snapshot.json");
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(&scanned, ScanOptions::builder().snapshots(Duration::from_secs(3600), &snapshot_path).build());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        // The last snapshot is taken as the scan ends. Timestamps are saved in milliseconds,
+        // so compare against the map after the same round trip.
+        let directory_map: FolderHierarchy = serde_json::from_str(&serde_json::to_string(&scanner.directory_map()).unwrap()).unwrap();
+        assert_eq!(load_snapshot(&snapshot_path).unwrap(), directory_map);
+        assert_eq!(directory_map.value, 14 + 13 + 16);
+        assert!(!snapshot_path.with_extension("partial").exists());
+
+        // Snapshots are also taken while the scan goes on.
+        std::fs::remove_file(&snapshot_path).unwrap();
+        let done = CancellationToken::new();
+        let snapshots = tokio::spawn(write_snapshots(Arc::clone(&scanner), scanned.clone(), snapshot_path.clone(), Duration::from_millis(10), done.clone()));
+        for _ in 0..200 {
+            if snapshot_path.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!snapshots.is_finished());
+        done.cancel();
+        snapshots.await.unwrap();
+        assert_eq!(load_snapshot(&snapshot_path).unwrap(), directory_map);
+
+        std::fs::write(&snapshot_path, "not json").unwrap();
+        assert_eq!(load_snapshot(&snapshot_path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_get_scan_diff_accepts_deep_trees() {
+        const LEVELS: usize = 500;
+
+        let root = PathBuf::from("/virtual");
+        let mut deepest = root.clone();
+        for _ in 0..LEVELS {
+            deepest.push("d");
+        }
+        let scan = |len: u64| {
+            let mut file_system = MemoryFileSystem::new();
+            file_system.add_file(deepest.join("bottom.txt"), len);
+            let scanner = DirectoryScanner::open_with_file_system(Arc::new(file_system));
+            scanner.scan(&root);
+            assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+            CString::new(to_json(&scanner.directory_map_for(&root).unwrap())).unwrap()
+        };
+        let (old_c, new_c) = (scan(5), scan(8));
+
+        // Far deeper than the nesting serde_json accepts by default.
+        let diff_ptr = get_scan_diff(old_c.as_ptr(), new_c.as_ptr());
+        let diff: ScanDiff = serde_json::from_str(unsafe { CStr::from_ptr(diff_ptr) }.to_str().unwrap()).unwrap();
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert_eq!(diff.changed.len(), LEVELS + 2);
+        assert!(diff.changed.iter().all(|change| change.old_size == 5 && change.new_size == 8));
+
+        free_directory_map_string(diff_ptr);
+    }
+
+    #[test]
+    fn test_snapshots_of_deep_trees_can_be_reloaded() {
+        const LEVELS: usize = 500;
+
+        let root = PathBuf::from("/virtual");
+        let mut deepest = root.clone();
+        for _ in 0..LEVELS {
+            deepest.push("d");
+        }
+        let mut file_system = MemoryFileSystem::new();
+        file_system.add_file(deepest.join("bottom.txt"), 5);
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let snapshot_path = temp_dir.path().join("snapshot.json");
+
+        let scanner = DirectoryScanner::open_with_file_system(Arc::new(file_system));
+        scanner.scan_with_options(&root, ScanOptions::builder().snapshots(Duration::from_secs(3600), &snapshot_path).build());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        // Far deeper than the nesting serde_json accepts by default.
+        let snapshot = load_snapshot(&snapshot_path).unwrap();
+        assert_eq!(snapshot.summary(), scanner.scan_summary(&root).unwrap());
+        assert_eq!(snapshot.summary().max_depth, LEVELS + 1);
+        assert_eq!(snapshot.largest_files(1)[0].path, deepest.join("bottom.txt").to_string_lossy());
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..50 {
+            let folder = temp_dir.path().join(format!("folder{}", i));
+            std::fs::create_dir(&folder).unwrap();
+            for j in 0..20 {
+                std::fs::write(folder.join(format!("file{}.txt", j)), [0u8; 10]).unwrap();
+            }
+        }
+
+        let scanner_ptr = create_directory_scanner();
+        let scanner = unsafe { &*scanner_ptr };
+        pause_scanning(scanner_ptr);
+
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(!wait_for_scan_completion(scanner_ptr, 100), "the scan completed while paused");
+        assert_eq!(get_scan_state(scanner_ptr), ScanState::Scanning as i32);
+        assert_eq!(scanner.scan_progress().0, 0, "files were scanned while paused");
+
+        resume_scanning(scanner_ptr);
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        assert_eq!(get_scan_state(scanner_ptr), ScanState::Completed as i32);
+        assert_eq!(scanner.scan_progress().0, 50 * 20);
+        assert_eq!(scanner.directory_map().value, 50 * 20 * 10);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_stop_interrupts_paused_scan() {
+        let scanner = DirectoryScanner::open();
+        scanner.pause();
+        let waiter = {
+            let scanner = Arc::clone(&scanner);
+            tokio::spawn(async move { scanner.wait_while_paused().await })
+        };
+
+        scanner.request_stop();
+        tokio::time::timeout(Duration::from_secs(10), waiter).await.unwrap().unwrap();
+        assert!(scanner.is_paused());
+    }
+
+    #[test]
+    fn test_paused_scans_leave_the_runtime_free() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..20 {
+            std::fs::create_dir(temp_dir.path().join(format!("folder{i}"))).unwrap();
+            std::fs::write(temp_dir.path().join(format!("folder{i}/file.txt")), b"data").unwrap();
+        }
+        let runtime = Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+
+        let scanner = DirectoryScanner::open_with_handle(runtime.handle().clone());
+        scanner.pause();
+        scanner.scan(temp_dir.path());
+        assert!(!scanner.wait_for_completion(Duration::from_millis(50)), "A paused scan cannot complete");
+        // The only worker thread is not held by the paused scan.
+        let other_task = runtime.spawn(async { 42 });
+        assert_eq!(runtime.block_on(async { tokio::time::timeout(Duration::from_secs(1), other_task).await }).unwrap().unwrap(), 42);
+
+        // Nor is the timer, so a paused scan still times out.
+        scanner.scan_with_options(temp_dir.path(), ScanOptions::builder().timeout(Duration::from_millis(50)).build());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.scan_state(), ScanState::TimedOut);
+        scanner.shutdown(Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_cancellation_token_stops_the_scan_promptly() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..50 {
+            std::fs::create_dir(temp_dir.path().join(format!("folder{i}"))).unwrap();
+            std::fs::write(temp_dir.path().join(format!("folder{i}/file.txt")), b"data").unwrap();
+        }
+
+        let token = CancellationToken::new();
+        let scanner = DirectoryScanner::open();
+        scanner.set_cancellation_token(&token);
+        scanner.pause();
+        scanner.scan(temp_dir.path());
+        assert!(!scanner.wait_for_completion(Duration::from_millis(50)), "A paused scan cannot complete");
+
+        token.cancel();
+        let cancelled_at = std::time::Instant::now();
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert!(cancelled_at.elapsed() < Duration::from_secs(1), "Took {:?} to stop", cancelled_at.elapsed());
+        assert_eq!(scanner.scan_state(), ScanState::Stopped);
+        assert!(scanner.is_stop_requested());
+        assert!(scanner.directory_map().file_count < 50);
+
+        // Stopping the scanner leaves the caller's token alone.
+        let token = CancellationToken::new();
+        scanner.reset();
+        scanner.set_cancellation_token(&token);
+        stop_scanning(Arc::as_ptr(&scanner));
+        assert!(scanner.cancellation_token().is_cancelled());
+        assert!(!token.is_cancelled());
+    }
+
+    #[test]
+    fn test_swapping_the_cancellation_token_still_stops_running_scans() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..20 {
+            std::fs::create_dir(temp_dir.path().join(format!("folder{i}"))).unwrap();
+            std::fs::write(temp_dir.path().join(format!("folder{i}/file.txt")), b"data").unwrap();
+        }
+        let scanner = DirectoryScanner::open();
+
+        // Stopping the scanner reaches a scan started before the swap.
+        scanner.pause();
+        scanner.scan(temp_dir.path());
+        assert!(!scanner.wait_for_completion(Duration::from_millis(50)), "A paused scan cannot complete");
+        scanner.set_cancellation_token(&CancellationToken::new());
+        scanner.request_stop();
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.scan_state(), ScanState::Stopped);
+
+        // So does cancelling the caller's token.
+        scanner.reset();
+        scanner.pause();
+        scanner.scan(temp_dir.path());
+        assert!(!scanner.wait_for_completion(Duration::from_millis(50)), "A paused scan cannot complete");
+        let token = CancellationToken::new();
+        scanner.set_cancellation_token(&token);
+        token.cancel();
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.scan_state(), ScanState::Stopped);
+
+        // Resetting stops a scan still running.
+        scanner.reset();
+        scanner.pause();
+        scanner.scan(temp_dir.path());
+        assert!(!scanner.wait_for_completion(Duration::from_millis(50)), "A paused scan cannot complete");
+        scanner.reset();
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert!(!scanner.is_stop_requested());
+    }
+
+    lazy_static::lazy_static! {
+        static ref SCANNER_TO_STOP: Mutex<Option<Arc<DirectoryScanner>>> = Mutex::new(None);
+    }
+
+    extern "C" fn stop_after_first_directory(_path_ptr: *const c_char) {
+        if let Some(scanner) = SCANNER_TO_STOP.lock().unwrap().take() {
+            scanner.request_stop();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stopped_scan_keeps_processed_entries() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..20 {
+            fs::create_dir(temp_dir.path().join(format!("folder{i}"))).await.unwrap();
+            fs::write(temp_dir.path().join(format!("folder{i}/file.txt")), b"data").await.unwrap();
+        }
+
+        let scanner = DirectoryScanner::open();
+        *SCANNER_TO_STOP.lock().unwrap() = Some(Arc::clone(&scanner));
+        scanner.set_scan_callback(Some(stop_after_first_directory));
+        let options = ScanOptions { max_concurrency: 1, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), Arc::clone(&scanner), &options, 0, &Traversal::new(&options)).await.unwrap();
+
+        // The folder finished before the stop is kept whole, and nothing after it was started.
+        assert_eq!(hierarchy.children.len(), 1);
+        let folder = &hierarchy.children[0];
+        assert!(folder.name.starts_with("folder"));
+        assert_eq!(folder.children.len(), 1);
+        assert_eq!((folder.value, folder.file_count), (4, 1));
+        assert_eq!((hierarchy.value, hierarchy.file_count, hierarchy.dir_count), (4, 1, 1));
+    }
+
+    /// Reads from `inner`, taking `delay` to open the directories in `slow`.
+    struct SlowFileSystem {
+        inner: MemoryFileSystem,
+        slow: HashSet<PathBuf>,
+        delay: Duration,
+    }
+
+    impl FileSystem for SlowFileSystem {
+        fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<BoxStream<'a, io::Result<DirectoryEntry>>>> {
+            Box::pin(async move {
+                if self.slow.contains(path) {
+                    tokio::time::sleep(self.delay).await;
+                }
+                self.inner.read_dir(path).await
+            })
+        }
+
+        fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<EntryMetadata>> {
+            self.inner.metadata(path)
+        }
+
+        fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+            self.inner.canonicalize(path)
+        }
+
+        fn read_link<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+            self.inner.read_link(path)
+        }
+    }
+
+    #[test]
+    fn test_stop_drops_branches_still_being_scanned() {
+        let root = PathBuf::from("/virtual");
+        let mut inner = MemoryFileSystem::new();
+        let mut slow = HashSet::new();
+        for i in 0..100 {
+            let folder = root.join(format!("folder{i:03}"));
+            for name in ["a.bin", "b.bin", "c.bin"] {
+                inner.add_file(folder.join(name), 10);
+            }
+            // A nested directory that takes long to open keeps the folders after the first ten unfinished.
+            inner.add_file(folder.join("nested/d.bin"), 5);
+            if i >= 10 {
+                slow.insert(folder.join("nested"));
+            }
+        }
+        let file_system = SlowFileSystem { inner, slow, delay: Duration::from_secs(30) };
+
+        let scanner = DirectoryScanner::open_with_file_system(Arc::new(file_system));
+        scanner.scan_with_options(&root, ScanOptions::builder().max_concurrency(32).build());
+        let started = Instant::now();
+        while scanner.directory_map_for(&root).is_none_or(|root| root.children.len() < 10) {
+            assert!(started.elapsed() < Duration::from_secs(10), "The fast folders were not scanned");
+            thread::sleep(Duration::from_millis(5));
+        }
+        // Give the scans of the slow folders time to be waiting on their nested directories.
+        thread::sleep(Duration::from_millis(50));
+
+        scanner.request_stop();
+        let stopped_at = Instant::now();
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert!(stopped_at.elapsed() < Duration::from_secs(1), "Took {:?} to stop", stopped_at.elapsed());
+        assert_eq!(scanner.scan_state(), ScanState::Stopped);
+
+        // Only the folders scanned in full are kept, whole, with totals that add up.
+        let hierarchy = scanner.directory_map_for(&root).unwrap();
+        for folder in &hierarchy.children {
+            assert_eq!((folder.value, folder.file_count, folder.dir_count), (35, 4, 1), "{}", folder.name);
+        }
+        assert_eq!(hierarchy.children.len(), 10);
+        assert_eq!((hierarchy.value, hierarchy.file_count, hierarchy.dir_count), (350, 40, 20));
+        assert!(scanner.scan_errors().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reset_allows_a_fresh_scan_after_a_stop() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        let scanner_ptr = create_directory_scanner();
+        let scanner = unsafe { &*scanner_ptr };
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+
+        stop_scanning(scanner_ptr);
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        assert_eq!(scanner.scan_state(), ScanState::Stopped);
+        assert_eq!(scanner.directory_map().value, 0);
+
+        reset_scanner(scanner_ptr);
+        assert!(!scanner.is_stop_requested());
+        assert_eq!(scanner.scan_state(), ScanState::Idle);
+        assert_eq!(scanner.scan_progress(), (0, 0));
+        assert!(scanner.scanned_roots().is_empty());
+        assert!(scanner.last_error().is_none());
+
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        assert_eq!(scanner.scan_state(), ScanState::Completed);
+        assert_eq!(scanner.directory_map().value, 14 + 13 + 16);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_extension_filter() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir_all(temp_dir.path().join("movies/deep")).await.unwrap();
+        std::fs::write(temp_dir.path().join("movies/a.mp4"), [0u8; 100]).unwrap();
+        std::fs::write(temp_dir.path().join("movies/deep/b.MKV"), [0u8; 50]).unwrap();
+        std::fs::write(temp_dir.path().join("movies/notes.txt"), [0u8; 7]).unwrap();
+
+        let options = ScanOptions {
+            include_extensions: vec!["mp4".to_string(), ".mkv".to_string()],
+            ..Default::default()
+        };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        let movies = &hierarchy.children[0];
+        assert_eq!(hierarchy.value, 150);
+        assert!(movies.children.iter().all(|c| c.name != "notes.txt"));
+        assert_eq!(movies.children.iter().find(|c| c.name == "deep").unwrap().children[0].name, "b.MKV");
+
+        let options = ScanOptions {
+            exclude_extensions: vec!["TXT".to_string()],
+            ..Default::default()
+        };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.value, 150);
+    }
+
+    #[cfg(not(windows))]
+    #[tokio::test]
+    async fn test_include_globs_select_files_by_relative_path() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir_all(temp_dir.path().join("logs/old")).await.unwrap();
+        fs::write(temp_dir.path().join("app.log"), b"12345").await.unwrap();
+        fs::write(temp_dir.path().join("logs/old/server.log"), b"123").await.unwrap();
+        fs::write(temp_dir.path().join("logs/readme.txt"), b"1234567").await.unwrap();
+        fs::write(temp_dir.path().join("cache_1.tmp"), b"1").await.unwrap();
+        fs::write(temp_dir.path().join("logs/cache_2.tmp"), b"12").await.unwrap();
+
+        let names = |hierarchy: &FolderHierarchy| {
+            let mut names: Vec<_> = hierarchy.files().map(|file| file.name.clone()).collect();
+            names.sort();
+            names
+        };
+
+        let options = ScanOptions { include_globs: vec!["**/*.log".into(), "cache_*.tmp".into()], ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(names(&hierarchy), ["app.log", "cache_1.tmp", "server.log"]);
+        assert_eq!((hierarchy.value, hierarchy.file_count), (5 + 3 + 1, 3));
+        // Directories without a match are still walked and listed.
+        let logs = hierarchy.children.iter().find(|c| c.name == "logs").unwrap();
+        assert_eq!(logs.children.len(), 1);
+        assert_eq!(logs.children[0].name, "old");
+
+        let options = ScanOptions { count_unmatched_files: true, ..options };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(names(&hierarchy), ["app.log", "cache_1.tmp", "server.log"]);
+        assert_eq!((hierarchy.value, hierarchy.file_count), (18, 5));
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions { include_globs: vec!["**/*.log".into()], ..Default::default() });
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(names(&scanner.directory_map()), ["app.log", "server.log"]);
+
+        scanner.scan_with_options(temp_dir.path(), ScanOptions { include_globs: vec!["[".into()], ..Default::default() });
+        assert_eq!(scanner.scan_state(), ScanState::Error);
+        assert_eq!(scanner.last_error_kind(), Some(ScanErrorKind::Io));
+    }
+
+    #[tokio::test]
+    async fn test_hidden_entries_can_be_skipped() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir_all(temp_dir.path().join(".cache")).await.unwrap();
+        std::fs::write(temp_dir.path().join(".cache/blob"), [0u8; 1000]).unwrap();
+        std::fs::write(temp_dir.path().join(".hidden"), [0u8; 100]).unwrap();
+        std::fs::write(temp_dir.path().join("visible.txt"), [0u8; 10]).unwrap();
+
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &ScanOptions::default(), 0, &Traversal::new(&ScanOptions::default())).await.unwrap();
+        assert_eq!(hierarchy.value, 1110);
+        assert_eq!(hierarchy.children.len(), 3);
+
+        let options = ScanOptions { include_hidden: false, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.value, 10);
+        assert_eq!(hierarchy.children.len(), 1);
+        assert_eq!(hierarchy.children[0].name, "visible.txt");
+    }
+
+    #[test]
+    fn test_concurrent_readers_see_consistent_maps() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..100 {
+            let folder = temp_dir.path().join(format!("folder{}", i));
+            std::fs::create_dir(&folder).unwrap();
+            for j in 0..20 {
+                std::fs::write(folder.join(format!("file{}.txt", j)), [0u8; 10]).unwrap();
+            }
+        }
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(temp_dir.path());
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let scanner = Arc::clone(&scanner);
+                thread::spawn(move || {
+                    let mut last_value = 0;
+                    loop {
+                        let scanning = scanner.scan_state() == ScanState::Scanning;
+                        let directory_map = scanner.directory_map();
+                        // Every snapshot is taken between two updates, so the totals add up.
+                        assert_eq!(directory_map.value, directory_map.children.iter().map(|child| child.value).sum::<u64>());
+                        assert_eq!(directory_map.file_count, directory_map.children.iter().map(|child| child.file_count).sum::<u64>());
+                        assert!(directory_map.value >= last_value);
+                        last_value = directory_map.value;
+                        if !scanning {
+                            return last_value;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), 100 * 20 * 10);
+        }
+
+        // A reader holding the map does not keep others from reading it.
+        let held = scanner.directory_map.read().unwrap();
+        let other_reader = {
+            let scanner = Arc::clone(&scanner);
+            thread::spawn(move || scanner.scan_summary(scanner.scanned_roots()[0].clone()).unwrap())
+        };
+        let summary = other_reader.join().unwrap();
+        drop(held);
+        assert_eq!((summary.bytes, summary.files, summary.dirs), (100 * 20 * 10, 2000, 100));
+    }
+
+    #[tokio::test]
+    async fn test_get_directory_map_does_not_block_during_scan() {
+        let root = PathBuf::from("/virtual");
+        let mut inner = MemoryFileSystem::new();
+        for i in 0..200 {
+            for j in 0..25 {
+                inner.add_file(root.join(format!("folder{i}/file{j}.txt")), 2);
+            }
+        }
+        // A folder that takes long to open keeps the scan running while the map is read.
+        let slow = HashSet::from([root.join("folder199")]);
+        let file_system = SlowFileSystem { inner, slow, delay: Duration::from_secs(30) };
+
+        let scanner_ptr = Arc::into_raw(DirectoryScanner::open_with_file_system(Arc::new(file_system))) as *mut DirectoryScanner;
+        let test_path_c = CString::new(root.to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+
+        let scanner = unsafe { &*scanner_ptr };
+        while scanner.scan_progress().0 == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(scanner.scan_state(), ScanState::Scanning);
+        let started = std::time::Instant::now();
+        let result_ptr = get_directory_map(scanner_ptr, test_path_c.as_ptr(), 0);
+        assert!(started.elapsed() < Duration::from_millis(500), "get_directory_map blocked on the running scan");
+        assert_eq!(scanner.scan_state(), ScanState::Scanning);
+
+        let result_str = unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap();
+        let directory_map: FolderHierarchy = serde_json::from_str(result_str).unwrap();
+        assert!(directory_map.children.len() < 200);
+        free_directory_map_string(result_ptr);
+
+        stop_scanning(scanner_ptr);
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_scan_errors_are_recorded_instead_of_panicking() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let scanner_ptr = create_directory_scanner();
+
+        let missing_path_c = CString::new(temp_dir.path().join("missing").to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, missing_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let error_ptr = get_last_error(scanner_ptr);
+        let error = unsafe { CStr::from_ptr(error_ptr) }.to_str().unwrap().to_string();
+        assert!(error.contains("missing"), "unexpected last error: {}", error);
+
+        free_directory_scanner(scanner_ptr);
+
+        // A dangling link can only be read when links are followed.
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(temp_dir.path().join("nowhere"), temp_dir.path().join("subfolder1/dangling")).unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions { follow_symlinks: true, ..Default::default() });
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        assert_eq!(scanner.directory_map().value, 14 + 13 + 16);
+        #[cfg(unix)]
+        assert!(scanner.last_error().unwrap().contains("dangling"));
+    }
+
+    /// Reads from `inner`, failing the next reads of the directories in `failures` with their
+    /// queued errors, first one first.
+    struct FlakyFileSystem {
+        inner: MemoryFileSystem,
+        failures: Mutex<HashMap<PathBuf, VecDeque<io::ErrorKind>>>,
+    }
+
+    impl FileSystem for FlakyFileSystem {
+        fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<BoxStream<'a, io::Result<DirectoryEntry>>>> {
+            match self.failures.lock().unwrap().get_mut(path).and_then(VecDeque::pop_front) {
+                Some(kind) => Box::pin(async move { Err(io::Error::from(kind)) }),
+                None => self.inner.read_dir(path),
+            }
+        }
+
+        fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<EntryMetadata>> {
+            self.inner.metadata(path)
+        }
+
+        fn canonicalize<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+            self.inner.canonicalize(path)
+        }
+
+        fn read_link<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<PathBuf>> {
+            self.inner.read_link(path)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transient_read_failures_are_retried() {
+        let root = PathBuf::from("/virtual");
+        let (flaky, blocking, denied, broken) = (root.join("flaky"), root.join("blocking"), root.join("denied"), root.join("broken"));
+        let failing = flaky.join("failing");
+        let mut inner = MemoryFileSystem::new();
+        inner
+            .add_file(root.join("top.bin"), 14)
+            .add_file(flaky.join("f.bin"), 10)
+            .add_file(failing.join("x.bin"), 13)
+            .add_file(blocking.join("b.bin"), 5)
+            .add_file(denied.join("d.bin"), 16)
+            .add_file(broken.join("o.bin"), 7);
+        let file_system = Arc::new(FlakyFileSystem {
+            inner,
+            failures: Mutex::new(HashMap::from([
+                (flaky.clone(), VecDeque::from([io::ErrorKind::TimedOut, io::ErrorKind::Interrupted])),
+                (blocking.clone(), VecDeque::from([io::ErrorKind::WouldBlock])),
+                (denied.clone(), VecDeque::from([io::ErrorKind::PermissionDenied; 2])),
+                (broken.clone(), VecDeque::from([io::ErrorKind::Other; 2])),
+                (failing.clone(), VecDeque::from([io::ErrorKind::TimedOut; 4])),
+            ])),
+        });
+
+        let scanner = DirectoryScanner::open_with_file_system(Arc::clone(&file_system) as Arc<dyn FileSystem>);
+        let options = ScanOptions::builder().max_retries(3).retry_backoff(Duration::from_millis(1)).build();
+        let hierarchy = scan_folder(root.clone(), Arc::clone(&scanner), &options, 0, &Traversal::new(&options)).await.unwrap();
+
+        // Transient failures are overcome, while other errors are not tried again,
+        // and retries run out on a directory failing more times than allowed.
+        let failures = file_system.failures.lock().unwrap().drain().map(|(path, left)| (path, left.len())).collect::<HashMap<_, _>>();
+        assert_eq!(failures, HashMap::from([(flaky, 0), (blocking, 0), (denied.clone(), 1), (broken.clone(), 1), (failing.clone(), 0)]));
+        assert_eq!(hierarchy.value, 14 + 10 + 5);
+
+        let errors = scanner.scan_errors();
+        let kinds: HashMap<_, _> = errors.iter().map(|error| (PathBuf::from(&error.path), error.kind)).collect();
+        assert_eq!(kinds, HashMap::from([
+            (denied, ScanErrorKind::PermissionDenied),
+            (broken, ScanErrorKind::Io),
+            (failing, ScanErrorKind::Interrupted),
+        ]));
+    }
+
+    #[test]
+    fn test_error_kinds_follow_io_errors() {
+        assert_eq!(ScanErrorKind::from(io::ErrorKind::PermissionDenied), ScanErrorKind::PermissionDenied);
+        assert_eq!(ScanErrorKind::from(io::ErrorKind::NotFound), ScanErrorKind::NotFound);
+        assert_eq!(ScanErrorKind::from(io::ErrorKind::TimedOut), ScanErrorKind::Interrupted);
+        assert_eq!(ScanErrorKind::from(io::ErrorKind::WouldBlock), ScanErrorKind::Interrupted);
+        assert_eq!(ScanErrorKind::from(io::ErrorKind::Other), ScanErrorKind::Io);
+        assert_eq!(ScanErrorKind::from(io::ErrorKind::InvalidData), ScanErrorKind::Io);
+        assert!(ScanErrorKind::Interrupted.is_transient());
+        assert!(!ScanErrorKind::Io.is_transient());
+    }
+
+    #[test]
+    fn test_invalid_utf8_paths_are_reported_instead_of_panicking() {
+        let scanner_ptr = create_directory_scanner();
+        let invalid_path_c = CString::new(b"/tmp/\xff".to_vec()).unwrap();
+        let expect_error = |result_ptr: *mut c_char| {
+            assert_eq!(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap(), r#"{"error":"Invalid UTF-8 in path"}"#);
+            free_directory_map_string(result_ptr);
+            assert_eq!(get_last_error_code(scanner_ptr), ScanErrorKind::InvalidUtf8Path as i32);
+            reset_scanner(scanner_ptr);
+        };
+
+        expect_error(get_directory_map(scanner_ptr, invalid_path_c.as_ptr(), -1));
+        expect_error(get_directory_map_pretty(scanner_ptr, invalid_path_c.as_ptr(), -1));
+        expect_error(get_largest_files(scanner_ptr, invalid_path_c.as_ptr(), 10));
+        let query_c = CString::new("log").unwrap();
+        expect_error(search_files(scanner_ptr, invalid_path_c.as_ptr(), query_c.as_ptr(), 0));
+
+        expect_error(prune_below(scanner_ptr, invalid_path_c.as_ptr(), 1024));
+        expect_error(get_directory_map_collapsed(scanner_ptr, invalid_path_c.as_ptr()));
+        expect_error(get_directory_map_sorted(scanner_ptr, invalid_path_c.as_ptr(), 0));
+        expect_error(get_scan_summary(scanner_ptr, invalid_path_c.as_ptr()));
+        assert_eq!(get_map_memory_estimate(scanner_ptr, invalid_path_c.as_ptr()), 0);
+        assert_eq!(get_last_error_code(scanner_ptr), ScanErrorKind::InvalidUtf8Path as i32);
+        reset_scanner(scanner_ptr);
+        expect_error(get_extension_histogram(scanner_ptr, invalid_path_c.as_ptr()));
+        expect_error(get_usage_by_user(scanner_ptr, invalid_path_c.as_ptr()));
+        expect_error(get_duplicate_files(scanner_ptr, invalid_path_c.as_ptr()));
+        assert!(find_first_larger_than(scanner_ptr, invalid_path_c.as_ptr(), 0).is_null());
+        assert_eq!(get_last_error_code(scanner_ptr), ScanErrorKind::InvalidUtf8Path as i32);
+        reset_scanner(scanner_ptr);
+        expect_error(get_empty_directories(scanner_ptr, invalid_path_c.as_ptr(), true));
+        expect_error(get_directory_map_flat(scanner_ptr, invalid_path_c.as_ptr()));
+        assert!(!watch(scanner_ptr, invalid_path_c.as_ptr()));
+        assert_eq!(get_last_error_code(scanner_ptr), ScanErrorKind::InvalidUtf8Path as i32);
+        reset_scanner(scanner_ptr);
+        assert!(!unwatch(scanner_ptr, invalid_path_c.as_ptr()));
+        assert_eq!(get_last_error_code(scanner_ptr), ScanErrorKind::InvalidUtf8Path as i32);
+        reset_scanner(scanner_ptr);
+        let result_ptr = import_binary(invalid_path_c.as_ptr());
+        assert_eq!(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap(), r#"{"error":"Invalid UTF-8 in path"}"#);
+        free_directory_map_string(result_ptr);
+
+        let valid_path_c = CString::new("/tmp").unwrap();
+        let result_ptr = search_files(scanner_ptr, valid_path_c.as_ptr(), invalid_path_c.as_ptr(), 0);
+        assert_eq!(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap(), r#"{"error":"Invalid UTF-8 in query"}"#);
+        free_directory_map_string(result_ptr);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_last_error_code() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let scanner_ptr = create_directory_scanner();
+        assert_eq!(get_last_error_code(scanner_ptr), 0);
+
+        let missing_path_c = CString::new(temp_dir.path().join("missing").to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, missing_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        assert_eq!(get_last_error_code(scanner_ptr), ScanErrorKind::NotFound as i32);
+
+        let invalid_path_c = CString::new(b"/tmp/\xff".to_vec()).unwrap();
+        scan_directory_async(scanner_ptr, invalid_path_c.as_ptr());
+        assert_eq!(get_last_error_code(scanner_ptr), ScanErrorKind::InvalidUtf8Path as i32);
+
+        // Only the path is checked for it; an invalid filter is just an error.
+        let valid_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let invalid_extensions_c = CString::new(b"txt,\xff".to_vec()).unwrap();
+        scan_directory_with_filter(scanner_ptr, valid_path_c.as_ptr(), invalid_extensions_c.as_ptr(), false);
+        assert_eq!(get_last_error_code(scanner_ptr), ScanErrorKind::Io as i32);
+        scan_directory_with_filter(scanner_ptr, invalid_path_c.as_ptr(), valid_path_c.as_ptr(), false);
+        assert_eq!(get_last_error_code(scanner_ptr), ScanErrorKind::InvalidUtf8Path as i32);
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_permission_denied_is_reported_by_code() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let locked = temp_dir.path().join("locked");
+        fs::create_dir(&locked).await.unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if std::fs::read_dir(&locked).is_ok() {
+            // Running with privileges that ignore permissions, such as root.
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+            return;
+        }
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(get_last_error_code(Arc::as_ptr(&scanner)), ScanErrorKind::PermissionDenied as i32);
+        assert_eq!(scanner.scan_errors()[0].kind, ScanErrorKind::PermissionDenied);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_denied_directories_are_listed_as_inaccessible() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let locked = temp_dir.path().join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::write(temp_dir.path().join("open.txt"), [0u8; 4]).unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        if std::fs::read_dir(&locked).is_ok() {
+            // Running with privileges that ignore permissions, such as root.
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+            return;
+        }
+
+        let scanner = DirectoryScanner::open();
+        // A dangling link is an error too, but not a denied one.
+        std::os::unix::fs::symlink(temp_dir.path().join("nowhere"), temp_dir.path().join("dangling")).unwrap();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions { follow_symlinks: true, ..Default::default() });
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let result_ptr = get_inaccessible_paths(Arc::as_ptr(&scanner));
+        let paths: Vec<String> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(paths, [locked.to_string_lossy()]);
+        assert_eq!(scanner.scan_errors().len(), 2);
+        // The scan goes on past the denied directory.
+        assert_eq!(scanner.directory_map_for(temp_dir.path()).unwrap().file_count, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names_are_reported_as_lossy() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+        std::fs::write(temp_dir.path().join(name), b"data").unwrap();
+        std::fs::write(temp_dir.path().join("plain.txt"), b"data").unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let errors = scanner.scan_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ScanErrorKind::LossyPath);
+        assert_eq!(errors[0].path, temp_dir.path().join("caf\u{FFFD}.txt").to_str().unwrap());
+        assert_eq!(errors[0].raw_path.as_deref(), Some(temp_dir.path().join(name).as_os_str().as_bytes()));
+        assert!(errors[0].message.contains(r"caf\xE9.txt"), "{}", errors[0].message);
+        assert_eq!(get_last_error_code(Arc::as_ptr(&scanner)), ScanErrorKind::LossyPath as i32);
+        // The entry itself is still scanned.
+        assert_eq!((scanner.directory_map().value, scanner.directory_map().file_count), (8, 2));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_scan_errors_are_collected() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        std::os::unix::fs::symlink(temp_dir.path().join("nowhere"), temp_dir.path().join("subfolder1/dangling")).unwrap();
+        std::os::unix::fs::symlink(temp_dir.path().join("nowhere"), temp_dir.path().join("subfolder2/also_dangling")).unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions { follow_symlinks: true, ..Default::default() });
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        let result_ptr = get_scan_errors(Arc::as_ptr(&scanner));
+        let mut errors: Vec<ScanError> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+        let paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, [
+            temp_dir.path().join("subfolder1/dangling").to_string_lossy(),
+            temp_dir.path().join("subfolder2/also_dangling").to_string_lossy(),
+        ]);
+        assert!(errors.iter().all(|e| !e.message.is_empty()));
+        // The rest of the tree is still scanned.
+        assert_eq!(scanner.directory_map().value, 14 + 13 + 16);
+
+        // Each scan starts with an empty list.
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert!(scanner.scan_errors().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hardlinks_can_be_counted_once() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir(temp_dir.path().join("other")).await.unwrap();
+        fs::write(temp_dir.path().join("original.bin"), vec![0; 1000]).await.unwrap();
+        std::fs::hard_link(temp_dir.path().join("original.bin"), temp_dir.path().join("other/link.bin")).unwrap();
+        fs::write(temp_dir.path().join("single.bin"), vec![0; 10]).await.unwrap();
+
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.value, 2010);
+
+        let options = ScanOptions { dedup_hardlinks: true, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.value, 1010);
+        assert_eq!(hierarchy.file_count, 3);
+        let other = hierarchy.children.iter().find(|c| c.name == "other").unwrap();
+        assert_eq!(other.children.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_on_disk_size_counts_allocated_blocks() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let sparse = std::fs::File::create(temp_dir.path().join("sparse.bin")).unwrap();
+        sparse.set_len(64 * 1024 * 1024).unwrap();
+        drop(sparse);
+
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.value, 64 * 1024 * 1024);
+
+        let options = ScanOptions { on_disk_size: true, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert!(hierarchy.value < 64 * 1024 * 1024);
+        assert_eq!(hierarchy.children[0].value, hierarchy.value);
+    }
+
+    #[tokio::test]
+    async fn test_one_filesystem_skips_other_devices() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let options = ScanOptions { one_filesystem: true, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!((hierarchy.value, hierarchy.dir_count), (14 + 13 + 16, 5));
+
+        // A followed link into a directory of another device reaches it like a mount point would.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let device = |path: &Path| path.metadata().unwrap().dev();
+            let Some(other_device) = ["/dev/shm", "/dev", "/run"]
+                .into_iter()
+                .map(Path::new)
+                .find(|path| path.is_dir() && device(path) != device(temp_dir.path()))
+            else {
+                return;
+            };
+            std::os::unix::fs::symlink(other_device, temp_dir.path().join("subfolder1/mounted")).unwrap();
+
+            let options = ScanOptions { one_filesystem: true, follow_symlinks: true, ..Default::default() };
+            let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+            assert_eq!((hierarchy.value, hierarchy.dir_count), (14 + 13 + 16, 5));
+            let subfolder1 = hierarchy.children.iter().find(|c| c.name == "subfolder1").unwrap();
+            assert!(subfolder1.children.iter().all(|c| c.name != "mounted"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_entries_carry_unix_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir(temp_dir.path().join("shared")).await.unwrap();
+        fs::write(temp_dir.path().join("shared/open.txt"), b"anyone").await.unwrap();
+        std::fs::set_permissions(temp_dir.path().join("shared"), std::fs::Permissions::from_mode(0o750)).unwrap();
+        std::fs::set_permissions(temp_dir.path().join("shared/open.txt"), std::fs::Permissions::from_mode(0o666)).unwrap();
+
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &ScanOptions::default(), 0, &Traversal::new(&ScanOptions::default())).await.unwrap();
+        let shared = &hierarchy.children[0];
+        assert_eq!(shared.mode.map(|mode| mode & 0o7777), Some(0o750));
+        let file = &shared.children[0];
+        assert_eq!(file.mode.map(|mode| mode & 0o7777), Some(0o666));
+        assert_eq!(serde_json::to_value(file).unwrap()["mode"], 0o100666);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_usage_is_added_up_by_owner() {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::create_dir(temp_dir.path().join("docs")).unwrap();
+        std::fs::write(temp_dir.path().join("docs/a.txt"), [0u8; 20]).unwrap();
+        std::fs::write(temp_dir.path().join("b.txt"), [0u8; 10]).unwrap();
+        // Files created here belong to the user running the test.
+        let uid = std::fs::metadata(temp_dir.path().join("b.txt")).unwrap().uid();
+        let gid = std::fs::metadata(temp_dir.path().join("b.txt")).unwrap().gid();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions::builder().read_owners(true).build());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let hierarchy = scanner.directory_map_for(temp_dir.path()).unwrap();
+        assert!(hierarchy.iter().all(|node| (node.uid, node.gid) == (Some(uid), Some(gid))));
+
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let result_ptr = get_usage_by_user(Arc::as_ptr(&scanner), test_path_c.as_ptr());
+        let usage: BTreeMap<u32, u64> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(usage, BTreeMap::from([(uid, 30)]));
+
+        // Owners are only read when asked for.
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert!(scanner.directory_map_for(temp_dir.path()).unwrap().iter().all(|node| node.uid.is_none()));
+        assert_eq!(scanner.usage_by_user(temp_dir.path()), Some(BTreeMap::new()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_cycles_terminate() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        std::os::unix::fs::symlink(temp_dir.path(), temp_dir.path().join("subfolder1/back_to_root")).unwrap();
+        std::os::unix::fs::symlink(temp_dir.path().join("subfolder1"), temp_dir.path().join("subfolder2/to_subfolder1")).unwrap();
+
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &ScanOptions::default(), 0, &Traversal::new(&ScanOptions::default())).await.unwrap();
+        let subfolder1 = hierarchy.children.iter().find(|c| c.name == "subfolder1").unwrap();
+        let link = subfolder1.children.iter().find(|c| c.name == "back_to_root").unwrap();
+        assert_eq!(link.entry_type, EntryKind::Symlink);
+        assert!(link.children.is_empty());
+
+        let options = ScanOptions { follow_symlinks: true, ..Default::default() };
+        let traversal = Traversal::new(&options);
+        traversal.first_visit(&options, temp_dir.path(), &RealFileSystem).await;
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &traversal).await.unwrap();
+        assert_eq!(hierarchy.value, 14 + 13 + 16);
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_junctions_are_recorded_as_links() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        let mklink = |link: PathBuf, target: &Path| {
+            let status = std::process::Command::new("cmd").arg("/C").arg("mklink").arg("/J").arg(link).arg(target).output().unwrap().status;
+            assert!(status.success());
+        };
+        mklink(temp_dir.path().join("subfolder1/back_to_root"), temp_dir.path());
+        mklink(temp_dir.path().join("junction"), &temp_dir.path().join("subfolder2"));
+
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        let junction = hierarchy.children.iter().find(|c| c.name == "junction").unwrap();
+        assert_eq!(junction.entry_type, EntryKind::Symlink);
+        assert!(junction.children.is_empty());
+        assert_eq!((hierarchy.file_count, hierarchy.dir_count), (3 + 2, 5));
+
+        // Followed junctions lead back into the tree, but every directory is only walked once.
+        let options = ScanOptions { follow_symlinks: true, ..Default::default() };
+        let traversal = Traversal::new(&options);
+        traversal.first_visit(&options, temp_dir.path(), &RealFileSystem).await;
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &traversal).await.unwrap();
+        assert_eq!(hierarchy.value, 14 + 13 + 16);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_targets_are_recorded() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        std::os::unix::fs::symlink("subfolder1/test_file1.txt", temp_dir.path().join("file_link")).unwrap();
+        std::os::unix::fs::symlink(temp_dir.path().join("subfolder2"), temp_dir.path().join("dir_link")).unwrap();
+
+        for follow_symlinks in [false, true] {
+            let options = ScanOptions { follow_symlinks, ..Default::default() };
+            let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+            let entry = |name: &str| hierarchy.children.iter().find(|c| c.name == name).unwrap();
+
+            assert_eq!(entry("file_link").entry_type, EntryKind::Symlink);
+            assert_eq!(entry("file_link").symlink_target.as_deref(), Some("subfolder1/test_file1.txt"));
+            assert_eq!(entry("dir_link").entry_type, EntryKind::Symlink);
+            assert_eq!(entry("dir_link").symlink_target, Some(temp_dir.path().join("subfolder2").to_string_lossy().into_owned()));
+            assert_eq!(entry("dir_link").children.is_empty(), !follow_symlinks);
+            assert_eq!(entry("subfolder1").symlink_target, None);
+        }
+    }
+
+    #[test]
+    fn test_links_are_resolved_through_the_file_system() {
+        // None of these paths exist on disk, so nothing can be resolved by going around the file system.
+        let root = PathBuf::from("/virtual");
+        let mut file_system = MemoryFileSystem::new();
+        file_system
+            .add_file(root.join("folder/file.bin"), 4)
+            .add_symlink(root.join("folder/back_to_root"), &root)
+            .add_symlink(root.join("file_link"), "folder/file.bin");
+        let scanner = DirectoryScanner::open_with_file_system(Arc::new(file_system));
+
+        for follow_symlinks in [false, true] {
+            scanner.scan_with_options(&root, ScanOptions { follow_symlinks, timeout: Some(Duration::from_secs(10)), ..Default::default() });
+            assert!(scanner.wait_for_completion(Duration::from_secs(20)));
+            assert_eq!(scanner.scan_state(), ScanState::Completed);
+            let hierarchy = scanner.directory_map_for(&root).unwrap();
+            let file_link = hierarchy.children.iter().find(|c| c.name == "file_link").unwrap();
+            assert_eq!(file_link.symlink_target.as_deref(), Some("folder/file.bin"));
+
+            // Followed, the link back to the root leads to a directory already walked, and is left out.
+            let folder = hierarchy.children.iter().find(|c| c.name == "folder").unwrap();
+            let back_to_root = folder.children.iter().find(|c| c.name == "back_to_root");
+            assert_eq!(back_to_root.is_none(), follow_symlinks);
+            if let Some(back_to_root) = back_to_root {
+                assert_eq!(back_to_root.symlink_target, Some(display_path(&root)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_scanning_a_file_reports_the_file_alone() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let file_path = temp_dir.path().join("notes.txt");
+        std::fs::write(&file_path, b"not a directory").unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let path_c = CString::new(file_path.to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+        assert_eq!(get_scan_state(scanner_ptr), ScanState::Completed as i32);
+        let result_ptr = get_directory_map(scanner_ptr, path_c.as_ptr(), 0);
+        let file: FolderHierarchy = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!((file.name.as_str(), file.entry_type, file.value, file.file_count), ("notes.txt", EntryKind::File, 15, 1));
+        assert!(file.children.is_empty());
+        assert!(unsafe { &*scanner_ptr }.scan_errors().is_empty());
+        free_directory_scanner(scanner_ptr);
+
+        let file = scan_blocking(&file_path).unwrap();
+        assert_eq!((file.entry_type, file.value), (EntryKind::File, 15));
+        // Left out by the filters, the file is still reported, with nothing counted.
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(&file_path, ScanOptions { include_extensions: vec!["rs".into()], ..Default::default() });
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!((scanner.directory_map().value, scanner.directory_map().file_count), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_top_level_file_entries_use_their_own_path() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        std::fs::write(temp_dir.path().join("top_level.txt"), "top").unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        let directory_map = scanner.directory_map();
+        let file = directory_map.children.iter().find(|c| c.name == "top_level.txt").unwrap();
+        assert!(Path::new(&file.path).ends_with("top_level.txt"));
+        assert_eq!(Path::new(&file.path), temp_dir.path().join("top_level.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_entry_types_distinguish_files_and_empty_directories() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir_all(temp_dir.path().join("folder/empty")).await.unwrap();
+        File::create(temp_dir.path().join("folder/empty_file.txt")).unwrap();
+
+        let scanner = DirectoryScanner::open();
+        let hierarchy = scan_folder(temp_dir.path().join("folder"), scanner, &ScanOptions::default(), 0, &Traversal::new(&ScanOptions::default())).await.unwrap();
+
+        let empty_dir = hierarchy.children.iter().find(|c| c.name == "empty").unwrap();
+        let empty_file = hierarchy.children.iter().find(|c| c.name == "empty_file.txt").unwrap();
+        assert_eq!(empty_dir.entry_type, EntryKind::Directory);
+        assert_eq!(empty_file.entry_type, EntryKind::File);
+        assert!(empty_dir.children.is_empty() && empty_file.children.is_empty());
+
+        let json = serde_json::to_string(empty_file).unwrap();
+        assert!(json.contains("\"entry_type\":\"file\""));
+    }
+
+    #[tokio::test]
+    async fn test_entries_are_classified_from_their_metadata() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(temp_dir.path().join("subfolder2"), temp_dir.path().join("subfolder1/link")).unwrap();
+
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(temp_dir.path().join("subfolder1"), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        let file = hierarchy.children.iter().find(|c| c.name == "test_file1.txt").unwrap();
+        let folder = hierarchy.children.iter().find(|c| c.name == "subsubfolder1").unwrap();
+        assert_eq!((file.entry_type, file.value), (EntryKind::File, 14));
+        assert_eq!((folder.entry_type, folder.value), (EntryKind::Directory, 13));
+
+        #[cfg(unix)]
+        {
+            // A link that is not followed is a leaf sized by the link itself; a followed one is scanned as its target.
+            let link = hierarchy.children.iter().find(|c| c.name == "link").unwrap();
+            assert_eq!(link.entry_type, EntryKind::Symlink);
+            assert!(link.children.is_empty());
+
+            let options = ScanOptions { follow_symlinks: true, ..Default::default() };
+            let hierarchy = scan_folder(temp_dir.path().join("subfolder1"), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+            let link = hierarchy.children.iter().find(|c| c.name == "link").unwrap();
+            assert_eq!((link.entry_type, link.value, link.file_count), (EntryKind::Symlink, 16, 1));
+            assert_eq!(link.children.len(), 3);
+        }
+    }
+
+    lazy_static::lazy_static! {
+        static ref DIRECTORIES_TO_DELETE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+    }
+
+    extern "C" fn delete_other_directories(path_ptr: *const c_char) {
+        let scanned = PathBuf::from(unsafe { CStr::from_ptr(path_ptr) }.to_str().unwrap());
+        for path in DIRECTORIES_TO_DELETE.lock().unwrap().drain(..).filter(|path| *path != scanned) {
+            std::fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_entries_deleted_mid_scan_are_skipped() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for name in ["first", "second"] {
+            fs::create_dir(temp_dir.path().join(name)).await.unwrap();
+            fs::write(temp_dir.path().join(name).join("file.txt"), b"data").await.unwrap();
+        }
+
+        // Whichever sibling is scanned first deletes the other before it is read.
+        let scanner = DirectoryScanner::open();
+        *DIRECTORIES_TO_DELETE.lock().unwrap() = vec![temp_dir.path().join("first"), temp_dir.path().join("second")];
+        scanner.set_scan_callback(Some(delete_other_directories));
+        let options = ScanOptions { max_concurrency: 1, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), Arc::clone(&scanner), &options, 0, &Traversal::new(&options)).await.unwrap();
+
+        assert_eq!(hierarchy.children.len(), 1);
+        assert_eq!((hierarchy.value, hierarchy.file_count, hierarchy.dir_count), (4, 1, 1));
+        let errors = scanner.scan_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ScanErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_content_type_is_detected_from_magic_numbers() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        // A PNG signature and IHDR chunk start, under a misleading extension.
+        let png = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0x0D, b'I', b'H', b'D', b'R'];
+        fs::write(temp_dir.path().join("picture.dat"), png).await.unwrap();
+        fs::write(temp_dir.path().join("archive.bin"), [b'P', b'K', 3, 4, 0, 0, 0, 0]).await.unwrap();
+        fs::write(temp_dir.path().join("notes.txt"), b"plain words").await.unwrap();
+        File::create(temp_dir.path().join("empty.png")).unwrap();
+
+        let category = |hierarchy: &FolderHierarchy, name: &str| hierarchy.children.iter().find(|c| c.name == name).unwrap().category.clone();
+        let options = ScanOptions { detect_content_type: true, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(category(&hierarchy, "picture.dat").as_deref(), Some("image"));
+        assert_eq!(category(&hierarchy, "archive.bin").as_deref(), Some("archive"));
+        assert_eq!(category(&hierarchy, "notes.txt"), None);
+        assert_eq!(category(&hierarchy, "empty.png"), None);
+        assert_eq!(hierarchy.category, None);
+
+        // Nothing is read unless asked for.
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(category(&hierarchy, "picture.dat"), None);
+    }
+
+    #[tokio::test]
+    async fn test_zip_entries_are_listed_as_children() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let mut archive = zip::ZipWriter::new(File::create(temp_dir.path().join("bundle.zip")).unwrap());
+        let deflated = zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        archive.start_file("readme.txt", deflated).unwrap();
+        archive.write_all(&[b'a'; 1000]).unwrap();
+        archive.add_directory("docs/empty", deflated).unwrap();
+        archive.start_file("docs/guide.txt", deflated).unwrap();
+        archive.write_all(&[b'b'; 500]).unwrap();
+        archive.finish().unwrap();
+        fs::write(temp_dir.path().join("broken.zip"), b"PK not really an archive").await.unwrap();
+        let compressed = std::fs::metadata(temp_dir.path().join("bundle.zip")).unwrap().len();
+
+        let scanner = DirectoryScanner::open();
+        let options = ScanOptions { descend_archives: true, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), Arc::clone(&scanner), &options, 0, &Traversal::new(&options)).await.unwrap();
+        let bundle = hierarchy.children.iter().find(|c| c.name == "bundle.zip").unwrap();
+        assert_eq!(bundle.entry_type, EntryKind::File);
+        assert_eq!((bundle.value, bundle.file_count, bundle.dir_count), (1500, 2, 2));
+        let names: Vec<&str> = bundle.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["docs", "readme.txt"]);
+        let docs = &bundle.children[0];
+        assert_eq!(docs.children.iter().map(|c| (c.name.as_str(), c.value)).collect::<Vec<_>>(), [("empty", 0), ("guide.txt", 500)]);
+        assert_eq!(Path::new(&docs.children[1].path), temp_dir.path().join("bundle.zip/docs/guide.txt"));
+
+        // A malformed archive is an ordinary file.
+        let broken = hierarchy.children.iter().find(|c| c.name == "broken.zip").unwrap();
+        assert_eq!((broken.value, broken.file_count), (24, 1));
+        assert!(broken.children.is_empty());
+        let errors = scanner.scan_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(Path::new(&errors[0].path), temp_dir.path().join("broken.zip"));
+        assert_eq!(hierarchy.value, 1524);
+
+        // Archives are not opened unless asked for.
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        let bundle = hierarchy.children.iter().find(|c| c.name == "bundle.zip").unwrap();
+        assert_eq!((bundle.value, bundle.children.len()), (compressed, 0));
+    }
+
+    #[tokio::test]
+    async fn test_entries_carry_timestamps() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &ScanOptions::default(), 0, &Traversal::new(&ScanOptions::default())).await.unwrap();
+        let subfolder1 = hierarchy.children.iter().find(|c| c.name == "subfolder1").unwrap();
+        let file = subfolder1.children.iter().find(|c| c.entry_type == EntryKind::File).unwrap();
+        let metadata = std::fs::metadata(&file.path).unwrap();
+        assert_eq!(file.modified, metadata.modified().ok());
+        assert_eq!(file.created, metadata.created().ok());
+        assert!(subfolder1.modified.is_some());
+
+        let json = serde_json::to_value(file).unwrap();
+        let millis = metadata.modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+        assert_eq!(json["modified"], millis);
+        assert_eq!(json["created"].is_null(), file.created.is_none());
+    }
+
+    #[cfg(any(windows, target_os = "macos"))]
+    #[test]
+    fn test_lookups_match_either_normalization_form() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+        // The root is created composed and the directory below it decomposed, as macOS lists names.
+        let root = temp_dir.path().join(composed);
+        std::fs::create_dir_all(root.join(format!("{decomposed}/inner"))).unwrap();
+        std::fs::write(root.join(format!("{decomposed}/inner/file.txt")), b"hello").unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(&root);
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+
+        let root_decomposed = temp_dir.path().join(decomposed);
+        let found = scanner.directory_map_for(&root_decomposed).expect("Root found from its decomposed form");
+        assert_eq!(found.path, root.to_string_lossy());
+        let below = scanner.subtree(root_decomposed.join(format!("{composed}/inner"))).expect("Directory found from its composed form");
+        assert_eq!(below.value, 5);
+        assert!(below.path.contains(decomposed));
+    }
+
+    #[cfg(not(any(windows, target_os = "macos")))]
+    #[test]
+    fn test_lookups_tell_normalization_forms_apart() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+        // Two directories here, with the same name in either form.
+        std::fs::create_dir(temp_dir.path().join(composed)).unwrap();
+        std::fs::write(temp_dir.path().join(composed).join("file.txt"), b"hello").unwrap();
+        std::fs::create_dir(temp_dir.path().join(decomposed)).unwrap();
+        std::fs::write(temp_dir.path().join(decomposed).join("file.txt"), b"hi").unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.subtree(temp_dir.path().join(composed)).unwrap().value, 5);
+        assert_eq!(scanner.subtree(temp_dir.path().join(decomposed)).unwrap().value, 2);
+
+        // Nor do they collide as roots.
+        scanner.scan(temp_dir.path().join(decomposed));
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        scanner.scan(temp_dir.path().join(composed));
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.directory_map_for(temp_dir.path().join(composed)).unwrap().value, 5);
+        assert_eq!(scanner.directory_map_for(temp_dir.path().join(decomposed)).unwrap().value, 2);
+    }
+
+    #[test]
+    fn test_atomic_swap_never_shows_a_partial_tree() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..20 {
+            let folder = temp_dir.path().join(format!("folder{i}"));
+            std::fs::create_dir(&folder).unwrap();
+            for j in 0..10 {
+                std::fs::write(folder.join(format!("file{j}.bin")), [0u8; 10]).unwrap();
+            }
+        }
+
+        let scanner = DirectoryScanner::open();
+        let options = ScanOptions::builder().atomic_swap(true).build();
+        for scan in 0..2 {
+            scanner.scan_with_options(temp_dir.path(), options.clone());
+            // The first scan's tree only shows up once complete, and the second one's replaces it in one go.
+            loop {
+                let done = scanner.scan_state() != ScanState::Scanning;
+                match scanner.directory_map_for(temp_dir.path()) {
+                    Some(root) => assert_eq!((root.value, root.file_count, root.children.len()), (2000, 200, 20)),
+                    None => assert_eq!(scan, 0),
+                }
+                if done {
+                    break;
+                }
+                thread::yield_now();
+            }
+            assert_eq!(scanner.scan_state(), ScanState::Completed);
+        }
+        assert_eq!(scanner.directory_map_for(temp_dir.path()).unwrap().value, 2000);
+    }
+
+    #[test]
+    fn test_scans_complete_with_any_thread_count() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..8 {
+            let folder = temp_dir.path().join(format!("folder{i}"));
+            std::fs::create_dir(&folder).unwrap();
+            for j in 0..5 {
+                std::fs::write(folder.join(format!("file{j}.bin")), [0u8; 3]).unwrap();
+            }
+        }
+
+        for threads in [1, 4] {
+            let scanner = DirectoryScanner::open_with_threads(threads).unwrap();
+            scanner.scan(temp_dir.path());
+            assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+            let root = scanner.directory_map_for(temp_dir.path()).unwrap();
+            assert_eq!((root.value, root.file_count, root.dir_count), (120, 40, 8));
+        }
+
+        let scanner = DirectoryScanner::open();
+        assert!(!scanner.set_scan_threads(0));
+        assert!(scanner.set_scan_threads(2));
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert_eq!(scanner.directory_map_for(temp_dir.path()).unwrap().value, 120);
+    }
+
+    #[test]
+    fn test_relative_paths_are_given_from_the_scanned_root() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let inner = temp_dir.path().join("sub").join("inner");
+        std::fs::create_dir_all(&inner).unwrap();
+        std::fs::write(inner.join("file.txt"), [0u8; 5]).unwrap();
+
+        let scanner = DirectoryScanner::open();
+        // Passed with a trailing separator, which the root is still looked up without.
+        scanner.scan_with_options(temp_dir.path().join(""), ScanOptions::builder().relative_paths(true).build());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let root = scanner.directory_map_for(temp_dir.path()).unwrap();
+        assert_eq!(root.path, ".");
+        let sub = &root.children[0];
+        assert_eq!(sub.path, "sub");
+        let file = &sub.children[0].children[0];
+        assert_eq!(file.path, display_path(&Path::new("sub").join("inner").join("file.txt")));
+        // Lookups still take the path on disk.
+        assert_eq!(scanner.with_entry(inner.join("file.txt"), |file| file.value), Some(5));
+        assert_eq!(scanner.scanned_roots(), [temp_dir.path().join("").to_string_lossy()]);
+    }
+
+    #[tokio::test]
+    async fn test_directories_only_lists_no_files() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        fs::write(temp_dir.path().join("top.txt"), [0u8; 7]).await.unwrap();
+
+        let options = ScanOptions { directories_only: true, ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert!(hierarchy.iter().all(|node| node.entry_type == EntryKind::Directory));
+        assert_eq!(hierarchy.iter().count(), 6);
+        assert_eq!((hierarchy.value, hierarchy.file_count, hierarchy.dir_count), (14 + 13 + 16 + 7, 4, 5));
+        let subfolder1 = hierarchy.children.iter().find(|c| c.name == "subfolder1").unwrap();
+        assert_eq!((subfolder1.value, subfolder1.file_count), (14 + 13, 2));
+        assert!(subfolder1.has_children);
+    }
+
+    #[tokio::test]
+    async fn test_scans_read_from_the_injected_file_system() {
+        let root = PathBuf::from("/virtual");
+        let mut file_system = MemoryFileSystem::new();
+        file_system
+            .add_file(root.join("top.txt"), 10)
+            .add_file(root.join("docs/a.txt"), 20)
+            .add_file(root.join("docs/nested/b.txt"), 30)
+            .add_file(root.join("locked/secret.txt"), 40)
+            .add_directory(root.join("empty"))
+            .deny(root.join("locked"));
+
+        let scanner = DirectoryScanner::open_with_file_system(Arc::new(file_system));
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(root.clone(), Arc::clone(&scanner), &options, 0, &Traversal::new(&options)).await.unwrap();
+        // The denied directory is left out, like one that cannot be read on disk.
+        assert_eq!((hierarchy.value, hierarchy.file_count, hierarchy.dir_count), (60, 3, 3));
+        let docs = hierarchy.children.iter().find(|c| c.name == "docs").unwrap();
+        assert_eq!((docs.value, docs.file_count), (50, 2));
+
+        let errors = scanner.scan_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((PathBuf::from(&errors[0].path), errors[0].kind), (root.join("locked"), ScanErrorKind::PermissionDenied));
+    }
+
+    #[tokio::test]
+    async fn test_scans_let_other_tasks_run() {
+        // Listing memory never has to wait, so only the scan's own yields give the other task a turn.
+        let root = PathBuf::from("/virtual");
+        let mut file_system = MemoryFileSystem::new();
+        for i in 0..4000 {
+            file_system.add_file(root.join(format!("file{i}.txt")), 1);
+        }
+        let scanner = DirectoryScanner::open_with_file_system(Arc::new(file_system));
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = Arc::clone(&ticks);
+            async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(root, Arc::clone(&scanner), &options, 0, &Traversal::new(&options)).await.unwrap();
+        let ticks_during_scan = ticks.load(Ordering::Relaxed);
+        ticker.abort();
+
+        assert_eq!(hierarchy.file_count, 4000);
+        // Once every `YIELD_INTERVAL` entries while listing them, and again while scanning them.
+        assert!(ticks_during_scan >= 2 * (4000 / YIELD_INTERVAL), "{ticks_during_scan} ticks");
+    }
+
+    #[test]
+    fn test_identical_files_are_reported_as_duplicates() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        std::fs::create_dir(temp_dir.path().join("backup")).unwrap();
+        std::fs::write(temp_dir.path().join("photo.jpg"), b"the same bytes").unwrap();
+        std::fs::write(temp_dir.path().join("backup/photo copy.jpg"), b"the same bytes").unwrap();
+        std::fs::write(temp_dir.path().join("other.jpg"), b"different bytes").unwrap();
+        std::fs::write(temp_dir.path().join("empty.txt"), b"").unwrap();
+        std::fs::write(temp_dir.path().join("backup/empty.txt"), b"").unwrap();
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions::builder().hash_files(true).build());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        let hash = |path: &str| scanner.subtree(temp_dir.path().join(path)).unwrap().hash.unwrap();
+        assert_eq!(hash("photo.jpg"), hash("backup/photo copy.jpg"));
+        assert_ne!(hash("photo.jpg"), hash("other.jpg"));
+        assert_eq!(hash("photo.jpg").len(), 16);
+
+        // Empty files all look alike, so they are not reported.
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let result_ptr = get_duplicate_files(Arc::as_ptr(&scanner), test_path_c.as_ptr());
+        let duplicates: Vec<DuplicateFiles> = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        let paths = [temp_dir.path().join("backup/photo copy.jpg"), temp_dir.path().join("photo.jpg")].map(|path| path.to_string_lossy().into_owned());
+        assert_eq!(duplicates, [DuplicateFiles { hash: hash("photo.jpg"), value: 14, paths: paths.to_vec() }]);
+
+        // Without the option, nothing is hashed.
+        scanner.scan(temp_dir.path());
+        assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+        assert!(scanner.directory_map().iter().all(|node| node.hash.is_none()));
+        assert_eq!(scanner.duplicate_files(temp_dir.path()), Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_min_size_lists_only_large_files() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        fs::create_dir(temp_dir.path().join("nested")).await.unwrap();
+        fs::write(temp_dir.path().join("large.bin"), vec![0; 4096]).await.unwrap();
+        fs::write(temp_dir.path().join("small.txt"), b"tiny").await.unwrap();
+        fs::write(temp_dir.path().join("nested/large.bin"), vec![0; 2048]).await.unwrap();
+        fs::write(temp_dir.path().join("nested/small.txt"), b"also tiny").await.unwrap();
+
+        let options = ScanOptions { min_size: Some(1024), ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        let mut names: Vec<_> = hierarchy.children.iter().map(|c| c.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["large.bin", "nested"]);
+        let nested = hierarchy.children.iter().find(|c| c.name == "nested").unwrap();
+        assert_eq!(nested.children.len(), 1);
+        assert_eq!(nested.children[0].name, "large.bin");
+        assert_eq!(hierarchy.value, 4096 + 4 + 2048 + 9);
+        assert_eq!(hierarchy.file_count, 4);
+
+        let options = ScanOptions { count_small_files: false, ..options };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.value, 4096 + 2048);
+        assert_eq!(hierarchy.file_count, 2);
+    }
+
+    #[test]
+    fn test_exclude_patterns_match_directory_names() {
+        let patterns = ["node_modules", ".*", "build-*-out", "*cache*", "tmp?", "a*b", "v[0-9]"];
+        let options = ScanOptions { exclude_patterns: patterns.map(String::from).to_vec(), ..Default::default() };
+        let traversal = Traversal::new(&options);
+        let excludes = |name: &str| futures::executor::block_on(traversal.excludes_directory(&options, &Path::new("/base").join(name), &RealFileSystem));
+        for name in ["node_modules", ".git", "build-release-out", "pip-cache-dir", "tmp1", "acb", "v1"] {
+            assert!(excludes(name), "{name} should be excluded");
+        }
+        for name in ["node_modules2", "git", "tmp", "acbc", "vx", "src"] {
+            assert!(!excludes(name), "{name} should not be excluded");
+        }
+        // Only the name is matched, not the directories above it.
+        assert!(!futures::executor::block_on(traversal.excludes_directory(&options, Path::new("/node_modules/src"), &RealFileSystem)));
+    }
+
+    #[tokio::test]
+    async fn test_exclude_patterns_skip_matching_directories() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+        fs::create_dir_all(temp_dir.path().join("subfolder1/node_modules/package")).await.unwrap();
+        fs::write(temp_dir.path().join("subfolder1/node_modules/package/index.js"), vec![b'x'; 500]).await.unwrap();
+        fs::create_dir(temp_dir.path().join("target")).await.unwrap();
+        fs::write(temp_dir.path().join("target/build.log"), vec![b'x'; 300]).await.unwrap();
+        // Only directories are matched, so a file with an excluded name is still counted.
+        fs::write(temp_dir.path().join("target.txt"), b"kept").await.unwrap();
+
+        let options = ScanOptions { exclude_patterns: vec!["node_modules".to_string(), "targ*".to_string()], ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+
+        assert_eq!(hierarchy.value, 14 + 13 + 16 + 4);
+        assert_eq!(hierarchy.dir_count, 5);
+        assert!(hierarchy.children.iter().all(|c| c.name != "target"));
+        let subfolder1 = hierarchy.children.iter().find(|c| c.name == "subfolder1").unwrap();
+        assert!(subfolder1.children.iter().all(|c| c.name != "node_modules"));
+
+        let scanner = DirectoryScanner::open();
+        scanner.scan_with_options(temp_dir.path(), ScanOptions::builder().exclude("[").build());
+        assert_eq!(scanner.scan_state(), ScanState::Error);
+        assert_eq!(scanner.last_error_kind(), Some(ScanErrorKind::Io));
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_long_paths_are_scanned() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let mut deep = temp_dir.path().to_path_buf();
+        while deep.as_os_str().len() < 300 {
+            deep.push("a_rather_long_directory_name");
+        }
+        std::fs::create_dir_all(extended_length(&deep)).unwrap();
+        std::fs::write(extended_length(&deep.join("file.txt")), b"deep").unwrap();
+
+        let options = ScanOptions::default();
+        let root = extended_length(temp_dir.path());
+        let hierarchy = scan_folder(root, DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.value, 4);
+        assert!(!hierarchy.path.starts_with(r"\\?\"));
+
+        let mut node = &hierarchy;
+        while let Some(child) = node.children.first() {
+            node = child;
+        }
+        assert_eq!(node.path, deep.join("file.txt").to_string_lossy());
+    }
+
+    #[test]
+    fn test_extended_length_paths_are_hidden_from_consumers() {
+        let path = Path::new("/some/dir");
+        assert_eq!(display_path(&extended_length(path)), path.to_string_lossy());
+        #[cfg(windows)]
+        {
+            assert_eq!(extended_length(Path::new(r"C:\dir")), PathBuf::from(r"\\?\C:\dir"));
+            assert_eq!(extended_length(Path::new(r"\\server\share\dir")), PathBuf::from(r"\\?\UNC\server\share\dir"));
+            assert_eq!(display_path(Path::new(r"\\?\C:\dir")), r"C:\dir");
+            assert_eq!(display_path(Path::new(r"\\?\UNC\server\share\dir")), r"\\server\share\dir");
+        }
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_wide_paths_are_scanned_without_loss() {
+        use std::os::windows::ffi::{OsStrExt, OsStringExt};
+
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let folder = temp_dir.path().join("caf\u{e9} \u{65e5}\u{672c}");
+        std::fs::create_dir(&folder).unwrap();
+        std::fs::write(folder.join("r\u{e9}sum\u{e9}.txt"), [0u8; 10]).unwrap();
+        // An unpaired surrogate is a valid Windows file name, but not valid UTF-8.
+        let mut unpaired = folder.as_os_str().encode_wide().collect::<Vec<_>>();
+        unpaired.extend("\\".encode_utf16().chain([0xD800]));
+        std::fs::write(PathBuf::from(std::ffi::OsString::from_wide(&unpaired)), [0u8; 5]).unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let wide_path: Vec<u16> = folder.as_os_str().encode_wide().collect();
+        scan_directory_async_wide(scanner_ptr, wide_path.as_ptr(), wide_path.len());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let directory_map = unsafe { &*scanner_ptr }.directory_map();
+        assert_eq!((directory_map.value, directory_map.file_count), (15, 2));
+        assert!(directory_map.children.iter().any(|child| child.name == "r\u{e9}sum\u{e9}.txt"));
+        assert!(unsafe { &*scanner_ptr }.scan_errors().is_empty());
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_scan_from_fd_follows_the_open_directory() {
+        use std::os::fd::AsRawFd;
+
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let original = temp_dir.path().join("original");
+        std::fs::create_dir(&original).unwrap();
+        create_test_directory_structure(&original).await.unwrap();
+
+        // Once open, the directory is found through the descriptor even after it has been moved away.
+        let directory = File::open(&original).unwrap();
+        std::fs::rename(&original, temp_dir.path().join("moved")).unwrap();
+        std::fs::create_dir(&original).unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let root_ptr = scan_from_fd(scanner_ptr, directory.as_raw_fd());
+        assert!(!root_ptr.is_null());
+        let root = unsafe { CStr::from_ptr(root_ptr) }.to_str().unwrap().to_string();
+        free_directory_map_string(root_ptr);
+        drop(directory);
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let directory_map = unsafe { &*scanner_ptr }.directory_map_for(&root).unwrap();
+        assert_eq!((directory_map.value, directory_map.file_count, directory_map.dir_count), (14 + 13 + 16, 3, 5));
+        assert_eq!(get_subtree_value(scanner_ptr, &Path::new(&root).join("subfolder1/test_file1.txt")), Some(14));
+
+        assert!(scan_from_fd(scanner_ptr, -1).is_null());
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[test]
+    fn test_root_keys_follow_the_platform_case_rules() {
+        assert_eq!(root_key(r"C:\Users\"), "C:/Users");
+        #[cfg(windows)]
+        assert_eq!(root_key("C:/Users"), root_key("c:/users"));
+        #[cfg(not(windows))]
+        assert_ne!(root_key("/home/Users"), root_key("/home/users"));
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_get_directory_map_ignores_case_on_windows() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let scanner_ptr = create_directory_scanner();
+        let test_path_c = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        scan_directory_async(scanner_ptr, test_path_c.as_ptr());
+        assert!(wait_for_scan_completion(scanner_ptr, 10_000));
+
+        let upper_root = temp_dir.path().to_str().unwrap().to_uppercase();
+        let upper_root_c = CString::new(upper_root.as_str()).unwrap();
+        let result_ptr = get_directory_map(scanner_ptr, upper_root_c.as_ptr(), 0);
+        let root: FolderHierarchy = serde_json::from_str(unsafe { CStr::from_ptr(result_ptr) }.to_str().unwrap()).unwrap();
+        free_directory_map_string(result_ptr);
+        assert_eq!(root.value, 14 + 13 + 16);
+
+        let upper_file = PathBuf::from(format!("{upper_root}/SUBFOLDER1/Test_File1.TXT"));
+        assert_eq!(get_subtree_value(scanner_ptr, &upper_file), Some(14));
+
+        free_directory_scanner(scanner_ptr);
+    }
+
+    #[tokio::test]
+    async fn test_very_deep_trees_do_not_overflow_the_stack() {
+        const LEVELS: usize = 1500;
+
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        let mut deepest = temp_dir.path().to_path_buf();
+        for _ in 0..LEVELS {
+            deepest.push("d");
+        }
+        std::fs::create_dir_all(&deepest).unwrap();
+        std::fs::write(deepest.join("bottom.txt"), b"found").unwrap();
+
+        let options = ScanOptions::default();
+        let scanner = DirectoryScanner::open();
+        let total = count_entries(temp_dir.path().to_path_buf(), &scanner, &options, &Traversal::new(&options)).await;
+        assert_eq!(total, 1 + LEVELS as u64 + 1);
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), scanner, &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!((hierarchy.value, hierarchy.file_count, hierarchy.dir_count), (5, 1, LEVELS as u64));
+        assert_eq!(hierarchy.summary().max_depth, LEVELS + 1);
+        assert_eq!(hierarchy.largest_files(1)[0].path, deepest.join("bottom.txt").to_string_lossy());
+    }
+
+    #[tokio::test]
+    async fn test_max_depth_truncates_children_but_keeps_totals() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        create_test_directory_structure(temp_dir.path()).await.unwrap();
+
+        let options = ScanOptions { max_depth: Some(1), ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+
+        assert_eq!(hierarchy.value, 14 + 13 + 16);
+        assert_eq!(hierarchy.children.len(), 2);
+
+        let subfolder1 = hierarchy.children.iter().find(|c| c.name == "subfolder1").unwrap();
+        assert!(subfolder1.children.is_empty());
+        assert_eq!(subfolder1.value, 14 + 13);
+
+        let subfolder2 = hierarchy.children.iter().find(|c| c.name == "subfolder2").unwrap();
+        assert!(subfolder2.children.is_empty());
+        assert_eq!(subfolder2.value, 16);
+    }
+
+    #[tokio::test]
+    async fn test_max_nodes_caps_the_hierarchy_but_keeps_totals() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..10 {
+            let folder = temp_dir.path().join(format!("folder{i}"));
+            fs::create_dir(&folder).await.unwrap();
+            for j in 0..10 {
+                fs::write(folder.join(format!("file{j}.txt")), vec![0u8; i * 10 + j]).await.unwrap();
+            }
+        }
+        let total: u64 = (0..100).sum();
+
+        let options = ScanOptions { max_nodes: Some(25), ..Default::default() };
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        // The root and its folders come first, and the files below them fill up the rest.
+        assert_eq!(hierarchy.iter().count(), 25);
+        assert_eq!(hierarchy.children.len(), 10);
+        assert_eq!((hierarchy.value, hierarchy.file_count, hierarchy.dir_count), (total, 100, 10));
+        // Every kept directory still carries the totals of what was dropped below it.
+        for folder in &hierarchy.children {
+            assert_eq!(folder.file_count, 10);
+        }
+
+        // Background scans apply the limit to the root's own entries too.
+        for max_nodes in [1, 3, 25] {
+            let scanner = DirectoryScanner::open();
+            scanner.scan_with_options(temp_dir.path(), ScanOptions { max_nodes: Some(max_nodes), ..Default::default() });
+            assert!(scanner.wait_for_completion(Duration::from_secs(10)));
+            let mapped = scanner.directory_map_for(temp_dir.path()).unwrap();
+            assert_eq!(mapped.iter().count(), max_nodes);
+            assert_eq!((mapped.value, mapped.file_count, mapped.dir_count), (total, 100, 10));
+        }
+
+        let options = ScanOptions::default();
+        let hierarchy = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &options, 0, &Traversal::new(&options)).await.unwrap();
+        assert_eq!(hierarchy.iter().count(), 111);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_traversal_matches_sequential_totals() {
+        let temp_dir = tempdir().expect("Failed to create a temporary directory");
+        for i in 0..20 {
+            let branch = temp_dir.path().join(format!("branch{i}"));
+            for j in 0..5 {
+                fs::create_dir_all(branch.join(format!("leaf{j}"))).await.unwrap();
+                fs::write(branch.join(format!("leaf{j}/file.txt")), vec![b'x'; i * 10 + j]).await.unwrap();
+            }
+            fs::write(branch.join("top.txt"), vec![b'y'; i]).await.unwrap();
+        }
+
+        let sequential = ScanOptions { max_concurrency: 1, ..Default::default() };
+        let concurrent = ScanOptions { max_concurrency: 8, ..Default::default() };
+        let sequential = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &sequential, 0, &Traversal::new(&sequential)).await.unwrap();
+        let concurrent = scan_folder(temp_dir.path().to_path_buf(), DirectoryScanner::open(), &concurrent, 0, &Traversal::new(&concurrent)).await.unwrap();
+
+        assert_eq!(concurrent.value, sequential.value);
+        assert_eq!(concurrent.file_count, 20 * 6);
+        assert_eq!(concurrent.dir_count, 20 * 6);
+        // Children keep the directory listing order regardless of which finishes first.
+        assert_eq!(concurrent, sequential);
+    }
+}
+